@@ -1,25 +1,108 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::Arc;
 
+use actix::fut::ready;
 use actix::{
     Actor, ActorContext, ActorFutureExt, Context, Handler, Recipient, ResponseActFuture, WrapFuture,
 };
 use async_trait::async_trait;
-use lapin::options::{BasicPublishOptions, ExchangeDeclareOptions};
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions, ExchangeDeclareOptions};
+use lapin::publisher_confirm::Confirmation;
+use lapin::types::{AMQPValue, FieldTable};
 use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind, Result};
 use once_cell::sync::Lazy;
 use tokio::sync::Mutex;
 use tokio_amqp::LapinTokioExt;
-use tracing::{error, info_span, Instrument, Span};
+use tracing::{error, info_span, warn, Instrument, Span};
 use tracing_actix::ActorInstrument;
 
-use super::{Collector, CollectorFactory, PublishExpanded};
+use crate::config::{EncodingConfig, ExchangeKindConfig, DEFAULT_ROUTING_KEY};
 
-static AMQP_CONNECTION: Lazy<Mutex<Option<Connection>>> = Lazy::new(|| Mutex::new(None));
+use super::encoding::{content_encoding, content_type, encode};
+use super::envelope::Envelope;
+use super::{Collector, CollectorFactory, Delivery, PublishExpanded};
+
+const APP_ID: &str = "stargazer";
+
+/// Connections to AMQP brokers, keyed by URI.
+///
+/// Each connection has one channel shared by all collectors publishing to the broker.
+static AMQP_POOL: Lazy<parking_lot::Mutex<HashMap<String, PoolSlot>>> = Lazy::new(Default::default);
+
+type PoolSlot = Arc<Mutex<Option<Pooled>>>;
+
+#[derive(Debug)]
+struct Pooled {
+    connection: Connection,
+    channel: Channel,
+}
+
+/// Get the shared channel of given broker, and a scratch channel on the same connection.
+///
+/// A new connection is made if there's no usable one, or `reconnect` is set. The scratch channel is for operations
+/// which may close their channel on failure, e.g. declaring an exchange, so that they won't break the shared one.
+async fn shared_channel(uri: &str, reconnect: bool) -> Result<(Channel, Channel)> {
+    let slot = AMQP_POOL.lock().entry(uri.to_string()).or_default().clone();
+    // Only factories of the same broker wait for each other.
+    let mut guard = slot.lock().await;
+    let connection = match guard.take() {
+        Some(pooled) if !reconnect && pooled.connection.status().connected() => {
+            if pooled.channel.status().connected() {
+                let scratch = pooled.connection.create_channel().await?;
+                let channel = pooled.channel.clone();
+                *guard = Some(pooled);
+                return Ok((channel, scratch));
+            }
+            pooled.connection
+        }
+        _ => Connection::connect(uri, ConnectionProperties::default().with_tokio()).await?,
+    };
+    let channel = connection.create_channel().await?;
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+    let scratch = connection.create_channel().await?;
+    *guard = Some(Pooled {
+        connection,
+        channel: channel.clone(),
+    });
+    Ok((channel, scratch))
+}
+
+/// Declare an exchange on given channel, and close the channel afterwards.
+async fn declare_exchange(
+    channel: Channel,
+    exchange: &str,
+    exchange_kind: ExchangeKindConfig,
+) -> Result<()> {
+    let kind = match exchange_kind {
+        ExchangeKindConfig::Direct => ExchangeKind::Direct,
+        ExchangeKindConfig::Fanout => ExchangeKind::Fanout,
+        ExchangeKindConfig::Headers => ExchangeKind::Headers,
+        ExchangeKindConfig::Topic => ExchangeKind::Topic,
+    };
+    channel
+        .exchange_declare(
+            exchange,
+            kind,
+            ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            Default::default(),
+        )
+        .await?;
+    channel.close(200, "OK").await
+}
 
 #[derive(Debug)]
 pub struct AMQPFactory {
     uri: String,
     exchange: String,
+    exchange_kind: ExchangeKindConfig,
+    routing_key: String,
+    encoding: EncodingConfig,
 }
 
 impl AMQPFactory {
@@ -27,8 +110,33 @@ impl AMQPFactory {
         Self {
             uri: uri.to_string(),
             exchange: exchange.to_string(),
+            exchange_kind: ExchangeKindConfig::default(),
+            routing_key: String::from(DEFAULT_ROUTING_KEY),
+            encoding: EncodingConfig::default(),
         }
     }
+
+    /// Set the kind of the exchange to declare.
+    #[must_use]
+    pub const fn exchange_kind(mut self, exchange_kind: ExchangeKindConfig) -> Self {
+        self.exchange_kind = exchange_kind;
+        self
+    }
+
+    /// Set the routing key template. `{vtuber}`, `{source}` and `{topic}` will be substituted.
+    #[must_use]
+    pub fn routing_key(mut self, routing_key: &str) -> Self {
+        self.routing_key = routing_key.to_string();
+        self
+    }
+
+    /// Set the encoding of message bodies.
+    #[must_use]
+    pub const fn encoding(mut self, encoding: EncodingConfig) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
     fn span(&self) -> Span {
         info_span!("amqp_factory", uri = %self.uri, exchange = %self.exchange)
     }
@@ -41,33 +149,24 @@ impl CollectorFactory for AMQPFactory {
     }
 
     async fn build(&self) -> Option<Recipient<PublishExpanded>> {
-        async fn _build(uri: &str, exchange: &str, update_conn: bool) -> Result<AMQPActor> {
-            let mut guard = AMQP_CONNECTION.lock().await;
-            if guard.is_none() || update_conn {
-                *guard = Some(
-                    Connection::connect(uri, ConnectionProperties::default().with_tokio()).await?,
-                );
-            }
-            let conn = guard.as_ref().unwrap();
-            let chan = conn.create_channel().await?;
-            AMQPActor::new(chan, uri, exchange).await
+        async fn _build(this: &AMQPFactory) -> Result<AMQPActor> {
+            let (chan, scratch) = match shared_channel(&this.uri, false).await {
+                Ok(chans) => chans,
+                Err(_) => shared_channel(&this.uri, true).await?,
+            };
+            // a conflicting declaration only fails this collector, not others sharing the connection
+            declare_exchange(scratch, &this.exchange, this.exchange_kind).await?;
+            Ok(AMQPActor::new(chan, &this.uri, &this.exchange)
+                .routing_key(&this.routing_key)
+                .encoding(this.encoding))
         }
-        match _build(self.uri.as_str(), self.exchange.as_str(), false)
-            .instrument(self.span())
-            .await
-        {
+        match _build(self).instrument(self.span()).await {
             Ok(act) => Some(act.start().recipient()),
-            Err(_) => match _build(self.uri.as_str(), self.exchange.as_str(), true)
-                .instrument(self.span())
-                .await
-            {
-                Ok(act) => Some(act.start().recipient()),
-                Err(e) => {
-                    self.span()
-                        .in_scope(|| error!("amqp connect fail: {:?}", e));
-                    None
-                }
-            },
+            Err(e) => {
+                self.span()
+                    .in_scope(|| error!("amqp connect fail: {:?}", e));
+                None
+            }
         }
     }
 }
@@ -77,6 +176,8 @@ pub struct AMQPActor {
     channel: Channel,
     uri: String,
     exchange: String,
+    routing_key: String,
+    encoding: EncodingConfig,
 }
 
 impl_stop_on_panic!(AMQPActor);
@@ -84,28 +185,31 @@ impl_stop_on_panic!(AMQPActor);
 impl Collector for AMQPActor {}
 
 impl AMQPActor {
-    /// Creates a new `AMQPActor` with given channel and exchange.
+    /// Creates a new `AMQPActor` with given channel and exchange. The exchange should have been declared.
+    /// The channel should be in confirm mode, or publishes won't wait for the broker.
     /// The `uri` parameter is for logging usage.
-    ///
-    /// # Errors
-    /// Raise errors if unable to declare exchange on current channel.
-    pub async fn new(channel: Channel, uri: &str, exchange: &str) -> Result<Self> {
-        channel
-            .exchange_declare(
-                exchange,
-                ExchangeKind::Topic,
-                ExchangeDeclareOptions {
-                    durable: true,
-                    ..Default::default()
-                },
-                Default::default(),
-            )
-            .await?;
-        Ok(Self {
+    pub fn new(channel: Channel, uri: &str, exchange: &str) -> Self {
+        Self {
             channel,
             uri: uri.to_string(),
             exchange: exchange.to_string(),
-        })
+            routing_key: String::from(DEFAULT_ROUTING_KEY),
+            encoding: EncodingConfig::default(),
+        }
+    }
+
+    /// Set the routing key template. `{vtuber}`, `{source}` and `{topic}` will be substituted.
+    #[must_use]
+    pub fn routing_key(mut self, routing_key: &str) -> Self {
+        self.routing_key = routing_key.to_string();
+        self
+    }
+
+    /// Set the encoding of message bodies.
+    #[must_use]
+    pub const fn encoding(mut self, encoding: EncodingConfig) -> Self {
+        self.encoding = encoding;
+        self
     }
 
     fn span(&self) -> Span {
@@ -117,30 +221,105 @@ impl Actor for AMQPActor {
     type Context = Context<Self>;
 }
 
+pub(crate) fn render_routing_key(template: &str, msg: &PublishExpanded) -> String {
+    template
+        .replace("{vtuber}", &msg.vtuber)
+        .replace("{source}", &msg.source)
+        .replace("{topic}", &msg.topic)
+}
+
+#[allow(clippy::cast_sign_loss)]
+fn properties<T>(
+    msg: &PublishExpanded,
+    envelope: &Envelope<T>,
+    encoding: EncodingConfig,
+) -> BasicProperties {
+    let mut headers = FieldTable::default();
+    headers.insert(
+        "vtuber".into(),
+        AMQPValue::LongString(msg.vtuber.clone().into()),
+    );
+    headers.insert(
+        "source".into(),
+        AMQPValue::LongString(msg.source.clone().into()),
+    );
+    headers.insert(
+        "topic".into(),
+        AMQPValue::LongString(msg.topic.clone().into()),
+    );
+    if msg.replay {
+        headers.insert("x-replay".into(), AMQPValue::Boolean(true));
+    }
+    let properties = content_encoding(encoding.compression).map_or_else(
+        BasicProperties::default,
+        |content_encoding| {
+            BasicProperties::default().with_content_encoding(content_encoding.into())
+        },
+    );
+    properties
+        .with_content_type(content_type(encoding.format).into())
+        .with_message_id(msg.meta.id.to_string().into())
+        // in seconds, sharing the emission time of the envelope
+        .with_timestamp((envelope.emitted_at / 1000) as u64)
+        .with_app_id(APP_ID.into())
+        .with_headers(headers)
+}
+
 impl Handler<PublishExpanded> for AMQPActor {
-    type Result = ResponseActFuture<Self, bool>;
+    type Result = ResponseActFuture<Self, Delivery>;
 
     fn handle(&mut self, msg: PublishExpanded, _ctx: &mut Self::Context) -> Self::Result {
-        let payload = serde_json::to_vec(&*msg.data).unwrap(); // TODO error handling
+        let _span = self.span().entered();
+        let envelope = Envelope::new(&msg);
+        let payload = match encode(&envelope, self.encoding, false) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("unable to encode payload: {}", e);
+                return Box::pin(ready(Delivery::Rejected));
+            }
+        };
+        let properties = properties(&msg, &envelope, self.encoding);
+        let routing_key = render_routing_key(&self.routing_key, &msg);
+        let channel = self.channel.clone();
+        let exchange = self.exchange.clone();
         Box::pin(
-            self.channel
-                .basic_publish(
-                    self.exchange.as_str(),
-                    &format!("{}.{}", msg.vtuber, msg.topic),
-                    BasicPublishOptions::default(),
-                    payload,
-                    BasicProperties::default(),
-                )
-                .into_actor(self)
-                .map(|res, _act, ctx| match res {
-                    Ok(_) => true,
-                    Err(e) => {
-                        error!("publish error: {:?}, stopping actor", e);
-                        ctx.stop();
-                        false
-                    }
-                })
-                .actor_instrument(self.span()),
+            async move {
+                channel
+                    .basic_publish(
+                        exchange.as_str(),
+                        routing_key.as_str(),
+                        BasicPublishOptions {
+                            mandatory: true,
+                            ..Default::default()
+                        },
+                        payload,
+                        properties,
+                    )
+                    .await?
+                    .await
+            }
+            .into_actor(self)
+            .map(|res, _act, ctx| match res {
+                Ok(Confirmation::Ack(None) | Confirmation::NotRequested) => Delivery::Sent,
+                Ok(Confirmation::Ack(Some(returned)) | Confirmation::Nack(Some(returned))) => {
+                    // no queue is bound for the routing key, and retrying won't fix it
+                    warn!(
+                        "message returned by broker, dropping: {} {}",
+                        returned.reply_code, returned.reply_text
+                    );
+                    Delivery::Discarded
+                }
+                Ok(Confirmation::Nack(None)) => {
+                    warn!("message nacked by broker");
+                    Delivery::Rejected
+                }
+                Err(e) => {
+                    error!("publish error: {:?}, stopping actor", e);
+                    ctx.stop();
+                    Delivery::Failed
+                }
+            })
+            .actor_instrument(self.span()),
         )
     }
 }
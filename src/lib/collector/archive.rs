@@ -0,0 +1,480 @@
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::fut::ready;
+use actix::{
+    Actor, ActorFutureExt, Context, Handler, MailboxError, Recipient, ResponseActFuture, WrapFuture,
+};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Json, Query};
+use actix_web::{get, post, ResponseError};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{self, doc, Bson, DateTime};
+use mongodb::options::FindOptions;
+use mongodb::{Database, IndexModel};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, info, info_span, warn, Span};
+use tracing_actix::ActorInstrument;
+
+use crate::db::{create_ttl_index, CollOperation, Collection, DBResult, Document};
+use crate::ArbiterContext;
+
+use super::envelope::EventMeta;
+use super::{Collector, CollectorActor, CollectorFactory, Delivery, PublishExpanded, Replay};
+
+pub const ARCHIVE_COLLECTION: &str = "events";
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 500;
+const MAX_REPLAY_LIMIT: u32 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedEvent {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub vtuber: String,
+    #[serde(default)]
+    pub meta: EventMeta,
+    #[serde(default)]
+    pub source: String,
+    pub topic: String,
+    pub timestamp: DateTime,
+    /// Payload converted to BSON, so that it can be queried.
+    pub payload: Bson,
+    /// Payload serialized as JSON, replayed as is since BSON conversion may change its shape, e.g. integer widths.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_payload: Option<String>,
+}
+
+/// Decode a persisted payload as originally emitted, falling back to the BSON one for ones persisted without raw JSON.
+pub(crate) fn decode_payload(raw_payload: Option<String>, payload: Bson) -> serde_json::Value {
+    raw_payload
+        .and_then(|raw| serde_json::from_str(&raw).ok())
+        .unwrap_or_else(|| payload.into_relaxed_extjson())
+}
+
+impl ArchivedEvent {
+    /// The payload as originally emitted, falling back to the BSON one for events archived without raw JSON.
+    fn json_payload(&mut self) -> serde_json::Value {
+        decode_payload(self.raw_payload.take(), mem::take(&mut self.payload))
+    }
+}
+
+impl From<ArchivedEvent> for PublishExpanded {
+    fn from(mut event: ArchivedEvent) -> Self {
+        let data = event.json_payload();
+        Self {
+            meta: event.meta.clone(),
+            vtuber: event.vtuber,
+            source: event.source,
+            topic: event.topic,
+            data: Arc::new(data),
+            tags: Vec::new(),
+            replay: true,
+            text: None,
+            dead_letter: None,
+        }
+    }
+}
+
+/// Create indexes needed by the archive, including the TTL index.
+///
+/// # Errors
+/// Pass errors raised by mongodb driver.
+pub async fn create_indexes(
+    db: &Database,
+    collection: &Collection<ArchivedEvent>,
+    ttl: Duration,
+) -> DBResult<()> {
+    create_ttl_index(db, collection, doc! {"timestamp": 1}, ttl).await?;
+    collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"vtuber": 1, "topic": 1, "_id": -1})
+                .build(),
+            None,
+        )
+        .await
+        .map(|_| ())
+}
+
+#[derive(Debug)]
+pub struct ArchiveFactory {
+    collection: Collection<ArchivedEvent>,
+}
+
+impl ArchiveFactory {
+    pub const fn new(collection: Collection<ArchivedEvent>) -> Self {
+        Self { collection }
+    }
+}
+
+#[async_trait]
+impl CollectorFactory for ArchiveFactory {
+    fn ident(&self) -> String {
+        format!("archive(collection={})", self.collection.name())
+    }
+
+    async fn build(&self) -> Option<Recipient<PublishExpanded>> {
+        Some(
+            ArchiveActor::new(self.collection.clone())
+                .start()
+                .recipient(),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct InsertEventOp(pub ArchivedEvent);
+
+#[async_trait]
+impl CollOperation for InsertEventOp {
+    type Result = ();
+    type Item = ArchivedEvent;
+
+    const DESC: &'static str = "InsertEvent";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection.insert_one(self.0, None).await.map(|_| ())
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryEventsOp {
+    pub filter: Document,
+    pub limit: Option<i64>,
+    /// Return oldest events first instead of newest ones.
+    pub oldest_first: bool,
+}
+
+#[async_trait]
+impl CollOperation for QueryEventsOp {
+    type Result = Vec<ArchivedEvent>;
+    type Item = ArchivedEvent;
+
+    const DESC: &'static str = "QueryEvents";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .find(
+                self.filter,
+                FindOptions::builder()
+                    .sort(doc! {"_id": if self.oldest_first { 1 } else { -1 }})
+                    .limit(self.limit)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveActor {
+    collection: Collection<ArchivedEvent>,
+}
+
+impl_stop_on_panic!(ArchiveActor);
+
+impl Collector for ArchiveActor {}
+
+impl ArchiveActor {
+    pub const fn new(collection: Collection<ArchivedEvent>) -> Self {
+        Self { collection }
+    }
+
+    fn span(&self) -> Span {
+        info_span!("archive", collection = %self.collection.name())
+    }
+}
+
+impl Actor for ArchiveActor {
+    type Context = Context<Self>;
+}
+
+impl Handler<PublishExpanded> for ArchiveActor {
+    type Result = ResponseActFuture<Self, Delivery>;
+
+    fn handle(&mut self, msg: PublishExpanded, _ctx: &mut Self::Context) -> Self::Result {
+        if msg.replay && msg.dead_letter.is_none() {
+            // already archived, unless it's given up by the archive before
+            return Box::pin(ready(Delivery::Sent));
+        }
+        let raw_payload = match serde_json::to_string(&*msg.data) {
+            Ok(raw_payload) => raw_payload,
+            Err(e) => {
+                self.span()
+                    .in_scope(|| error!("unable to serialize payload: {}", e));
+                return Box::pin(ready(Delivery::Rejected));
+            }
+        };
+        // payloads not representable in BSON, e.g. with integers beyond i64, are still archived for replay
+        let payload = bson::to_bson(&*msg.data).unwrap_or_else(|e| {
+            self.span()
+                .in_scope(|| warn!("unable to convert payload, archiving raw JSON only: {}", e));
+            Bson::Null
+        });
+        let op = InsertEventOp(ArchivedEvent {
+            id: ObjectId::new(),
+            meta: msg.meta,
+            vtuber: msg.vtuber,
+            source: msg.source,
+            topic: msg.topic,
+            timestamp: DateTime::now(),
+            payload,
+            raw_payload: Some(raw_payload),
+        });
+        let collection = self.collection.clone();
+        Box::pin(
+            async move { op.execute(&collection).await }
+                .into_actor(self)
+                .map(|res, _act, _ctx| match res {
+                    Ok(()) => Delivery::Sent,
+                    Err(e) => {
+                        error!("unable to archive event: {}", e);
+                        Delivery::Failed
+                    }
+                })
+                .actor_instrument(self.span()),
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error("database error: {0}")]
+    DBError(#[from] mongodb::error::Error),
+    #[error("invalid {field}: {value}")]
+    InvalidParam { field: &'static str, value: String },
+    #[error("no such collector: {0}")]
+    MissingCollector(String),
+    #[error("context error: {0}")]
+    Context(#[from] crate::Error),
+    #[error("mailbox error: {0}")]
+    Mailbox(#[from] MailboxError),
+}
+
+impl ResponseError for QueryError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            QueryError::DBError(_) | QueryError::Context(_) | QueryError::Mailbox(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            QueryError::InvalidParam { .. } => StatusCode::BAD_REQUEST,
+            QueryError::MissingCollector(_) => StatusCode::NOT_FOUND,
+        }
+    }
+}
+
+/// Filters on archived events.
+///
+/// `since` and `until` are RFC 3339 timestamps.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventsFilter {
+    pub vtuber: Option<String>,
+    pub topic: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+pub(crate) fn parse_cursor(cursor: &str) -> Result<ObjectId, QueryError> {
+    ObjectId::parse_str(cursor).map_err(|_| QueryError::InvalidParam {
+        field: "cursor",
+        value: cursor.to_string(),
+    })
+}
+
+fn parse_time(field: &'static str, value: &str) -> Result<DateTime, QueryError> {
+    humantime::parse_rfc3339_weak(value)
+        .map(DateTime::from_system_time)
+        .map_err(|_| QueryError::InvalidParam {
+            field,
+            value: value.to_string(),
+        })
+}
+
+impl EventsFilter {
+    /// Build a mongodb query from the filter.
+    ///
+    /// # Errors
+    /// Raise an [`QueryError::InvalidParam`](QueryError::InvalidParam) if timestamps can't be parsed.
+    pub fn to_document(&self) -> Result<Document, QueryError> {
+        let mut filter = Document::new();
+        if let Some(vtuber) = &self.vtuber {
+            filter.insert("vtuber", vtuber);
+        }
+        if let Some(topic) = &self.topic {
+            filter.insert("topic", topic);
+        }
+        let mut range = Document::new();
+        if let Some(since) = &self.since {
+            range.insert("$gte", parse_time("since", since)?);
+        }
+        if let Some(until) = &self.until {
+            range.insert("$lt", parse_time("until", until)?);
+        }
+        if !range.is_empty() {
+            filter.insert("timestamp", range);
+        }
+        Ok(filter)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EventsQuery {
+    #[serde(flatten)]
+    pub filter: EventsFilter,
+    /// Id of the last event on previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+impl EventsQuery {
+    /// Build a mongodb query and limit from the request.
+    ///
+    /// # Errors
+    /// Raise an [`QueryError::InvalidParam`](QueryError::InvalidParam) if any parameter is malformed.
+    pub fn to_document(&self) -> Result<(Document, u32), QueryError> {
+        let mut filter = self.filter.to_document()?;
+        if let Some(cursor) = &self.cursor {
+            filter.insert("_id", doc! {"$lt": parse_cursor(cursor)?});
+        }
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT);
+        if limit == 0 || limit > MAX_LIMIT {
+            return Err(QueryError::InvalidParam {
+                field: "limit",
+                value: limit.to_string(),
+            });
+        }
+        Ok((filter, limit))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EventView {
+    id: String,
+    vtuber: String,
+    topic: String,
+    timestamp: String,
+    payload: serde_json::Value,
+}
+
+impl From<ArchivedEvent> for EventView {
+    fn from(mut event: ArchivedEvent) -> Self {
+        let payload = event.json_payload();
+        Self {
+            id: event.id.to_hex(),
+            vtuber: event.vtuber,
+            topic: event.topic,
+            timestamp: event.timestamp.to_rfc3339_string(),
+            payload,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EventsPage {
+    events: Vec<EventView>,
+    /// Cursor to fetch next page. `None` if there's no more events.
+    next: Option<String>,
+}
+
+#[get("/events")]
+pub async fn events(
+    coll: Data<Collection<ArchivedEvent>>,
+    query: Query<EventsQuery>,
+) -> Result<Json<EventsPage>, QueryError> {
+    let (filter, limit) = query.to_document()?;
+    let events = QueryEventsOp {
+        filter,
+        limit: Some(i64::from(limit)),
+        oldest_first: false,
+    }
+    .execute(&*coll.into_inner())
+    .await?;
+
+    let next = if events.len() == limit as usize {
+        events.last().map(|event| event.id.to_hex())
+    } else {
+        None
+    };
+    Ok(Json(EventsPage {
+        events: events.into_iter().map(EventView::from).collect(),
+        next,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplayRequest {
+    #[serde(flatten)]
+    pub filter: EventsFilter,
+    /// Ident of the collector to replay to. Replay to all collectors if not set.
+    pub target: Option<String>,
+    /// Id of the last replayed event in previous request.
+    pub cursor: Option<String>,
+    /// Max events to replay. Oldest events are replayed first.
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayResponse {
+    replayed: usize,
+    collectors: Vec<String>,
+    /// Cursor to continue replaying. `None` if there's no more events.
+    next: Option<String>,
+}
+
+#[post("/events/replay")]
+pub async fn replay(
+    coll: Data<Collection<ArchivedEvent>>,
+    ctx: Data<ArbiterContext>,
+    req: Json<ReplayRequest>,
+) -> Result<Json<ReplayResponse>, QueryError> {
+    let req = req.into_inner();
+    let limit = req.limit.unwrap_or(MAX_REPLAY_LIMIT);
+    if limit == 0 || limit > MAX_REPLAY_LIMIT {
+        return Err(QueryError::InvalidParam {
+            field: "limit",
+            value: limit.to_string(),
+        });
+    }
+
+    let mut filter = req.filter.to_document()?;
+    if let Some(cursor) = &req.cursor {
+        filter.insert("_id", doc! {"$gt": parse_cursor(cursor)?});
+    }
+    let archived = QueryEventsOp {
+        filter,
+        limit: Some(i64::from(limit)),
+        oldest_first: true,
+    }
+    .execute(&*coll.into_inner())
+    .await?;
+    let replayed = archived.len();
+    let next = if replayed == limit as usize {
+        archived.last().map(|event| event.id.to_hex())
+    } else {
+        None
+    };
+
+    let collectors = ctx
+        .send::<CollectorActor, _>(Replay {
+            events: archived.into_iter().map(PublishExpanded::from).collect(),
+            target: req.target.clone(),
+        })?
+        .await?;
+    if let (Some(target), true) = (req.target, collectors.is_empty()) {
+        return Err(QueryError::MissingCollector(target));
+    }
+    info!("replayed {} events to {:?}", replayed, collectors);
+
+    Ok(Json(ReplayResponse {
+        replayed,
+        collectors,
+        next,
+    }))
+}
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_web::web::{Data, Json, Query};
+use actix_web::{get, post};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{self, doc, Bson, DateTime};
+use mongodb::options::{FindOptions, ReplaceOptions};
+use mongodb::IndexModel;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::db::{CollOperation, Collection, DBResult, Document};
+use crate::ArbiterContext;
+
+use super::archive::{decode_payload, parse_cursor, EventsFilter, QueryError};
+use super::envelope::EventMeta;
+use super::{CollectorActor, PublishExpanded, Replay};
+
+pub const DEAD_LETTER_COLLECTION: &str = "dead_letters";
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 500;
+const MAX_REPLAY_LIMIT: u32 = 1000;
+
+/// An event given up by a collector after too many failed attempts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// Ident of the collector which rejected the event.
+    pub collector: String,
+    pub vtuber: String,
+    #[serde(default)]
+    pub meta: EventMeta,
+    #[serde(default)]
+    pub source: String,
+    pub topic: String,
+    pub timestamp: DateTime,
+    pub attempts: u32,
+    /// Payload converted to BSON, so that it can be queried.
+    pub payload: Bson,
+    /// Payload serialized as JSON, replayed as is since BSON conversion may change its shape, e.g. integer widths.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_payload: Option<String>,
+}
+
+impl DeadLetter {
+    /// Build a dead letter from an event. A redelivered letter keeps its id, so that it's updated in place.
+    ///
+    /// # Errors
+    /// Raise an error if the payload can't be serialized.
+    pub fn new(
+        collector: String,
+        event: &PublishExpanded,
+        attempts: u32,
+    ) -> serde_json::Result<Self> {
+        let raw_payload = serde_json::to_string(&*event.data)?;
+        // payloads not representable in BSON, e.g. with integers beyond i64, are still kept for replay
+        let payload = bson::to_bson(&*event.data).unwrap_or_else(|e| {
+            warn!("unable to convert payload, keeping raw JSON only: {}", e);
+            Bson::Null
+        });
+        Ok(Self {
+            id: event.dead_letter.unwrap_or_default(),
+            collector,
+            meta: event.meta.clone(),
+            vtuber: event.vtuber.clone(),
+            source: event.source.clone(),
+            topic: event.topic.clone(),
+            timestamp: DateTime::now(),
+            attempts,
+            payload,
+            raw_payload: Some(raw_payload),
+        })
+    }
+}
+
+impl From<DeadLetter> for PublishExpanded {
+    fn from(letter: DeadLetter) -> Self {
+        Self {
+            meta: letter.meta,
+            vtuber: letter.vtuber,
+            source: letter.source,
+            topic: letter.topic,
+            data: Arc::new(decode_payload(letter.raw_payload, letter.payload)),
+            tags: Vec::new(),
+            replay: true,
+            text: None,
+            dead_letter: Some(letter.id),
+        }
+    }
+}
+
+/// Create indexes needed by the dead-letter store.
+///
+/// # Errors
+/// Pass errors raised by mongodb driver.
+pub async fn create_indexes(collection: &Collection<DeadLetter>) -> DBResult<()> {
+    collection
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"collector": 1, "_id": -1})
+                .build(),
+            None,
+        )
+        .await
+        .map(|_| ())
+}
+
+/// Insert a dead letter, or replace the one with the same id.
+#[derive(Debug)]
+pub struct InsertDeadLetterOp(pub DeadLetter);
+
+#[async_trait]
+impl CollOperation for InsertDeadLetterOp {
+    type Result = ();
+    type Item = DeadLetter;
+
+    const DESC: &'static str = "InsertDeadLetter";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .replace_one(
+                doc! {"_id": self.0.id},
+                self.0,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map(|_| ())
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryDeadLettersOp {
+    pub filter: Document,
+    pub limit: i64,
+    /// Return oldest letters first instead of newest ones.
+    pub oldest_first: bool,
+}
+
+#[async_trait]
+impl CollOperation for QueryDeadLettersOp {
+    type Result = Vec<DeadLetter>;
+    type Item = DeadLetter;
+
+    const DESC: &'static str = "QueryDeadLetters";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .find(
+                self.filter,
+                FindOptions::builder()
+                    .sort(doc! {"_id": if self.oldest_first { 1 } else { -1 }})
+                    .limit(self.limit)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await
+    }
+}
+
+#[derive(Debug)]
+pub struct DeleteDeadLettersOp(pub Vec<ObjectId>);
+
+#[async_trait]
+impl CollOperation for DeleteDeadLettersOp {
+    type Result = u64;
+    type Item = DeadLetter;
+
+    const DESC: &'static str = "DeleteDeadLetters";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .delete_many(doc! {"_id": {"$in": self.0}}, None)
+            .await
+            .map(|res| res.deleted_count)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeadLettersFilter {
+    /// Ident of the collector which rejected the events.
+    pub collector: Option<String>,
+    #[serde(flatten)]
+    pub events: EventsFilter,
+}
+
+impl DeadLettersFilter {
+    /// Build a mongodb query from the filter.
+    ///
+    /// # Errors
+    /// Raise an [`QueryError::InvalidParam`](QueryError::InvalidParam) if timestamps can't be parsed.
+    pub fn to_document(&self) -> Result<Document, QueryError> {
+        let mut filter = self.events.to_document()?;
+        if let Some(collector) = &self.collector {
+            filter.insert("collector", collector);
+        }
+        Ok(filter)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DeadLettersQuery {
+    #[serde(flatten)]
+    pub filter: DeadLettersFilter,
+    /// Id of the last letter on previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadLetterView {
+    id: String,
+    collector: String,
+    vtuber: String,
+    topic: String,
+    timestamp: String,
+    attempts: u32,
+    payload: serde_json::Value,
+}
+
+impl From<DeadLetter> for DeadLetterView {
+    fn from(letter: DeadLetter) -> Self {
+        Self {
+            id: letter.id.to_hex(),
+            collector: letter.collector,
+            vtuber: letter.vtuber,
+            topic: letter.topic,
+            timestamp: letter.timestamp.to_rfc3339_string(),
+            attempts: letter.attempts,
+            payload: decode_payload(letter.raw_payload, letter.payload),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadLettersPage {
+    dead_letters: Vec<DeadLetterView>,
+    /// Cursor to fetch next page. `None` if there's no more letters.
+    next: Option<String>,
+}
+
+fn check_limit(limit: u32, max: u32) -> Result<u32, QueryError> {
+    if limit == 0 || limit > max {
+        return Err(QueryError::InvalidParam {
+            field: "limit",
+            value: limit.to_string(),
+        });
+    }
+    Ok(limit)
+}
+
+#[get("/dead_letters")]
+pub async fn dead_letters(
+    coll: Data<Collection<DeadLetter>>,
+    query: Query<DeadLettersQuery>,
+) -> Result<Json<DeadLettersPage>, QueryError> {
+    let limit = check_limit(query.limit.unwrap_or(DEFAULT_LIMIT), MAX_LIMIT)?;
+    let mut filter = query.filter.to_document()?;
+    if let Some(cursor) = &query.cursor {
+        filter.insert("_id", doc! {"$lt": parse_cursor(cursor)?});
+    }
+    let letters = QueryDeadLettersOp {
+        filter,
+        limit: i64::from(limit),
+        oldest_first: false,
+    }
+    .execute(&*coll.into_inner())
+    .await?;
+
+    let next = if letters.len() == limit as usize {
+        letters.last().map(|letter| letter.id.to_hex())
+    } else {
+        None
+    };
+    Ok(Json(DeadLettersPage {
+        dead_letters: letters.into_iter().map(DeadLetterView::from).collect(),
+        next,
+    }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeadLettersReplayRequest {
+    #[serde(flatten)]
+    pub filter: DeadLettersFilter,
+    /// Only replay letters with given ids.
+    pub ids: Option<Vec<String>>,
+    /// Ident of the collector to replay to. Letters are replayed to the collector rejecting them if not set.
+    pub target: Option<String>,
+    /// Max letters to replay. Oldest letters are replayed first.
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadLettersReplayResponse {
+    replayed: usize,
+    collectors: Vec<String>,
+}
+
+/// Replay dead letters. Each letter is removed from the store once its target confirms delivery.
+///
+/// Letters whose target collector doesn't exist on this instance aren't replayed.
+#[post("/dead_letters/replay")]
+pub async fn replay_dead_letters(
+    coll: Data<Collection<DeadLetter>>,
+    ctx: Data<ArbiterContext>,
+    req: Json<DeadLettersReplayRequest>,
+) -> Result<Json<DeadLettersReplayResponse>, QueryError> {
+    let req = req.into_inner();
+    let limit = check_limit(req.limit.unwrap_or(MAX_REPLAY_LIMIT), MAX_REPLAY_LIMIT)?;
+    let mut filter = req.filter.to_document()?;
+    if let Some(ids) = &req.ids {
+        let ids = ids
+            .iter()
+            .map(|id| parse_cursor(id))
+            .collect::<Result<Vec<_>, _>>()?;
+        filter.insert("_id", doc! {"$in": ids});
+    }
+    let coll = coll.into_inner();
+    let letters = QueryDeadLettersOp {
+        filter,
+        limit: i64::from(limit),
+        oldest_first: true,
+    }
+    .execute(&*coll)
+    .await?;
+
+    let mut groups: HashMap<String, Vec<PublishExpanded>> = HashMap::new();
+    for letter in letters {
+        let target = req
+            .target
+            .clone()
+            .unwrap_or_else(|| letter.collector.clone());
+        groups.entry(target).or_default().push(letter.into());
+    }
+
+    let mut replayed = 0;
+    let mut collectors = Vec::new();
+    for (target, events) in groups {
+        let count = events.len();
+        let sent = ctx
+            .send::<CollectorActor, _>(Replay {
+                events,
+                target: Some(target),
+            })?
+            .await?;
+        if !sent.is_empty() {
+            replayed += count;
+            collectors.extend(sent);
+        }
+    }
+    if let (Some(target), true) = (req.target, collectors.is_empty()) {
+        return Err(QueryError::MissingCollector(target));
+    }
+
+    info!("replayed {} dead letters to {:?}", replayed, collectors);
+
+    Ok(Json(DeadLettersReplayResponse {
+        replayed,
+        collectors,
+    }))
+}
@@ -1,8 +1,9 @@
 use actix::{Actor, Context, Handler, Recipient};
 use async_trait::async_trait;
-use tracing::{info, info_span};
+use tracing::{error, info, info_span};
 
-use super::{Collector, CollectorFactory, PublishExpanded};
+use super::envelope::Envelope;
+use super::{Collector, CollectorFactory, Delivery, PublishExpanded};
 
 #[derive(Debug)]
 pub struct DebugCollectorFactory;
@@ -27,13 +28,27 @@ impl Actor for DebugCollector {
 }
 
 impl Handler<PublishExpanded> for DebugCollector {
-    type Result = bool;
+    type Result = Delivery;
 
     fn handle(&mut self, msg: PublishExpanded, _ctx: &mut Self::Context) -> Self::Result {
-        let output = serde_json::to_string(&*msg.data).unwrap();
-        info_span!("debug")
-            .in_scope(|| info!("collected: [{}.{}] {}", msg.vtuber, msg.topic, output));
-        true
+        let _span = info_span!("debug").entered();
+        // log the rendered text if there's a template, or the raw event otherwise
+        let output = match &msg.text {
+            Some(text) => text.clone(),
+            None => match serde_json::to_string(&Envelope::new(&msg)) {
+                Ok(output) => output,
+                Err(e) => {
+                    error!("unable to serialize event: {}", e);
+                    return Delivery::Discarded;
+                }
+            },
+        };
+        let replay = if msg.replay { " (replay)" } else { "" };
+        info!(
+            "collected{}: [{}.{}] {}",
+            replay, msg.vtuber, msg.topic, output
+        );
+        Delivery::Sent
     }
 }
 
@@ -0,0 +1,126 @@
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::UpdateOptions;
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+
+use crate::config::DedupConfig;
+use crate::db::{create_ttl_index, CollOperation, Collection, DBResult};
+use crate::utils::DBErrorExt;
+
+pub const IDEMPOTENCY_COLLECTION: &str = "idempotency_keys";
+
+/// An idempotency key seen recently.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdempotencyKey {
+    /// Key namespaced by the source kind, e.g. `twitter:<key>`.
+    #[serde(rename = "_id")]
+    pub key: String,
+    /// Last time the key is claimed.
+    pub seen_at: DateTime,
+}
+
+/// Drops events already emitted by any worker within a time window.
+#[derive(Debug, Clone)]
+pub struct Dedup {
+    pub collection: Collection<IdempotencyKey>,
+    pub window: Duration,
+}
+
+impl Dedup {
+    pub const fn new(collection: Collection<IdempotencyKey>, config: DedupConfig) -> Self {
+        Self {
+            collection,
+            window: config.window,
+        }
+    }
+
+    /// Claim an idempotency key. Returns `false` if the key has been claimed within the window.
+    ///
+    /// # Errors
+    /// Pass errors raised by mongodb driver.
+    pub async fn claim(&self, source: &str, key: &str) -> DBResult<bool> {
+        ClaimKeyOp {
+            key: format!("{}:{}", source, key),
+            window: self.window,
+        }
+        .execute(&self.collection)
+        .await
+    }
+
+    /// Release a claimed key, so that the event can be emitted again, e.g. after it fails to be queued.
+    ///
+    /// # Errors
+    /// Pass errors raised by mongodb driver.
+    pub async fn release(&self, source: &str, key: &str) -> DBResult<()> {
+        ReleaseKeyOp(format!("{}:{}", source, key))
+            .execute(&self.collection)
+            .await
+    }
+}
+
+/// Create indexes needed by the dedup store, including the TTL index.
+///
+/// Keys are removed by mongodb some time after the window, so expiry is also checked on claim.
+///
+/// # Errors
+/// Pass errors raised by mongodb driver.
+pub async fn create_indexes(
+    db: &Database,
+    collection: &Collection<IdempotencyKey>,
+    window: Duration,
+) -> DBResult<()> {
+    create_ttl_index(db, collection, doc! {"seen_at": 1}, window).await
+}
+
+/// Record a key unless it's seen within the window.
+///
+/// Returns `true` if the key is claimed by this call.
+#[derive(Debug)]
+pub struct ClaimKeyOp {
+    pub key: String,
+    pub window: Duration,
+}
+
+#[async_trait]
+impl CollOperation for ClaimKeyOp {
+    type Result = bool;
+    type Item = IdempotencyKey;
+
+    const DESC: &'static str = "ClaimKey";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        let now = SystemTime::now();
+        // A fresh key doesn't match the filter, so the upsert collides with it on `_id`.
+        collection
+            .update_one(
+                doc! {"_id": &self.key, "seen_at": {"$lt": DateTime::from(now - self.window)}},
+                doc! {"$set": {"seen_at": DateTime::from(now)}},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map(|_| true)
+            .or_else(|e| (e.write() == Some(11000)).then_some(false).ok_or(e))
+    }
+}
+
+/// Forget a claimed key.
+#[derive(Debug)]
+pub struct ReleaseKeyOp(pub String);
+
+#[async_trait]
+impl CollOperation for ReleaseKeyOp {
+    type Result = ();
+    type Item = IdempotencyKey;
+
+    const DESC: &'static str = "ReleaseKey";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .delete_one(doc! {"_id": self.0}, None)
+            .await
+            .map(|_| ())
+    }
+}
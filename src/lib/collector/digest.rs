@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use glob::{Pattern, PatternError};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::DigestConfig;
+use crate::utils::timestamp;
+
+use super::envelope::EventMeta;
+use super::PublishExpanded;
+
+/// Topic of emitted digest events.
+pub const DIGEST_TOPIC: &str = "digest";
+
+/// Compiled digest rules of a collector.
+#[derive(Debug, Clone, Default)]
+pub struct Digest {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    topics: Vec<Pattern>,
+    config: DigestConfig,
+}
+
+impl Digest {
+    /// Compile digest rules.
+    ///
+    /// # Errors
+    /// Raise an error if any topic pattern is malformed.
+    pub fn new(configs: &[DigestConfig]) -> Result<Self, PatternError> {
+        Ok(Self {
+            rules: configs
+                .iter()
+                .map(|config| {
+                    Ok(Rule {
+                        topics: config
+                            .topics
+                            .iter()
+                            .map(|topic| Pattern::new(topic))
+                            .collect::<Result<_, _>>()?,
+                        config: config.clone(),
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Find the first rule summarizing events of given topic.
+    pub fn rule(&self, topic: &str) -> Option<&DigestConfig> {
+        self.rules
+            .iter()
+            .find(|rule| rule.topics.iter().any(|pattern| pattern.matches(topic)))
+            .map(|rule| &rule.config)
+    }
+}
+
+/// An item ranked in a digest.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct TopItem {
+    pub item: String,
+    pub count: u64,
+}
+
+/// Payload of digest events.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Summary {
+    /// Topic of summarized events.
+    pub topic: String,
+    /// When the first summarized event is emitted, in milliseconds.
+    pub since: i64,
+    /// When the last summarized event is emitted, in milliseconds.
+    pub until: i64,
+    /// Count of summarized events.
+    pub count: u64,
+    /// Sum of the numeric values picked from payloads, if configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<f64>,
+    /// Most frequent items picked from payloads, if configured.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub top: Vec<TopItem>,
+}
+
+/// Events of a vtuber and topic collected in the current window.
+#[derive(Debug)]
+pub struct Aggregate {
+    /// Id of the digest event to be emitted.
+    pub id: Uuid,
+    instance: Uuid,
+    vtuber: String,
+    source: String,
+    topic: String,
+    tags: Vec<String>,
+    since: i64,
+    until: i64,
+    count: u64,
+    total: Option<f64>,
+    items: HashMap<String, u64>,
+}
+
+impl Aggregate {
+    pub fn new(event: &PublishExpanded) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            instance: event.meta.instance,
+            vtuber: event.vtuber.clone(),
+            source: event.source.clone(),
+            topic: event.topic.clone(),
+            tags: event.tags.clone(),
+            since: event.meta.occurred_at,
+            until: event.meta.occurred_at,
+            count: 0,
+            total: None,
+            items: HashMap::new(),
+        }
+    }
+
+    /// Add an event to the aggregate. Returns whether the digest should be emitted now.
+    pub fn add(&mut self, config: &DigestConfig, event: &PublishExpanded) -> bool {
+        self.count += 1;
+        self.since = self.since.min(event.meta.occurred_at);
+        self.until = self.until.max(event.meta.occurred_at);
+
+        if config.total.is_some() || config.item.is_some() {
+            let payload = serde_json::to_value(&*event.data).unwrap_or_default();
+            if let Some(pointer) = &config.total {
+                if let Some(value) = payload.pointer(pointer).and_then(serde_json::Value::as_f64) {
+                    *self.total.get_or_insert(0.) += value;
+                }
+            }
+            if let Some(pointer) = &config.item {
+                let item = match payload.pointer(pointer) {
+                    Some(serde_json::Value::String(item)) => Some(item.clone()),
+                    Some(serde_json::Value::Null) | None => None,
+                    Some(value) => Some(value.to_string()),
+                };
+                if let Some(item) = item {
+                    *self.items.entry(item).or_default() += 1;
+                }
+            }
+        }
+
+        config.max_events > 0 && self.count >= config.max_events
+    }
+
+    /// Build the digest event.
+    pub fn finish(self, config: &DigestConfig) -> PublishExpanded {
+        let mut top: Vec<_> = self
+            .items
+            .into_iter()
+            .map(|(item, count)| TopItem { item, count })
+            .collect();
+        // most frequent first, ties broken by name to keep the output stable
+        top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.item.cmp(&b.item)));
+        top.truncate(config.top);
+
+        let summary = Summary {
+            topic: self.topic,
+            since: self.since,
+            until: self.until,
+            count: self.count,
+            total: self.total,
+            top,
+        };
+        PublishExpanded {
+            meta: EventMeta {
+                id: self.id,
+                occurred_at: timestamp(SystemTime::now()),
+                instance: self.instance,
+                idempotency_key: None,
+                fencing_token: None,
+            },
+            vtuber: self.vtuber,
+            source: self.source,
+            topic: String::from(DIGEST_TOPIC),
+            data: Arc::new(summary),
+            tags: self.tags,
+            replay: false,
+            text: None,
+            dead_letter: None,
+        }
+    }
+}
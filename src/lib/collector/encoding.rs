@@ -0,0 +1,72 @@
+use std::io::{self, Write};
+
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::config::{Compression, EncodingConfig, PayloadFormat};
+
+#[derive(Debug, Error)]
+pub enum EncodeError {
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("msgpack error: {0}")]
+    MsgPack(#[from] rmp_serde::encode::Error),
+    #[error("cbor error: {0}")]
+    Cbor(#[from] ciborium::ser::Error<io::Error>),
+    #[error("compression error: {0}")]
+    Compress(#[from] io::Error),
+}
+
+/// MIME type of given format.
+pub const fn content_type(format: PayloadFormat) -> &'static str {
+    match format {
+        PayloadFormat::Json => "application/json",
+        PayloadFormat::MsgPack => "application/msgpack",
+        PayloadFormat::Cbor => "application/cbor",
+    }
+}
+
+/// Content encoding of given compression, or `None` if not compressed.
+pub const fn content_encoding(compression: Compression) -> Option<&'static str> {
+    match compression {
+        Compression::None => None,
+        Compression::Gzip => Some("gzip"),
+        Compression::Zstd => Some("zstd"),
+    }
+}
+
+/// Serialize and compress a value.
+///
+/// If `delimited` is set, json values are terminated with a newline so that encoded values can be concatenated.
+/// Other formats are self-delimiting.
+///
+/// # Errors
+/// Raise an error if the value can't be serialized in given format, or compression fails.
+pub fn encode<T: Serialize + ?Sized>(
+    value: &T,
+    encoding: EncodingConfig,
+    delimited: bool,
+) -> Result<Vec<u8>, EncodeError> {
+    let mut data = match encoding.format {
+        PayloadFormat::Json => serde_json::to_vec(value)?,
+        PayloadFormat::MsgPack => rmp_serde::to_vec_named(value)?,
+        PayloadFormat::Cbor => {
+            let mut data = Vec::new();
+            ciborium::ser::into_writer(value, &mut data)?;
+            data
+        }
+    };
+    if delimited && encoding.format == PayloadFormat::Json {
+        data.push(b'\n');
+    }
+    Ok(match encoding.compression {
+        Compression::None => data,
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&data)?;
+            encoder.finish()?
+        }
+        Compression::Zstd => zstd::encode_all(data.as_slice(), 0)?,
+    })
+}
@@ -0,0 +1,137 @@
+use std::time::SystemTime;
+
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::utils::timestamp;
+
+use super::PublishExpanded;
+
+/// Version of the envelope format. Bumped on every breaking change.
+pub const ENVELOPE_VERSION: u32 = 1;
+
+/// Metadata assigned to an event when it's emitted by a source.
+///
+/// It's persisted with the event, so retried and replayed events keep their original metadata.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct EventMeta {
+    /// Unique id of the event.
+    pub id: Uuid,
+    /// When the event is emitted by the source, in milliseconds.
+    pub occurred_at: i64,
+    /// Id of the instance the event is emitted on.
+    pub instance: Uuid,
+    /// Deterministic key set by the source, identifying duplicated events.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Lease of the entry held by the emitting task.
+    #[serde(default)]
+    pub fencing_token: Option<FencingToken>,
+}
+
+/// Lease of an entry held by the task emitting an event.
+///
+/// Epochs only grow, so events carrying an epoch older than the latest seen one of the same entry are from stale owners.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FencingToken {
+    /// Id of the entry.
+    pub entry: String,
+    /// Lease epoch of the entry, bumped every time it's acquired.
+    pub epoch: u64,
+}
+
+impl Default for EventMeta {
+    fn default() -> Self {
+        Self {
+            id: Uuid::nil(),
+            occurred_at: 0,
+            instance: Uuid::nil(),
+            idempotency_key: None,
+            fencing_token: None,
+        }
+    }
+}
+
+/// Envelope wrapping events delivered to consumers.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Envelope<T> {
+    /// Version of the envelope format.
+    pub version: u32,
+    /// Unique id of the event. Retried and replayed events keep their id.
+    pub id: Uuid,
+    pub vtuber: String,
+    /// Kind of the source emitting the event, e.g. `bililive`.
+    pub source: String,
+    pub topic: String,
+    /// When the event is emitted by the source, in milliseconds.
+    pub occurred_at: i64,
+    /// When the event is delivered by the collector, in milliseconds.
+    pub emitted_at: i64,
+    /// Id of the instance the event is emitted on.
+    pub instance: Uuid,
+    /// Deterministic key set by the source. Events with the same key are duplicates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Lease of the entry held by the emitting task. Consumers may drop events of stale owners with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fencing_token: Option<FencingToken>,
+    /// Whether the event is re-delivered.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replay: bool,
+    /// Human-readable text rendered from the template of the topic, if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    pub payload: T,
+}
+
+impl<'a> Envelope<&'a (dyn erased_serde::Serialize + Send + Sync)> {
+    /// Wrap an event, setting the emission time to now.
+    pub fn new(event: &'a PublishExpanded) -> Self {
+        Self {
+            version: ENVELOPE_VERSION,
+            id: event.meta.id,
+            vtuber: event.vtuber.clone(),
+            source: event.source.clone(),
+            topic: event.topic.clone(),
+            occurred_at: event.meta.occurred_at,
+            emitted_at: timestamp(SystemTime::now()),
+            instance: event.meta.instance,
+            idempotency_key: event.meta.idempotency_key.clone(),
+            fencing_token: event.meta.fencing_token.clone(),
+            replay: event.replay,
+            text: event.text.clone(),
+            payload: &*event.data,
+        }
+    }
+}
+
+impl<T: Serialize> Envelope<T> {
+    /// Convert the payload into a json value.
+    ///
+    /// # Errors
+    /// Raise an error if the payload can't be serialized.
+    pub fn into_value(self) -> serde_json::Result<Envelope<serde_json::Value>> {
+        Ok(Envelope {
+            version: self.version,
+            id: self.id,
+            vtuber: self.vtuber,
+            source: self.source,
+            topic: self.topic,
+            occurred_at: self.occurred_at,
+            emitted_at: self.emitted_at,
+            instance: self.instance,
+            idempotency_key: self.idempotency_key,
+            fencing_token: self.fencing_token,
+            replay: self.replay,
+            text: self.text,
+            payload: serde_json::to_value(self.payload)?,
+        })
+    }
+}
+
+/// JSON Schema of the envelope with an arbitrary payload.
+pub fn envelope_schema() -> RootSchema {
+    schema_for!(Envelope<serde_json::Value>)
+}
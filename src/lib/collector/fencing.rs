@@ -0,0 +1,123 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use mongodb::bson::doc;
+use mongodb::options::UpdateOptions;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::FencingConfig;
+use crate::db::{CollOperation, Collection, DBResult};
+use crate::utils::DBErrorExt;
+
+use super::envelope::FencingToken;
+
+pub const FENCE_COLLECTION: &str = "fences";
+
+/// The latest lease epoch of an entry seen by collectors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fence {
+    /// Id of the entry.
+    #[serde(rename = "_id")]
+    pub entry: String,
+    pub epoch: u64,
+}
+
+/// Rejects events emitted by stale owners of entries, on any instance.
+///
+/// Admitted epochs are cached per entry for `cache_ttl`, so that only the first event of an epoch hits mongodb.
+#[derive(Debug, Clone)]
+pub struct Fencing {
+    pub collection: Collection<Fence>,
+    pub cache_ttl: Duration,
+    /// Latest admitted epoch of entries, and when it's checked against mongodb.
+    cache: Arc<Mutex<HashMap<String, (u64, Instant)>>>,
+}
+
+impl Fencing {
+    pub fn new(collection: Collection<Fence>, config: FencingConfig) -> Self {
+        Self {
+            collection,
+            cache_ttl: config.cache_ttl,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Check a fencing token. Returns `false` if a newer owner of the entry has emitted events.
+    ///
+    /// # Errors
+    /// Pass errors raised by mongodb driver.
+    pub async fn admit(&self, token: &FencingToken) -> DBResult<bool> {
+        if let Some(admitted) = self.cached(token) {
+            return Ok(admitted);
+        }
+        let admitted = AdmitTokenOp {
+            entry: token.entry.clone(),
+            epoch: token.epoch,
+        }
+        .execute(&self.collection)
+        .await?;
+        if admitted {
+            let mut cache = self.cache.lock();
+            cache.retain(|_, (_, checked_at)| checked_at.elapsed() < self.cache_ttl);
+            let cached = cache
+                .entry(token.entry.clone())
+                .or_insert((token.epoch, Instant::now()));
+            // tokens of the same entry may be checked concurrently, so never go back to an older epoch
+            if token.epoch >= cached.0 {
+                *cached = (token.epoch, Instant::now());
+            }
+        }
+        Ok(admitted)
+    }
+
+    /// Check a token against the cached epoch of its entry.
+    ///
+    /// Returns `None` if it must be checked against mongodb, i.e. the epoch changes or the cache expires.
+    fn cached(&self, token: &FencingToken) -> Option<bool> {
+        let cache = self.cache.lock();
+        let (epoch, checked_at) = cache.get(&token.entry)?;
+        if checked_at.elapsed() >= self.cache_ttl {
+            return None;
+        }
+        match token.epoch.cmp(epoch) {
+            Ordering::Less => Some(false),
+            Ordering::Equal => Some(true),
+            Ordering::Greater => None,
+        }
+    }
+}
+
+/// Record the epoch of an entry unless a newer one is seen.
+///
+/// Returns `true` if the epoch is not older than the latest seen one.
+#[derive(Debug)]
+pub struct AdmitTokenOp {
+    pub entry: String,
+    pub epoch: u64,
+}
+
+#[async_trait]
+impl CollOperation for AdmitTokenOp {
+    type Result = bool;
+    type Item = Fence;
+
+    const DESC: &'static str = "AdmitToken";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        let epoch = self.epoch as i64;
+        // A newer epoch doesn't match the filter, so the upsert collides with it on `_id`.
+        collection
+            .update_one(
+                doc! {"_id": &self.entry, "epoch": {"$lte": epoch}},
+                doc! {"$set": {"epoch": epoch}},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map(|_| true)
+            .or_else(|e| (e.write() == Some(11000)).then_some(false).ok_or(e))
+    }
+}
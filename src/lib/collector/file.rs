@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use actix::{Actor, AsyncContext, Context, Handler, Recipient};
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use parking_lot::Mutex;
+use tracing::{error, info, info_span, warn, Span};
+
+use crate::config::{FileCollectorConfig, FsyncPolicy};
+use crate::utils::timestamp;
+
+use super::encoding::encode;
+use super::envelope::Envelope;
+use super::{Collector, CollectorFactory, Delivery, PublishExpanded};
+
+// Files not written for this long will be closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Builds file collectors sharing one writer, so that collectors on all arbiters append to and rotate the same
+/// files.
+#[derive(Debug, Clone)]
+pub struct FileFactory {
+    path: String,
+    writer: Arc<Mutex<FileWriter>>,
+}
+
+impl FileFactory {
+    pub fn new(config: FileCollectorConfig) -> Self {
+        Self {
+            path: config.path.clone(),
+            writer: Arc::new(Mutex::new(FileWriter::new(config))),
+        }
+    }
+}
+
+#[async_trait]
+impl CollectorFactory for FileFactory {
+    fn ident(&self) -> String {
+        format!("file(path={})", self.path)
+    }
+
+    async fn build(&self) -> Option<Recipient<PublishExpanded>> {
+        Some(
+            FileCollector::new(self.path.clone(), self.writer.clone())
+                .start()
+                .recipient(),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct OpenedFile {
+    file: File,
+    size: u64,
+    opened_at: Instant,
+    last_write: Instant,
+    dirty: bool,
+}
+
+impl OpenedFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        let now = Instant::now();
+        Ok(Self {
+            file,
+            size,
+            opened_at: now,
+            last_write: now,
+            dirty: false,
+        })
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Open files of all collectors built by the same factory.
+#[derive(Debug)]
+struct FileWriter {
+    config: FileCollectorConfig,
+    files: HashMap<PathBuf, OpenedFile>,
+}
+
+impl FileWriter {
+    fn new(config: FileCollectorConfig) -> Self {
+        Self {
+            config,
+            files: HashMap::new(),
+        }
+    }
+
+    fn render_path(&self, vtuber: &str, topic: &str, now: SystemTime) -> PathBuf {
+        let date = humantime::format_rfc3339_seconds(now).to_string();
+        PathBuf::from(
+            self.config
+                .path
+                .replace("{date}", &date[..10])
+                .replace("{vtuber}", &sanitize(vtuber))
+                .replace("{topic}", &sanitize(topic)),
+        )
+    }
+
+    fn should_rotate(&self, file: &OpenedFile, incoming: u64) -> bool {
+        let oversize = self
+            .config
+            .max_size
+            .is_some_and(|max_size| file.size > 0 && file.size + incoming > max_size);
+        let outdated = self
+            .config
+            .max_age
+            .is_some_and(|max_age| file.opened_at.elapsed() >= max_age);
+        oversize || outdated
+    }
+
+    fn rotate(&mut self, path: &Path) -> io::Result<()> {
+        if let Some(mut file) = self.files.remove(path) {
+            file.sync()?;
+        }
+
+        let mut rotated = path.to_path_buf().into_os_string();
+        rotated.push(format!(".{}", timestamp(SystemTime::now())));
+        let rotated = PathBuf::from(rotated);
+        fs::rename(path, &rotated)?;
+        info!("rotated {} to {}", path.display(), rotated.display());
+
+        if self.config.compress {
+            actix_rt::task::spawn_blocking(move || {
+                if let Err(e) = compress(&rotated) {
+                    error!("unable to compress {}: {}", rotated.display(), e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, msg: &PublishExpanded, line: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now();
+        let path = self.render_path(&msg.vtuber, &msg.topic, now);
+        if !self.files.contains_key(&path) {
+            // The file may be left by a previous run, in which case we append to it.
+            self.files.insert(path.clone(), OpenedFile::open(&path)?);
+        }
+        if self.should_rotate(&self.files[&path], line.len() as u64) {
+            self.rotate(&path)?;
+            self.files.insert(path.clone(), OpenedFile::open(&path)?);
+        }
+
+        // SAFETY: ensured by the code above
+        let file = self.files.get_mut(&path).unwrap();
+        file.file.write_all(line)?;
+        file.size += line.len() as u64;
+        file.last_write = Instant::now();
+        file.dirty = true;
+        if self.config.fsync == FsyncPolicy::Always {
+            file.sync()?;
+        }
+        Ok(())
+    }
+
+    fn housekeep(&mut self) {
+        let periodic = self.config.fsync == FsyncPolicy::Periodic;
+        self.files.retain(|path, file| {
+            let idle = file.last_write.elapsed() >= IDLE_TIMEOUT;
+            if periodic || idle {
+                if let Err(e) = file.sync() {
+                    error!("unable to sync {}: {}", path.display(), e);
+                }
+            }
+            // close idle files
+            !idle
+        });
+    }
+}
+
+#[derive(Debug)]
+pub struct FileCollector {
+    path: String,
+    writer: Arc<Mutex<FileWriter>>,
+}
+
+impl_stop_on_panic!(FileCollector);
+
+impl Collector for FileCollector {}
+
+impl FileCollector {
+    fn new(path: String, writer: Arc<Mutex<FileWriter>>) -> Self {
+        Self { path, writer }
+    }
+
+    fn span(&self) -> Span {
+        info_span!("file", path = %self.path)
+    }
+}
+
+fn sanitize(s: &str) -> String {
+    s.replace(['/', '\\', '\0'], "_")
+}
+
+fn compress(path: &Path) -> io::Result<()> {
+    let mut gz_path = path.to_path_buf().into_os_string();
+    gz_path.push(".gz");
+
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(gz_path)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+impl Actor for FileCollector {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let interval = {
+            let writer = self.writer.lock();
+            if writer.config.fsync == FsyncPolicy::Periodic {
+                writer.config.fsync_interval
+            } else {
+                IDLE_TIMEOUT
+            }
+        };
+        ctx.run_interval(interval, |act, _| {
+            let span = act.span();
+            span.in_scope(|| act.writer.lock().housekeep());
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        let _span = self.span().entered();
+        for (path, file) in &mut self.writer.lock().files {
+            if let Err(e) = file.sync() {
+                warn!("unable to sync {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl Handler<PublishExpanded> for FileCollector {
+    type Result = Delivery;
+
+    fn handle(&mut self, msg: PublishExpanded, _ctx: &mut Self::Context) -> Self::Result {
+        let _span = self.span().entered();
+        let mut writer = self.writer.lock();
+        let line = match encode(&Envelope::new(&msg), writer.config.encoding, true) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("unable to encode event: {}", e);
+                return Delivery::Rejected;
+            }
+        };
+        match writer.write(&msg, &line) {
+            Ok(()) => Delivery::Sent,
+            Err(e) => {
+                error!("unable to write event: {}", e);
+                Delivery::Failed
+            }
+        }
+    }
+}
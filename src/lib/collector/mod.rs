@@ -1,33 +1,71 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::hash::{Hash, Hasher};
-use std::ops::{Add, Deref};
+use std::ops::{Add, AddAssign, Deref};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use actix::fut::{ready, wrap_future};
 use actix::{
-    Actor, ActorFutureExt, AsyncContext, AtomicResponse, Handler, Message, Recipient,
-    ResponseActFuture, WrapFuture,
+    Actor, ActorFutureExt, AsyncContext, AtomicResponse, Handler, Message, MessageResponse,
+    Recipient, ResponseActFuture, SystemService, WrapFuture,
 };
-use arraydeque::{ArrayDeque, Wrapping};
+use actix_web::get;
+use actix_web::web::{Data, Json};
 use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
 use mongodb::Database;
-use serde::Serialize;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, info_span, trace, warn, Span};
 use tracing_actix::ActorInstrument;
+use uuid::Uuid;
 
-use crate::db::{DBOperation, DBRef, DBResult};
+use crate::common::ResponseWrapper;
+use crate::config::{DigestConfig, RetryPolicyConfig};
+use crate::db::{CollOperation, Collection, DBOperation, DBRef, DBResult};
 use crate::manager::Vtuber;
-use crate::ArbiterContext;
+use crate::scheduler::messages::GetId;
+use crate::server::{Drain, KillerActor, RegisterDrain};
+use crate::utils::timestamp;
+use crate::{ArbiterContext, InstanceContext};
+
+use archive::QueryError;
+use dead_letter::{DeadLetter, DeleteDeadLettersOp, InsertDeadLetterOp, DEAD_LETTER_COLLECTION};
+use dedup::Dedup;
+use digest::{Aggregate, Digest};
+use envelope::{EventMeta, FencingToken};
+use fencing::Fencing;
+use outbox::{
+    AckOutboxOp, InsertOutboxOp, LoadOutboxOp, Outbox, OutboxEntry, ReclaimOutboxOp, RenewOutboxOp,
+    UnloadOutboxOp,
+};
+use priority::{Lanes, Priorities, Priority};
+use routing::Routing;
+use template::Templates;
 
 pub mod amqp;
+pub mod archive;
+pub mod dead_letter;
 pub mod debug;
+pub mod dedup;
+pub mod digest;
+pub mod encoding;
+pub mod envelope;
+pub mod fencing;
+pub mod file;
+pub mod outbox;
+pub mod priority;
+pub mod routing;
+pub mod stream;
+pub mod template;
 
 #[cfg(test)]
 mod tests;
 
+const QUEUE_SIZE: usize = 1024;
+
 fn span() -> Span {
     let arb_id = ArbiterContext::with(|ctx| ctx.arbiter_id);
     info_span!("collector", arb=?arb_id)
@@ -38,6 +76,12 @@ fn span() -> Span {
 #[rtype("()")]
 pub struct Publish {
     root: DBRef,
+    id: Uuid,
+    /// When the event is emitted, in milliseconds.
+    occurred_at: i64,
+    idempotency_key: Option<String>,
+    fencing_token: Option<FencingToken>,
+    source: String,
     topic: String,
     data: Arc<dyn erased_serde::Serialize + Send + Sync>,
 }
@@ -46,6 +90,11 @@ impl Debug for Publish {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Publish")
             .field("root", &self.root)
+            .field("id", &self.id)
+            .field("occurred_at", &self.occurred_at)
+            .field("idempotency_key", &self.idempotency_key)
+            .field("fencing_token", &self.fencing_token)
+            .field("source", &self.source)
             .field("topic", &self.topic)
             .field("data", &"...")
             .finish()
@@ -53,53 +102,192 @@ impl Debug for Publish {
 }
 
 impl Publish {
-    async fn expand(self, db: Database) -> DBResult<Option<PublishExpanded>> {
+    async fn expand(self, db: Database, instance: Uuid) -> DBResult<Option<PublishExpanded>> {
         let vtuber = self.root.get::<Vtuber>().execute(&db).await?;
+        let meta = EventMeta {
+            id: self.id,
+            occurred_at: self.occurred_at,
+            instance,
+            idempotency_key: self.idempotency_key,
+            fencing_token: self.fencing_token,
+        };
         Ok(vtuber.map(|vtuber| PublishExpanded {
+            meta,
             vtuber: vtuber.name,
+            source: self.source,
             topic: self.topic,
             data: self.data,
+            tags: vtuber.tags,
+            replay: false,
+            text: None,
+            dead_letter: None,
         }))
     }
 }
 
 #[derive(Clone, Message)]
-#[rtype("bool")]
+#[rtype("Delivery")]
 pub struct PublishExpanded {
-    // TODO more fields?
+    meta: EventMeta,
     vtuber: String,
+    /// Kind of the source task emitting this event, e.g. `bililive`.
+    source: String,
     topic: String,
     data: Arc<dyn erased_serde::Serialize + Send + Sync>,
+    /// Tags of the vtuber, used for routing.
+    tags: Vec<String>,
+    /// Whether this event is re-delivered from the archive.
+    replay: bool,
+    /// Human-readable text rendered from the template of the topic, set right before delivery.
+    text: Option<String>,
+    /// Id of the dead letter this event is redelivered from. The letter is removed once the event is delivered.
+    dead_letter: Option<ObjectId>,
 }
 
 impl Debug for PublishExpanded {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("PublishExpanded")
+            .field("meta", &self.meta)
             .field("vtuber", &self.vtuber)
+            .field("source", &self.source)
             .field("topic", &self.topic)
             .field("data", &"...")
+            .field("tags", &self.tags)
+            .field("replay", &self.replay)
+            .field("text", &self.text)
+            .field("dead_letter", &self.dead_letter)
             .finish()
     }
 }
 
+/// Outcome of delivering an event to a collector.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, MessageResponse)]
+pub enum Delivery {
+    /// The event is accepted by the collector.
+    Sent,
+    /// The event itself is refused, e.g. it's nacked or can't be encoded. Counts towards `max_attempts`.
+    Rejected,
+    /// The collector is unavailable, e.g. the connection is lost. The event is retried without counting an attempt.
+    Failed,
+    /// The event can't be delivered and retrying won't help, e.g. it's unroutable. It's dropped.
+    Discarded,
+}
+
+/// Re-deliver events to collectors.
+///
+/// Events are sent to the collector with given ident, or all collectors if `target` is `None`.
+/// Routing rules don't apply to replayed events.
+/// Returns idents of collectors the events are sent to.
+#[derive(Debug, Clone, Message)]
+#[rtype("Vec<String>")]
+pub struct Replay {
+    pub events: Vec<PublishExpanded>,
+    pub target: Option<String>,
+}
+
 impl Publish {
     /// Creates a new `Publish` event.
     ///
     /// # Panics
     /// Panics when given `data` can't be serialized into json.
-    pub fn new<T: 'static + Serialize + Send + Sync>(root: DBRef, topic: &str, data: T) -> Self {
+    pub fn new<T: 'static + Serialize + Send + Sync>(
+        root: DBRef,
+        source: &str,
+        topic: &str,
+        data: T,
+    ) -> Self {
         Self {
             root,
+            id: Uuid::new_v4(),
+            occurred_at: timestamp(SystemTime::now()),
+            idempotency_key: None,
+            fencing_token: None,
+            source: source.to_string(),
             topic: topic.to_string(),
             data: Arc::new(data),
         }
     }
+
+    /// Set a deterministic key identifying the event, so that duplicates can be dropped.
+    #[must_use]
+    pub fn idempotency_key(mut self, key: Option<String>) -> Self {
+        self.idempotency_key = key;
+        self
+    }
+
+    /// Set the lease of the entry held by the emitting task, so that events of stale owners can be rejected.
+    #[must_use]
+    pub fn fencing_token(mut self, token: FencingToken) -> Self {
+        self.fencing_token = Some(token);
+        self
+    }
 }
 
 #[derive(Debug, Clone, Message)]
 #[rtype("()")]
 struct Wake(CollectorFactoryWrapped);
 
+/// Load spilled events of a collector from the outbox.
+#[derive(Debug, Clone, Message)]
+#[rtype("()")]
+struct Refill(CollectorFactoryWrapped);
+
+/// Emit the digest of a vtuber and topic when its window ends.
+#[derive(Debug, Clone, Message)]
+#[rtype("()")]
+struct FlushDigest {
+    factory: CollectorFactoryWrapped,
+    key: (String, String),
+    /// Id of the aggregate, so that a digest emitted early won't flush the next window.
+    id: Uuid,
+}
+
+/// Get statistics of collectors, keyed by their idents.
+#[derive(Debug, Copy, Clone, Message)]
+#[rtype("HashMap<String, CollectorStats>")]
+pub struct GetStats;
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CollectorStats {
+    /// Events waiting in memory.
+    pub queued: usize,
+    /// Events acknowledged by the collector.
+    pub delivered: u64,
+    /// Events left in the outbox because the queue is full. They will be loaded later.
+    pub spilled: u64,
+    /// Events lost because the queue is full and they can't be persisted, or discarded by the collector.
+    pub dropped: u64,
+    /// Events moved to the dead-letter store after too many failed attempts.
+    pub dead_lettered: u64,
+    /// Events summarized into digests instead of being sent.
+    pub digested: u64,
+    /// Low-priority events discarded by sampling when the queue is under pressure.
+    pub sampled: u64,
+}
+
+impl AddAssign for CollectorStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.queued += rhs.queued;
+        self.delivered += rhs.delivered;
+        self.spilled += rhs.spilled;
+        self.dropped += rhs.dropped;
+        self.dead_lettered += rhs.dead_lettered;
+        self.digested += rhs.digested;
+        self.sampled += rhs.sampled;
+    }
+}
+
+/// An event in a collector queue.
+#[derive(Debug, Clone)]
+struct QueuedEvent {
+    event: PublishExpanded,
+    /// Id of the persisted copy in the outbox.
+    outbox_id: Option<ObjectId>,
+    /// Failed delivery attempts.
+    attempts: u32,
+    priority: Priority,
+}
+
 pub trait Collector: Actor<Context = actix::Context<Self>> + Handler<PublishExpanded> {}
 
 #[async_trait]
@@ -109,23 +297,99 @@ pub trait CollectorFactory: Debug {
 }
 
 #[derive(Debug, Clone)]
-pub struct CollectorFactoryWrapped(Rc<dyn CollectorFactory>);
+pub struct CollectorFactoryWrapped {
+    factory: Rc<dyn CollectorFactory>,
+    retry: RetryPolicyConfig,
+    routing: Routing,
+    digest: Digest,
+    templates: Option<Arc<Templates>>,
+    locale: Option<String>,
+}
+
+impl CollectorFactoryWrapped {
+    /// Set the retry policy of the collector.
+    #[must_use]
+    pub const fn retry(mut self, policy: RetryPolicyConfig) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Set the routing rules of the collector.
+    #[must_use]
+    pub fn routing(mut self, routing: Routing) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    /// Set the digest rules of the collector.
+    #[must_use]
+    pub fn digest(mut self, digest: Digest) -> Self {
+        self.digest = digest;
+        self
+    }
+
+    /// Render events with templates in given locale, falling back to the default locale of templates.
+    #[must_use]
+    pub fn templates(mut self, templates: Arc<Templates>, locale: Option<String>) -> Self {
+        self.templates = Some(templates);
+        self.locale = locale;
+        self
+    }
+
+    /// Attach the rendered text to an event, if there's a template for its topic.
+    fn render(&self, mut event: PublishExpanded) -> PublishExpanded {
+        if let Some(templates) = &self.templates {
+            let locale = self
+                .locale
+                .as_deref()
+                .unwrap_or_else(|| templates.default_locale());
+            event.text = templates.render(&event, locale).unwrap_or_else(|e| {
+                error!("unable to render template, delivering without text: {}", e);
+                None
+            });
+        }
+        event
+    }
+
+    /// Delay before next attempt after given consecutive failures.
+    fn backoff(&self, failures: u32) -> Duration {
+        let policy = &self.retry;
+        let delay = policy
+            .multiplier
+            .checked_pow(failures.saturating_sub(1))
+            .and_then(|factor| policy.initial_backoff.checked_mul(factor))
+            .map_or(policy.max_backoff, |delay| delay.min(policy.max_backoff));
+        if policy.jitter {
+            // wait for 50% ~ 100% of the delay
+            delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+        } else {
+            delay
+        }
+    }
+}
 
 impl<T: 'static + CollectorFactory> From<T> for CollectorFactoryWrapped {
     fn from(factory: T) -> Self {
-        Self(Rc::new(factory))
+        Self {
+            factory: Rc::new(factory),
+            retry: RetryPolicyConfig::default(),
+            routing: Routing::default(),
+            digest: Digest::default(),
+            templates: None,
+            locale: None,
+        }
     }
 }
 
 impl Hash for CollectorFactoryWrapped {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.ident().hash(state);
+        self.factory.ident().hash(state);
     }
 }
 
 impl PartialEq for CollectorFactoryWrapped {
     fn eq(&self, other: &Self) -> bool {
-        self.0.ident() == other.0.ident()
+        self.factory.ident() == other.factory.ident()
     }
 }
 
@@ -135,7 +399,7 @@ impl Deref for CollectorFactoryWrapped {
     type Target = Rc<dyn CollectorFactory>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.factory
     }
 }
 
@@ -155,11 +419,172 @@ impl Default for State {
 #[derive(Debug, Default)]
 struct Context {
     state: State,
-    queue: ArrayDeque<[PublishExpanded; 1024], Wrapping>,
+    queue: Lanes,
+    stats: CollectorStats,
+    /// Whether there are events in the outbox not loaded into the queue.
+    spilled: bool,
+    /// Bumped on each spill, so that a finished refill won't clear `spilled` by mistake.
+    spill_gen: u64,
+    refilling: bool,
+    /// Consecutive failures, used by the circuit breaker.
+    failures: u32,
+    /// Digests being collected, keyed by vtuber and topic.
+    digests: HashMap<(String, String), Aggregate>,
+}
+
+impl Context {
+    fn is_full(&self) -> bool {
+        self.queue.len() >= QUEUE_SIZE
+    }
+
+    /// Push an event into the queue.
+    ///
+    /// When the queue is full, persisted events are left in the outbox and their ids are returned,
+    /// so that they can be marked as not loaded. Others are dropped, evicting the oldest in-memory
+    /// event of the lowest priority if possible.
+    fn push(
+        &mut self,
+        factory: &CollectorFactoryWrapped,
+        event: QueuedEvent,
+        ctx: &mut <CollectorActor as Actor>::Context,
+    ) -> Option<ObjectId> {
+        if self.is_full() {
+            if let Some(id) = event.outbox_id {
+                self.spill();
+                return Some(id);
+            }
+            self.stats.dropped += 1;
+            warn!("queue of {} is full, dropping event", factory.ident());
+            if !self.queue.evict(event.priority) {
+                return None;
+            }
+        }
+        if matches!(self.state, State::Available(_)) && self.queue.is_empty()
+            || matches!(self.state, State::Uninit)
+        {
+            // collector available & queue empty | lazy init, schedule wake
+            ctx.notify(Wake(factory.clone()));
+        }
+        self.queue.push_back(event);
+        None
+    }
+
+    /// Leave an event in the outbox and load it when the queue drains.
+    fn spill(&mut self) {
+        self.stats.spilled += 1;
+        self.spilled = true;
+        self.spill_gen += 1;
+    }
+
+    /// Record a failure and wait before next attempt.
+    fn delay(&mut self, factory: &CollectorFactoryWrapped) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        let threshold = factory.retry.breaker_threshold;
+        let delay = if threshold > 0 && self.failures >= threshold {
+            if self.failures == threshold {
+                warn!("circuit breaker of {} open", factory.ident());
+            }
+            factory.retry.breaker_cooldown
+        } else {
+            factory.backoff(self.failures)
+        };
+        self.state = State::DelayedEstablish(Instant::now().add(delay));
+        delay
+    }
+
+    /// Record a failed delivery and put the event back.
+    ///
+    /// An attempt is counted only if the event is `rejected`, i.e. the failure is caused by the event itself.
+    /// Returns the event if it has run out of attempts.
+    fn fail(
+        &mut self,
+        factory: &CollectorFactoryWrapped,
+        mut event: QueuedEvent,
+        rejected: bool,
+    ) -> (Duration, Option<QueuedEvent>) {
+        let threshold = factory.retry.breaker_threshold;
+        // trials when the breaker is open don't count
+        if rejected && (threshold == 0 || self.failures < threshold) {
+            event.attempts += 1;
+        }
+        let delay = self.delay(factory);
+
+        let max_attempts = factory.retry.max_attempts;
+        if max_attempts > 0 && event.attempts >= max_attempts {
+            self.stats.dead_lettered += 1;
+            (delay, Some(event))
+        } else {
+            self.queue.push_front(event);
+            (delay, None)
+        }
+    }
+
+    /// Reset the circuit breaker because the collector is working.
+    fn recover(&mut self, factory: &CollectorFactoryWrapped) {
+        let threshold = factory.retry.breaker_threshold;
+        if threshold > 0 && self.failures >= threshold {
+            info!("circuit breaker of {} closed", factory.ident());
+        }
+        self.failures = 0;
+    }
+
+    /// Record a successful delivery.
+    fn succeed(&mut self, factory: &CollectorFactoryWrapped) {
+        self.recover(factory);
+        self.stats.delivered += 1;
+    }
+
+    /// Record an event discarded by the collector.
+    fn discard(&mut self, factory: &CollectorFactoryWrapped) {
+        self.recover(factory);
+        self.stats.dropped += 1;
+    }
+
+    /// Fold an event into the digest of its vtuber and topic.
+    ///
+    /// Returns the digest event if it should be emitted now.
+    fn digest(
+        &mut self,
+        factory: &CollectorFactoryWrapped,
+        config: &DigestConfig,
+        event: &PublishExpanded,
+        ctx: &mut <CollectorActor as Actor>::Context,
+    ) -> Option<PublishExpanded> {
+        self.stats.digested += 1;
+        let key = (event.vtuber.clone(), event.topic.clone());
+        let aggregate = self.digests.entry(key.clone()).or_insert_with(|| {
+            let aggregate = Aggregate::new(event);
+            ctx.notify_later(
+                FlushDigest {
+                    factory: factory.clone(),
+                    key: key.clone(),
+                    id: aggregate.id,
+                },
+                config.window,
+            );
+            aggregate
+        });
+        if aggregate.add(config, event) {
+            self.digests
+                .remove(&key)
+                .map(|aggregate| aggregate.finish(config))
+        } else {
+            None
+        }
+    }
+
+    fn should_refill(&self) -> bool {
+        self.spilled && !self.refilling && self.queue.len() <= QUEUE_SIZE / 2
+    }
 }
 
 pub struct CollectorActor {
+    id: Uuid,
     db: Database,
+    outbox: Option<Outbox>,
+    dedup: Option<Dedup>,
+    fencing: Option<Fencing>,
+    priorities: Priorities,
     collectors: HashMap<CollectorFactoryWrapped, Context>,
 }
 
@@ -168,44 +593,391 @@ impl_stop_on_panic!(CollectorActor);
 impl CollectorActor {
     pub fn new(db: Database, factories: Vec<CollectorFactoryWrapped>) -> Self {
         Self {
+            id: Uuid::new_v4(),
             db,
+            outbox: None,
+            dedup: None,
+            fencing: None,
+            priorities: Priorities::default(),
             collectors: factories
                 .into_iter()
                 .map(|factory| (factory, Context::default()))
                 .collect(),
         }
     }
+
+    /// Persist queued events in given outbox.
+    #[must_use]
+    pub fn outbox(mut self, outbox: Outbox) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// Drop events whose idempotency keys are seen recently.
+    #[must_use]
+    pub fn dedup(mut self, dedup: Dedup) -> Self {
+        self.dedup = Some(dedup);
+        self
+    }
+
+    /// Drop events emitted by stale owners of entries.
+    #[must_use]
+    pub fn fencing(mut self, fencing: Fencing) -> Self {
+        self.fencing = Some(fencing);
+        self
+    }
+
+    /// Assign priority classes to events by topic.
+    #[must_use]
+    pub fn priorities(mut self, priorities: Priorities) -> Self {
+        self.priorities = priorities;
+        self
+    }
+
+    /// Check whether a low-priority event should be discarded because the queue is under pressure.
+    fn should_sample_out(&self, collector_ctx: &Context) -> bool {
+        collector_ctx.queue.len() >= self.priorities.pressure_threshold
+            && rand::thread_rng().gen_range(0..100) >= self.priorities.low_sample_percent
+    }
+
+    /// Queue events, persisting them first if the outbox is enabled.
+    ///
+    /// Low-priority events may be sampled out before they are queued.
+    fn enqueue(
+        &mut self,
+        events: Vec<(CollectorFactoryWrapped, PublishExpanded)>,
+        ctx: &mut <Self as Actor>::Context,
+    ) -> ResponseActFuture<Self, ()> {
+        let mut prioritized = Vec::with_capacity(events.len());
+        for (factory, event) in events {
+            let priority = self.priorities.classify(&event.topic);
+            if let Some(collector_ctx) = self.collectors.get(&factory) {
+                if priority == Priority::Low && self.should_sample_out(collector_ctx) {
+                    if let Some(collector_ctx) = self.collectors.get_mut(&factory) {
+                        collector_ctx.stats.sampled += 1;
+                    }
+                    continue;
+                }
+            }
+            prioritized.push((factory, event, priority));
+        }
+
+        let outbox = if let Some(outbox) = &self.outbox {
+            outbox.clone()
+        } else {
+            for (factory, event, priority) in prioritized {
+                if let Some(collector_ctx) = self.collectors.get_mut(&factory) {
+                    let event = QueuedEvent {
+                        event,
+                        outbox_id: None,
+                        attempts: 0,
+                        priority,
+                    };
+                    collector_ctx.push(&factory, event, ctx);
+                }
+            }
+            return Box::pin(ready(()));
+        };
+
+        let mut entries = Vec::new();
+        let mut persisted = Vec::new();
+        for (factory, event, priority) in prioritized {
+            let collector_ctx = match self.collectors.get_mut(&factory) {
+                Some(collector_ctx) => collector_ctx,
+                None => continue,
+            };
+            // events are loaded later if the queue is full now
+            let loaded = !collector_ctx.is_full();
+            match OutboxEntry::new(
+                factory.ident(),
+                self.id,
+                outbox.instance.clone(),
+                loaded,
+                priority,
+                &event,
+            ) {
+                Ok(entry) => {
+                    let event = QueuedEvent {
+                        event,
+                        outbox_id: Some(entry.id),
+                        attempts: 0,
+                        priority,
+                    };
+                    entries.push(entry);
+                    persisted.push((factory, event, loaded));
+                }
+                Err(e) => {
+                    span().in_scope(|| warn!("unable to persist event, keeping in memory: {}", e));
+                    let event = QueuedEvent {
+                        event,
+                        outbox_id: None,
+                        attempts: 0,
+                        priority,
+                    };
+                    collector_ctx.push(&factory, event, ctx);
+                }
+            }
+        }
+
+        Box::pin(
+            async move { InsertOutboxOp(entries).execute(&outbox.collection).await }
+                .into_actor(self)
+                .map(|res, act, ctx| {
+                    let failed = res
+                        .map_err(|e| error!("unable to persist events, keeping in memory: {}", e))
+                        .is_err();
+                    let mut unloaded = Vec::new();
+                    for (factory, mut event, loaded) in persisted {
+                        if let Some(collector_ctx) = act.collectors.get_mut(&factory) {
+                            if failed {
+                                event.outbox_id = None;
+                                collector_ctx.push(&factory, event, ctx);
+                            } else if loaded {
+                                // the queue may have been filled while the events are being persisted
+                                unloaded.extend(collector_ctx.push(&factory, event, ctx));
+                            } else {
+                                collector_ctx.spill();
+                            }
+                        }
+                    }
+                    act.unload_outbox(unloaded, ctx);
+                })
+                .actor_instrument(span()),
+        )
+    }
+
+    /// Mark events which don't fit in the queue as not loaded, so that they're loaded again later.
+    fn unload_outbox(&mut self, ids: Vec<ObjectId>, ctx: &mut <Self as Actor>::Context) {
+        if let (Some(outbox), false) = (self.outbox.clone(), ids.is_empty()) {
+            ctx.spawn(wrap_future(async move {
+                if let Err(e) = UnloadOutboxOp(ids).execute(&outbox.collection).await {
+                    // they are claimed by other actors after this one is gone
+                    error!("unable to unload events: {}", e);
+                }
+            }));
+        }
+    }
+
+    fn collector_idents(&self) -> Vec<String> {
+        self.collectors
+            .keys()
+            .map(|factory| factory.ident())
+            .collect()
+    }
+
+    /// Claim events left by a previous run of this instance, then start renewing the outbox.
+    fn reclaim_outbox(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let (outbox, instance) = match &self.outbox {
+            Some(
+                outbox @ Outbox {
+                    instance: Some(instance),
+                    ..
+                },
+            ) => (outbox.clone(), instance.clone()),
+            _ => return self.renew_outbox(ctx),
+        };
+        let op = ReclaimOutboxOp {
+            collectors: self.collector_idents(),
+            owner: self.id,
+            instance,
+            started_at: outbox.started_at,
+        };
+        ctx.wait(
+            async move { op.execute(&outbox.collection).await }
+                .into_actor(self)
+                .map(|res, act, ctx| {
+                    match res {
+                        Ok(0) => (),
+                        Ok(claimed) => info!("reclaimed {} events of previous run", claimed),
+                        Err(e) => error!("unable to reclaim outbox: {}", e),
+                    }
+                    act.renew_outbox(ctx);
+                })
+                .actor_instrument(span()),
+        );
+    }
+
+    fn renew_outbox(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let outbox = if let Some(outbox) = &self.outbox {
+            outbox.clone()
+        } else {
+            return;
+        };
+        let op = RenewOutboxOp {
+            collectors: self.collector_idents(),
+            owner: self.id,
+            instance: outbox.instance.clone(),
+            lease: outbox.lease,
+        };
+        ctx.spawn(
+            async move { op.execute(&outbox.collection).await }
+                .into_actor(self)
+                .map(|res, act, ctx| {
+                    match res {
+                        Ok(0) => (),
+                        Ok(claimed) => {
+                            info!("claimed {} pending events", claimed);
+                            for collector_ctx in act.collectors.values_mut() {
+                                collector_ctx.spill_gen += 1;
+                                collector_ctx.spilled = true;
+                            }
+                        }
+                        Err(e) => error!("unable to renew outbox: {}", e),
+                    }
+                    for (factory, collector_ctx) in &act.collectors {
+                        if collector_ctx.should_refill() {
+                            ctx.notify(Refill(factory.clone()));
+                        }
+                    }
+                })
+                .actor_instrument(span()),
+        );
+    }
 }
 
 impl Actor for CollectorActor {
     type Context = actix::Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        KillerActor::from_registry().do_send(RegisterDrain(ctx.address().recipient()));
+        if let Some(lease) = self.outbox.as_ref().map(|outbox| outbox.lease) {
+            // events left by dead actors are claimed here
+            self.reclaim_outbox(ctx);
+            ctx.run_interval(lease / 3, Self::renew_outbox);
+        }
+    }
+}
+
+impl Handler<GetId> for CollectorActor {
+    type Result = ResponseWrapper<Uuid>;
+
+    fn handle(&mut self, _msg: GetId, _ctx: &mut Self::Context) -> Self::Result {
+        ResponseWrapper(self.id)
+    }
+}
+
+impl Handler<GetStats> for CollectorActor {
+    type Result = ResponseWrapper<HashMap<String, CollectorStats>>;
+
+    fn handle(&mut self, _msg: GetStats, _ctx: &mut Self::Context) -> Self::Result {
+        ResponseWrapper(
+            self.collectors
+                .iter()
+                .map(|(factory, collector_ctx)| {
+                    let stats = CollectorStats {
+                        queued: collector_ctx.queue.len(),
+                        ..collector_ctx.stats
+                    };
+                    (factory.ident(), stats)
+                })
+                .collect(),
+        )
+    }
 }
 
 impl Handler<Publish> for CollectorActor {
     type Result = ResponseActFuture<Self, ()>;
 
     fn handle(&mut self, msg: Publish, _: &mut Self::Context) -> Self::Result {
-        msg.expand(self.db.clone())
-            .into_actor(self)
-            .map(|msg, act, ctx| {
-                if let Ok(Some(msg)) = msg {
-                    act.collectors
-                        .iter_mut()
-                        .for_each(|(factory, collector_ctx)| {
-                            if matches!(collector_ctx.state, State::Available(_))
-                                && collector_ctx.queue.is_empty()
-                                || matches!(collector_ctx.state, State::Uninit)
-                            {
-                                // collector available & queue empty | lazy init, schedule wake
-                                ctx.notify(Wake(factory.clone()));
-                            }
-                            collector_ctx.queue.push_back(msg.clone());
-                        });
-                } else {
-                    span().in_scope(|| warn!("unable to fetch root metadata"));
+        let instance = ArbiterContext::with(|ctx| ctx.instance_id);
+        let db = self.db.clone();
+        let dedup = self.dedup.clone();
+        let fencing = self.fencing.clone();
+        async move {
+            if let (Some(fencing), Some(token)) = (&fencing, &msg.fencing_token) {
+                match fencing.admit(token).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        warn!("event from stale owner of {} rejected", token.entry);
+                        return Ok(None);
+                    }
+                    Err(e) => warn!("unable to check fencing token, publishing anyway: {:?}", e),
+                }
+            }
+            let mut claimed = None;
+            if let (Some(dedup), Some(key)) = (&dedup, &msg.idempotency_key) {
+                match dedup.claim(&msg.source, key).await {
+                    Ok(true) => claimed = Some((msg.source.clone(), key.clone())),
+                    Ok(false) => return Ok(None),
+                    Err(e) => warn!(
+                        "unable to claim idempotency key, publishing anyway: {:?}",
+                        e
+                    ),
+                }
+            }
+            let expanded = msg.expand(db, instance).await;
+            if !matches!(expanded, Ok(Some(_))) {
+                // the event won't be queued, so let a later emission of it through
+                if let (Some(dedup), Some((source, key))) = (&dedup, &claimed) {
+                    if let Err(e) = dedup.release(source, key).await {
+                        warn!("unable to release idempotency key: {:?}", e);
+                    }
                 }
+            }
+            expanded.map(Some)
+        }
+        .into_actor(self)
+        .then(|msg, act, ctx| match msg {
+            Ok(Some(Some(msg))) => {
+                let mut events = Vec::new();
+                for (factory, collector_ctx) in &mut act.collectors {
+                    if !factory.routing.matches(&msg) {
+                        continue;
+                    }
+                    if let Some(config) = factory.digest.rule(&msg.topic) {
+                        if let Some(digest) = collector_ctx.digest(factory, config, &msg, ctx) {
+                            events.push((factory.clone(), digest));
+                        }
+                    } else {
+                        events.push((factory.clone(), msg.clone()));
+                    }
+                }
+                act.enqueue(events, ctx)
+            }
+            Ok(None) => {
+                span().in_scope(|| debug!("duplicated or stale event dropped"));
+                Box::pin(ready(()))
+            }
+            _ => {
+                span().in_scope(|| warn!("unable to fetch root metadata"));
+                Box::pin(ready(()))
+            }
+        })
+        .actor_instrument(span())
+        .boxed_local()
+    }
+}
+
+impl Handler<Replay> for CollectorActor {
+    type Result = ResponseActFuture<Self, Vec<String>>;
+
+    fn handle(&mut self, msg: Replay, ctx: &mut Self::Context) -> Self::Result {
+        let _span = span().entered();
+        let targets: Vec<_> = self
+            .collectors
+            .keys()
+            .filter(|factory| {
+                msg.target
+                    .as_ref()
+                    .is_none_or(|target| *target == factory.ident())
             })
-            .boxed_local()
+            .cloned()
+            .collect();
+        let mut events = Vec::new();
+        for factory in &targets {
+            info!(
+                "replaying {} events to {}",
+                msg.events.len(),
+                factory.ident()
+            );
+            for event in &msg.events {
+                let mut event = event.clone();
+                event.replay = true;
+                events.push((factory.clone(), event));
+            }
+        }
+        let idents = targets.iter().map(|factory| factory.ident()).collect();
+        Box::pin(self.enqueue(events, ctx).map(|_, _, _| idents))
     }
 }
 
@@ -219,6 +991,7 @@ impl Handler<Wake> for CollectorActor {
             EarlyWake(Instant),
         }
         span().in_scope(|| trace!("waked"));
+        let normal_weight = self.priorities.normal_weight;
         self.collectors.get_mut(&msg.0).map_or_else(
             || {
                 span().in_scope(|| error!("collector not found"));
@@ -235,7 +1008,7 @@ impl Handler<Wake> for CollectorActor {
                 };
                 match branch {
                     Branch::Send(collector) => {
-                        let event = collector_ctx.queue.pop_front();
+                        let event = collector_ctx.queue.pop_front(normal_weight);
                         event.map_or_else(
                             || {
                                 span().in_scope(|| {
@@ -246,30 +1019,76 @@ impl Handler<Wake> for CollectorActor {
                             },
                             |event| {
                                 span().in_scope(|| debug!("dispatching event"));
+                                let rendered = msg.0.render(event.event.clone());
                                 AtomicResponse::new(Box::pin(
-                                    wrap_future::<_, Self>(collector.send(event.clone()))
+                                    wrap_future::<_, Self>(collector.send(rendered))
                                         .map(move |succ, act, ctx| {
+                                            let outbox = act.outbox.clone();
                                             if let Some(collector_ctx) =
                                                 act.collectors.get_mut(&msg.0)
                                             {
-                                                if succ.unwrap_or_default() {
-                                                    // event sent
-                                                    if !collector_ctx.queue.is_empty() {
-                                                        // there's event remaining in queue, schedule wake
-                                                        ctx.notify(msg);
+                                                match succ.unwrap_or(Delivery::Failed) {
+                                                    delivery @ (Delivery::Sent
+                                                    | Delivery::Discarded) => {
+                                                        if delivery == Delivery::Sent {
+                                                            collector_ctx.succeed(&msg.0);
+                                                            if let Some(id) =
+                                                                event.event.dead_letter
+                                                            {
+                                                                ctx.spawn(wrap_future(
+                                                                    ack_dead_letter(
+                                                                        act.db.collection(
+                                                                            DEAD_LETTER_COLLECTION,
+                                                                        ),
+                                                                        id,
+                                                                    ),
+                                                                ));
+                                                            }
+                                                        } else {
+                                                            // the collector works, just not for this event
+                                                            collector_ctx.discard(&msg.0);
+                                                        }
+                                                        if let (Some(outbox), Some(id)) =
+                                                            (outbox, event.outbox_id)
+                                                        {
+                                                            ctx.spawn(wrap_future(ack_outbox(
+                                                                outbox, id,
+                                                            )));
+                                                        }
+                                                        if collector_ctx.should_refill() {
+                                                            ctx.notify(Refill(msg.0.clone()));
+                                                        }
+                                                        if !collector_ctx.queue.is_empty() {
+                                                            // there's event remaining in queue, schedule wake
+                                                            ctx.notify(msg);
+                                                        }
+                                                    }
+                                                    delivery => {
+                                                        error!("failed to dispatch event");
+                                                        // failed to send event
+                                                        // update state & put back unsent event
+                                                        let (delay, dead) = collector_ctx.fail(
+                                                            &msg.0,
+                                                            event,
+                                                            delivery == Delivery::Rejected,
+                                                        );
+                                                        if let Some(event) = dead {
+                                                            warn!(
+                                                                "giving up event after {} attempts",
+                                                                event.attempts
+                                                            );
+                                                            ctx.spawn(wrap_future(dead_letter(
+                                                                act.db.collection(
+                                                                    DEAD_LETTER_COLLECTION,
+                                                                ),
+                                                                outbox,
+                                                                msg.0.ident(),
+                                                                event,
+                                                            )));
+                                                        }
+                                                        // schedule delayed wake
+                                                        ctx.notify_later(msg, delay);
                                                     }
-                                                } else {
-                                                    error!("failed to dispatch event");
-                                                    // failed to send event
-                                                    // update state
-                                                    collector_ctx.state = State::DelayedEstablish(
-                                                        Instant::now().add(Duration::from_secs(10)), // TODO config retry
-                                                    );
-                                                    // put back unsent event
-                                                    collector_ctx.queue.push_front(event);
-                                                    // schedule delayed wake
-                                                    ctx.notify_later(msg, Duration::from_secs(10));
-                                                    // TODO config retry
                                                 }
                                             } else {
                                                 error!("collector not found");
@@ -301,11 +1120,8 @@ impl Handler<Wake> for CollectorActor {
                                     } else {
                                         error!("failed to establish connection");
                                         // failed to build new recipient, schedule delayed wake
-                                        collector_ctx.state = State::DelayedEstablish(
-                                            Instant::now().add(Duration::from_secs(10)), // TODO config retry
-                                        );
-                                        ctx.notify_later(msg, Duration::from_secs(10));
-                                        // TODO config retry
+                                        let delay = collector_ctx.delay(&msg.0);
+                                        ctx.notify_later(msg, delay);
                                     }
                                 } else {
                                     error!("collector not found");
@@ -325,3 +1141,160 @@ impl Handler<Wake> for CollectorActor {
         )
     }
 }
+
+impl Handler<FlushDigest> for CollectorActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: FlushDigest, ctx: &mut Self::Context) -> Self::Result {
+        let digest = self
+            .collectors
+            .get_mut(&msg.factory)
+            .filter(|collector_ctx| {
+                collector_ctx
+                    .digests
+                    .get(&msg.key)
+                    .is_some_and(|aggregate| aggregate.id == msg.id)
+            })
+            .and_then(|collector_ctx| collector_ctx.digests.remove(&msg.key))
+            .zip(msg.factory.digest.rule(&msg.key.1))
+            .map(|(aggregate, config)| aggregate.finish(config));
+        match digest {
+            Some(digest) => self.enqueue(vec![(msg.factory, digest)], ctx),
+            // already emitted because enough events are collected
+            None => Box::pin(ready(())),
+        }
+    }
+}
+
+impl Handler<Drain> for CollectorActor {
+    type Result = AtomicResponse<Self, ()>;
+
+    fn handle(&mut self, _msg: Drain, ctx: &mut Self::Context) -> Self::Result {
+        // emit partial digests now, so that they are delivered or persisted in the outbox before shutdown
+        let mut digests = Vec::new();
+        for (factory, collector_ctx) in &mut self.collectors {
+            for ((_, topic), aggregate) in collector_ctx.digests.drain() {
+                if let Some(config) = factory.digest.rule(&topic) {
+                    digests.push((factory.clone(), aggregate.finish(config)));
+                }
+            }
+        }
+        if !digests.is_empty() {
+            info!("flushing {} digests", digests.len());
+        }
+        AtomicResponse::new(self.enqueue(digests, ctx))
+    }
+}
+
+impl Handler<Refill> for CollectorActor {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: Refill, _ctx: &mut Self::Context) -> Self::Result {
+        let outbox = self.outbox.clone();
+        let collector_ctx = self.collectors.get_mut(&msg.0);
+        let (outbox, collector_ctx) = match (outbox, collector_ctx) {
+            (Some(outbox), Some(collector_ctx)) if collector_ctx.should_refill() => {
+                (outbox, collector_ctx)
+            }
+            _ => return Box::pin(ready(())),
+        };
+
+        let limit = QUEUE_SIZE - collector_ctx.queue.len();
+        let spill_gen = collector_ctx.spill_gen;
+        collector_ctx.refilling = true;
+        let op = LoadOutboxOp {
+            collector: msg.0.ident(),
+            owner: self.id,
+            limit: limit as i64,
+        };
+        Box::pin(
+            async move { op.execute(&outbox.collection).await }
+                .into_actor(self)
+                .map(move |res, act, ctx| {
+                    if let Some(collector_ctx) = act.collectors.get_mut(&msg.0) {
+                        collector_ctx.refilling = false;
+                        match res {
+                            Ok(entries) => {
+                                debug!("loaded {} events from outbox", entries.len());
+                                if entries.len() < limit && collector_ctx.spill_gen == spill_gen {
+                                    // all spilled events are loaded
+                                    collector_ctx.spilled = false;
+                                }
+                                let mut unloaded = Vec::new();
+                                for entry in entries {
+                                    let event = QueuedEvent {
+                                        outbox_id: Some(entry.id),
+                                        priority: entry.priority,
+                                        event: entry.into(),
+                                        attempts: 0,
+                                    };
+                                    unloaded.extend(collector_ctx.push(&msg.0, event, ctx));
+                                }
+                                act.unload_outbox(unloaded, ctx);
+                            }
+                            // will be retried on next renewal
+                            Err(e) => error!("unable to load events from outbox: {}", e),
+                        }
+                    } else {
+                        error!("collector not found");
+                    }
+                })
+                .actor_instrument(span()),
+        )
+    }
+}
+
+/// Statistics of collectors on this instance, summed over all arbiters.
+#[get("/collectors")]
+pub async fn collector_stats(
+    ctx: Data<InstanceContext>,
+) -> Result<Json<HashMap<String, CollectorStats>>, QueryError> {
+    let mut total: HashMap<String, CollectorStats> = HashMap::new();
+    for stats in ctx
+        .send::<CollectorActor, _>(&GetStats)?
+        .await?
+        .into_values()
+    {
+        for (ident, stats) in stats {
+            *total.entry(ident).or_default() += stats;
+        }
+    }
+    Ok(Json(total))
+}
+
+async fn dead_letter(
+    collection: Collection<DeadLetter>,
+    outbox: Option<Outbox>,
+    collector: String,
+    event: QueuedEvent,
+) {
+    let letter = match DeadLetter::new(collector, &event.event, event.attempts) {
+        Ok(letter) => letter,
+        Err(e) => {
+            error!("unable to serialize payload, dropping: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = InsertDeadLetterOp(letter).execute(&collection).await {
+        // keep it in the outbox, so that it's delivered again by whoever claims it
+        error!("unable to save dead letter: {}", e);
+        return;
+    }
+    if let (Some(outbox), Some(id)) = (outbox, event.outbox_id) {
+        ack_outbox(outbox, id).await;
+    }
+}
+
+async fn ack_dead_letter(collection: Collection<DeadLetter>, id: ObjectId) {
+    if let Err(e) = DeleteDeadLettersOp(vec![id]).execute(&collection).await {
+        // the letter may be replayed again, but it's delivered at least once anyway
+        error!("unable to remove redelivered dead letter: {}", e);
+    }
+}
+
+async fn ack_outbox(outbox: Outbox, id: ObjectId) {
+    if let Err(e) = AckOutboxOp(id).execute(&outbox.collection).await {
+        // the event will be delivered again by whoever claims it
+        error!("unable to remove event from outbox: {}", e);
+    }
+}
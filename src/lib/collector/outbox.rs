@@ -0,0 +1,363 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::serde_helpers::uuid_as_binary;
+use mongodb::bson::{self, doc, Bson};
+use mongodb::options::FindOptions;
+use mongodb::IndexModel;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::OutboxConfig;
+use crate::db::{CollOperation, Collection, DBResult};
+use crate::utils::timestamp;
+
+use super::archive::decode_payload;
+use super::envelope::EventMeta;
+use super::priority::Priority;
+use super::PublishExpanded;
+
+pub const OUTBOX_COLLECTION: &str = "outbox";
+
+/// Start time of this process, shared by actors on all arbiters.
+static STARTED_AT: Lazy<i64> = Lazy::new(|| timestamp(SystemTime::now()));
+
+/// An event waiting to be delivered to a collector.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// Ident of the collector the event is sent to.
+    pub collector: String,
+    /// Collector actor responsible for delivering the event.
+    #[serde(with = "uuid_as_binary")]
+    pub owner: Uuid,
+    /// Stable name of the instance the owner runs on, if configured.
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// Last time the owner is known to be alive, in milliseconds.
+    pub timestamp: i64,
+    /// Whether the event has been loaded into the owner's memory queue.
+    pub loaded: bool,
+    /// Events of higher priority are loaded first.
+    #[serde(default)]
+    pub priority: Priority,
+    pub vtuber: String,
+    #[serde(default)]
+    pub meta: EventMeta,
+    #[serde(default)]
+    pub source: String,
+    pub topic: String,
+    /// Payload of entries persisted before raw payloads are kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Bson>,
+    /// Payload serialized as JSON, replayed as is since BSON conversion may change its shape, e.g. integer widths.
+    #[serde(default)]
+    pub raw_payload: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub replay: bool,
+    /// Id of the dead letter the event is redelivered from.
+    #[serde(default)]
+    pub dead_letter: Option<ObjectId>,
+}
+
+impl OutboxEntry {
+    /// Build an entry from an event.
+    ///
+    /// # Errors
+    /// Raise an error if the payload can't be serialized.
+    pub fn new(
+        collector: String,
+        owner: Uuid,
+        instance: Option<String>,
+        loaded: bool,
+        priority: Priority,
+        event: &PublishExpanded,
+    ) -> serde_json::Result<Self> {
+        Ok(Self {
+            id: ObjectId::new(),
+            collector,
+            owner,
+            instance,
+            timestamp: timestamp(SystemTime::now()),
+            loaded,
+            priority,
+            meta: event.meta.clone(),
+            vtuber: event.vtuber.clone(),
+            source: event.source.clone(),
+            topic: event.topic.clone(),
+            payload: None,
+            raw_payload: Some(serde_json::to_string(&*event.data)?),
+            tags: event.tags.clone(),
+            replay: event.replay,
+            dead_letter: event.dead_letter,
+        })
+    }
+}
+
+impl From<OutboxEntry> for PublishExpanded {
+    fn from(entry: OutboxEntry) -> Self {
+        Self {
+            meta: entry.meta,
+            vtuber: entry.vtuber,
+            source: entry.source,
+            topic: entry.topic,
+            data: Arc::new(decode_payload(
+                entry.raw_payload,
+                entry.payload.unwrap_or_default(),
+            )),
+            tags: entry.tags,
+            replay: entry.replay,
+            text: None,
+            dead_letter: entry.dead_letter,
+        }
+    }
+}
+
+/// Durable outbox of collector queues.
+///
+/// Every queued event is persisted before it's dispatched, and removed once the collector acknowledges it.
+/// Events owned by dead actors are claimed by live ones after `lease` and delivered again.
+/// If the instance has a stable name, events left by its previous run are reclaimed on startup.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    pub collection: Collection<OutboxEntry>,
+    pub lease: Duration,
+    pub instance: Option<String>,
+    /// Start time of this run, in milliseconds. Events of the same instance renewed before it are left by a previous run.
+    pub started_at: i64,
+}
+
+impl Outbox {
+    pub fn new(collection: Collection<OutboxEntry>, config: OutboxConfig) -> Self {
+        Self {
+            collection,
+            lease: config.lease,
+            instance: config.instance,
+            started_at: *STARTED_AT,
+        }
+    }
+}
+
+/// Create indexes needed by the outbox.
+///
+/// # Errors
+/// Pass errors raised by mongodb driver.
+pub async fn create_indexes(collection: &Collection<OutboxEntry>) -> DBResult<()> {
+    collection
+        .create_indexes(
+            [
+                IndexModel::builder()
+                    .keys(doc! {"owner": 1, "collector": 1, "loaded": 1, "priority": -1, "_id": 1})
+                    .build(),
+                IndexModel::builder()
+                    .keys(doc! {"collector": 1, "timestamp": 1})
+                    .build(),
+                IndexModel::builder()
+                    .keys(doc! {"instance": 1, "collector": 1, "timestamp": 1})
+                    .build(),
+            ],
+            None,
+        )
+        .await
+        .map(|_| ())
+}
+
+#[derive(Debug)]
+pub struct InsertOutboxOp(pub Vec<OutboxEntry>);
+
+#[async_trait]
+impl CollOperation for InsertOutboxOp {
+    type Result = ();
+    type Item = OutboxEntry;
+
+    const DESC: &'static str = "InsertOutbox";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        collection.insert_many(self.0, None).await.map(|_| ())
+    }
+}
+
+/// Remove an acknowledged event.
+#[derive(Debug)]
+pub struct AckOutboxOp(pub ObjectId);
+
+#[async_trait]
+impl CollOperation for AckOutboxOp {
+    type Result = ();
+    type Item = OutboxEntry;
+
+    const DESC: &'static str = "AckOutbox";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .delete_one(doc! {"_id": self.0}, None)
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Mark events as not loaded, so that they're loaded again when the queue drains.
+#[derive(Debug)]
+pub struct UnloadOutboxOp(pub Vec<ObjectId>);
+
+#[async_trait]
+impl CollOperation for UnloadOutboxOp {
+    type Result = ();
+    type Item = OutboxEntry;
+
+    const DESC: &'static str = "UnloadOutbox";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        collection
+            .update_many(
+                doc! {"_id": {"$in": self.0}},
+                doc! {"$set": {"loaded": false}},
+                None,
+            )
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Load spilled events of a collector into memory, highest priority first, then oldest first.
+#[derive(Debug)]
+pub struct LoadOutboxOp {
+    pub collector: String,
+    pub owner: Uuid,
+    pub limit: i64,
+}
+
+#[async_trait]
+impl CollOperation for LoadOutboxOp {
+    type Result = Vec<OutboxEntry>;
+    type Item = OutboxEntry;
+
+    const DESC: &'static str = "LoadOutbox";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        let entries: Vec<OutboxEntry> = collection
+            .find(
+                doc! {
+                    "owner": bson::Uuid::from(self.owner),
+                    "collector": &self.collector,
+                    "loaded": false
+                },
+                FindOptions::builder()
+                    .sort(doc! {"priority": -1, "_id": 1})
+                    .limit(self.limit)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await?;
+        if !entries.is_empty() {
+            let ids: Vec<_> = entries.iter().map(|entry| entry.id).collect();
+            collection
+                .update_many(
+                    doc! {"_id": {"$in": ids}},
+                    doc! {"$set": {"loaded": true}},
+                    None,
+                )
+                .await?;
+        }
+        Ok(entries)
+    }
+}
+
+/// Renew the lease of all events owned by an actor, and claim events whose owner has gone.
+///
+/// Returns count of claimed events.
+#[derive(Debug)]
+pub struct RenewOutboxOp {
+    pub collectors: Vec<String>,
+    pub owner: Uuid,
+    pub instance: Option<String>,
+    pub lease: Duration,
+}
+
+#[async_trait]
+impl CollOperation for RenewOutboxOp {
+    type Result = u64;
+    type Item = OutboxEntry;
+
+    const DESC: &'static str = "RenewOutbox";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        let now = SystemTime::now();
+        let owner = bson::Uuid::from(self.owner);
+        collection
+            .update_many(
+                doc! {"owner": owner},
+                doc! {"$set": {"timestamp": timestamp(now)}},
+                None,
+            )
+            .await?;
+        collection
+            .update_many(
+                doc! {
+                    "collector": {"$in": self.collectors},
+                    "timestamp": {"$lt": timestamp(now - self.lease)}
+                },
+                doc! {"$set": {
+                    "owner": owner,
+                    "instance": self.instance,
+                    "timestamp": timestamp(now),
+                    "loaded": false
+                }},
+                None,
+            )
+            .await
+            .map(|res| res.modified_count)
+    }
+}
+
+/// Claim events left by a previous run of the same instance, without waiting for their lease to expire.
+///
+/// Returns count of claimed events.
+#[derive(Debug)]
+pub struct ReclaimOutboxOp {
+    pub collectors: Vec<String>,
+    pub owner: Uuid,
+    pub instance: String,
+    /// Start time of this run. Live actors of the instance have renewed their events after it.
+    pub started_at: i64,
+}
+
+#[async_trait]
+impl CollOperation for ReclaimOutboxOp {
+    type Result = u64;
+    type Item = OutboxEntry;
+
+    const DESC: &'static str = "ReclaimOutbox";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .update_many(
+                doc! {
+                    "instance": self.instance,
+                    "collector": {"$in": self.collectors},
+                    "timestamp": {"$lt": self.started_at}
+                },
+                doc! {"$set": {
+                    "owner": bson::Uuid::from(self.owner),
+                    "timestamp": timestamp(SystemTime::now()),
+                    "loaded": false
+                }},
+                None,
+            )
+            .await
+            .map(|res| res.modified_count)
+    }
+}
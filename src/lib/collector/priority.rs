@@ -0,0 +1,168 @@
+use std::collections::VecDeque;
+
+use glob::{Pattern, PatternError};
+use serde::{Deserialize, Serialize};
+
+use crate::config::PrioritiesConfig;
+
+use super::QueuedEvent;
+
+/// Priority class of an event.
+///
+/// Stored as an integer in the outbox, so that spilled events can be loaded by priority.
+#[derive(
+    Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize,
+)]
+#[serde(into = "i32", from = "i32")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl From<Priority> for i32 {
+    fn from(priority: Priority) -> Self {
+        match priority {
+            Priority::Low => 0,
+            Priority::Normal => 1,
+            Priority::High => 2,
+        }
+    }
+}
+
+impl From<i32> for Priority {
+    fn from(rank: i32) -> Self {
+        match rank {
+            i32::MIN..=0 => Self::Low,
+            1 => Self::Normal,
+            _ => Self::High,
+        }
+    }
+}
+
+/// Compiled priority classes of topics.
+#[derive(Debug, Clone)]
+pub struct Priorities {
+    high: Vec<Pattern>,
+    low: Vec<Pattern>,
+    /// Normal-priority events sent for each low-priority one when both are queued.
+    pub normal_weight: u32,
+    /// Percentage of low-priority events kept when the queue is under pressure.
+    pub low_sample_percent: u32,
+    /// Queue length from which low-priority events are sampled.
+    pub pressure_threshold: usize,
+}
+
+impl Default for Priorities {
+    fn default() -> Self {
+        Self::new(&PrioritiesConfig::default()).expect("invalid default priorities")
+    }
+}
+
+impl Priorities {
+    /// Compile priority classes.
+    ///
+    /// # Errors
+    /// Raise an error if any topic pattern is malformed.
+    pub fn new(config: &PrioritiesConfig) -> Result<Self, PatternError> {
+        let compile = |topics: &[String]| {
+            topics
+                .iter()
+                .map(|topic| Pattern::new(topic))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            high: compile(&config.high)?,
+            low: compile(&config.low)?,
+            normal_weight: config.normal_weight,
+            low_sample_percent: config.low_sample_percent,
+            pressure_threshold: config.pressure_threshold,
+        })
+    }
+
+    /// Get the priority of given topic. High-priority patterns are checked first.
+    pub fn classify(&self, topic: &str) -> Priority {
+        if self.high.iter().any(|pattern| pattern.matches(topic)) {
+            Priority::High
+        } else if self.low.iter().any(|pattern| pattern.matches(topic)) {
+            Priority::Low
+        } else {
+            Priority::Normal
+        }
+    }
+}
+
+/// Queues of a collector, one per priority.
+///
+/// High-priority events always go out first.
+/// Normal and low-priority events are drained by weight so that low-priority ones won't starve.
+#[derive(Debug, Default)]
+pub(super) struct Lanes {
+    high: VecDeque<QueuedEvent>,
+    normal: VecDeque<QueuedEvent>,
+    low: VecDeque<QueuedEvent>,
+    /// Normal-priority events sent since the last low-priority one.
+    streak: u32,
+}
+
+impl Lanes {
+    fn lane_mut(&mut self, priority: Priority) -> &mut VecDeque<QueuedEvent> {
+        match priority {
+            Priority::High => &mut self.high,
+            Priority::Normal => &mut self.normal,
+            Priority::Low => &mut self.low,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.high.len() + self.normal.len() + self.low.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push_back(&mut self, event: QueuedEvent) {
+        self.lane_mut(event.priority).push_back(event);
+    }
+
+    /// Put back an event, so that it's the next one of its priority.
+    pub fn push_front(&mut self, event: QueuedEvent) {
+        self.lane_mut(event.priority).push_front(event);
+    }
+
+    /// Take the next event to be sent.
+    pub fn pop_front(&mut self, normal_weight: u32) -> Option<QueuedEvent> {
+        if let Some(event) = self.high.pop_front() {
+            return Some(event);
+        }
+        let low_turn = self.normal.is_empty() || self.streak >= normal_weight;
+        if low_turn {
+            if let Some(event) = self.low.pop_front() {
+                self.streak = 0;
+                return Some(event);
+            }
+        }
+        let event = self.normal.pop_front()?;
+        self.streak = self.streak.saturating_add(1);
+        Some(event)
+    }
+
+    /// Drop the oldest in-memory event not more important than given priority.
+    ///
+    /// Returns `false` if there's no such event.
+    pub fn evict(&mut self, priority: Priority) -> bool {
+        for lane in [Priority::Low, Priority::Normal, Priority::High] {
+            if lane > priority {
+                break;
+            }
+            let lane = self.lane_mut(lane);
+            if lane.front().is_some_and(|front| front.outbox_id.is_none()) {
+                lane.pop_front();
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::HashSet;
+
+use glob::{Pattern, PatternError};
+
+use crate::config::RouteConfig;
+
+use super::PublishExpanded;
+
+/// Compiled routing rules of a collector.
+#[derive(Debug, Clone, Default)]
+pub struct Routing {
+    topics: Vec<Pattern>,
+    vtubers: HashSet<String>,
+    tags: HashSet<String>,
+}
+
+impl Routing {
+    /// Compile routing rules.
+    ///
+    /// # Errors
+    /// Raise an error if any topic pattern is malformed.
+    pub fn new(config: &RouteConfig) -> Result<Self, PatternError> {
+        Ok(Self {
+            topics: config
+                .topics
+                .iter()
+                .map(|topic| Pattern::new(topic))
+                .collect::<Result<_, _>>()?,
+            vtubers: config.vtubers.iter().cloned().collect(),
+            tags: config.tags.iter().cloned().collect(),
+        })
+    }
+
+    /// Check whether the event should be sent to the collector.
+    pub fn matches(&self, event: &PublishExpanded) -> bool {
+        (self.topics.is_empty()
+            || self
+                .topics
+                .iter()
+                .any(|pattern| pattern.matches(&event.topic)))
+            && (self.vtubers.is_empty() || self.vtubers.contains(&event.vtuber))
+            && (self.tags.is_empty() || event.tags.iter().any(|tag| self.tags.contains(tag)))
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use actix::{Actor, ActorContext, AsyncContext, Context, Handler, Recipient, StreamHandler};
+use actix_web::http::StatusCode;
+use actix_web::web::{Bytes, Data, Payload, Query};
+use actix_web::{get, HttpRequest, HttpResponse, ResponseError};
+use actix_web_actors::ws::{self, CloseCode, CloseReason, ProtocolError, WebsocketContext};
+use async_trait::async_trait;
+use futures::future::ready;
+use futures::{stream, Stream, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use tracing::{debug, error, info_span, warn, Span};
+
+use crate::config::StreamConfig;
+
+use super::envelope::Envelope;
+use super::{Collector, CollectorFactory, Delivery, PublishExpanded};
+
+const LAST_EVENT_ID: &str = "Last-Event-ID";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamEvent {
+    /// Sequence number of the event on this instance, used to resume streams.
+    pub seq: u64,
+    #[serde(flatten)]
+    pub envelope: Envelope<serde_json::Value>,
+}
+
+#[derive(Debug)]
+struct Buffer {
+    next_seq: u64,
+    events: VecDeque<Arc<StreamEvent>>,
+}
+
+/// Fans out events to connected stream clients.
+///
+/// The hub is shared by all arbiters on an instance.
+/// Recent events are kept in a bounded buffer so that clients may resume from a given event id.
+#[derive(Debug)]
+pub struct StreamHub {
+    sender: Sender<Arc<StreamEvent>>,
+    buffer: Mutex<Buffer>,
+    capacity: usize,
+}
+
+impl StreamHub {
+    pub fn new(config: StreamConfig) -> Self {
+        let (sender, _) = broadcast::channel(config.client_buffer.max(1));
+        Self {
+            sender,
+            buffer: Mutex::new(Buffer {
+                next_seq: 1,
+                events: VecDeque::with_capacity(config.replay_buffer),
+            }),
+            capacity: config.replay_buffer,
+        }
+    }
+
+    /// Assign a sequence number to the event, buffer it and send it to all subscribers.
+    pub fn publish(&self, envelope: Envelope<serde_json::Value>) -> u64 {
+        // Sequence numbers are assigned in the lock so that subscribers never see a gap between buffered and live events.
+        let mut buffer = self.buffer.lock();
+        let seq = buffer.next_seq;
+        buffer.next_seq += 1;
+
+        let event = Arc::new(StreamEvent { seq, envelope });
+        if self.capacity > 0 {
+            if buffer.events.len() >= self.capacity {
+                buffer.events.pop_front();
+            }
+            buffer.events.push_back(event.clone());
+        }
+        // there may be no subscriber, which is fine
+        drop(self.sender.send(event));
+        seq
+    }
+
+    /// Subscribe to the hub.
+    ///
+    /// Returns buffered events after sequence number `last_event_id` if given, and a receiver for following events.
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> (Vec<Arc<StreamEvent>>, Receiver<Arc<StreamEvent>>) {
+        let buffer = self.buffer.lock();
+        let backlog = last_event_id.map_or_else(Vec::new, |last_event_id| {
+            buffer
+                .events
+                .iter()
+                .filter(|event| event.seq > last_event_id)
+                .cloned()
+                .collect()
+        });
+        (backlog, self.sender.subscribe())
+    }
+
+    /// Subscribe to the hub as a stream of events matching the filter.
+    ///
+    /// The stream yields an error and ends when the client falls too far behind.
+    pub fn events(
+        &self,
+        filter: StreamFilter,
+        last_event_id: Option<u64>,
+    ) -> impl Stream<Item = Result<Arc<StreamEvent>, Lagged>> {
+        let (backlog, receiver) = self.subscribe(last_event_id);
+        let live = stream::unfold(Some(receiver), |receiver| async move {
+            let mut receiver = receiver?;
+            match receiver.recv().await {
+                Ok(event) => Some((Ok(event), Some(receiver))),
+                // slow client, yield an error and stop
+                Err(RecvError::Lagged(skipped)) => Some((Err(Lagged(skipped)), None)),
+                Err(RecvError::Closed) => None,
+            }
+        });
+        stream::iter(backlog.into_iter().map(Ok))
+            .chain(live)
+            .filter(move |item| {
+                ready(match item {
+                    Ok(event) => filter.matches(event),
+                    Err(_) => true,
+                })
+            })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Error)]
+#[error("client lagged behind by {0} events")]
+pub struct Lagged(pub u64);
+
+#[derive(Debug, Clone)]
+pub struct StreamFactory {
+    hub: Arc<StreamHub>,
+}
+
+impl StreamFactory {
+    pub fn new(hub: Arc<StreamHub>) -> Self {
+        Self { hub }
+    }
+}
+
+#[async_trait]
+impl CollectorFactory for StreamFactory {
+    fn ident(&self) -> String {
+        String::from("stream")
+    }
+
+    async fn build(&self) -> Option<Recipient<PublishExpanded>> {
+        Some(StreamCollector::new(self.hub.clone()).start().recipient())
+    }
+}
+
+#[derive(Debug)]
+pub struct StreamCollector {
+    hub: Arc<StreamHub>,
+}
+
+impl_stop_on_panic!(StreamCollector);
+
+impl Collector for StreamCollector {}
+
+impl StreamCollector {
+    pub fn new(hub: Arc<StreamHub>) -> Self {
+        Self { hub }
+    }
+
+    fn span() -> Span {
+        info_span!("stream")
+    }
+}
+
+impl Actor for StreamCollector {
+    type Context = Context<Self>;
+}
+
+impl Handler<PublishExpanded> for StreamCollector {
+    type Result = Delivery;
+
+    fn handle(&mut self, msg: PublishExpanded, _ctx: &mut Self::Context) -> Self::Result {
+        let _span = Self::span().entered();
+        match Envelope::new(&msg).into_value() {
+            Ok(envelope) => {
+                let seq = self.hub.publish(envelope);
+                debug!("event {} published", seq);
+                // never blocks, slow clients are dropped by the hub
+                Delivery::Sent
+            }
+            Err(e) => {
+                // retrying won't help
+                error!("unable to serialize payload, dropping: {}", e);
+                Delivery::Discarded
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StreamFilter {
+    pub vtuber: Option<String>,
+    pub topic: Option<String>,
+}
+
+impl StreamFilter {
+    pub fn matches(&self, event: &StreamEvent) -> bool {
+        self.vtuber
+            .as_ref()
+            .is_none_or(|vtuber| *vtuber == event.envelope.vtuber)
+            && self
+                .topic
+                .as_ref()
+                .is_none_or(|topic| *topic == event.envelope.topic)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StreamQuery {
+    #[serde(flatten)]
+    pub filter: StreamFilter,
+    /// Resume after given sequence number. Takes precedence over the `Last-Event-ID` header.
+    pub last_event_id: Option<u64>,
+}
+
+impl StreamQuery {
+    fn last_event_id(&self, req: &HttpRequest) -> Result<Option<u64>, SubscribeError> {
+        if self.last_event_id.is_some() {
+            return Ok(self.last_event_id);
+        }
+        req.headers()
+            .get(LAST_EVENT_ID)
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.trim().parse().ok())
+                    .ok_or_else(|| {
+                        SubscribeError::InvalidLastEventId(
+                            String::from_utf8_lossy(value.as_bytes()).to_string(),
+                        )
+                    })
+            })
+            .transpose()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SubscribeError {
+    #[error("invalid last event id: {0}")]
+    InvalidLastEventId(String),
+    #[error("websocket error: {0}")]
+    WebSocket(#[from] actix_web::Error),
+}
+
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::InvalidLastEventId(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::WebSocket(e) => e.as_response_error().status_code(),
+        }
+    }
+}
+
+fn sse_frame(event: &StreamEvent) -> Result<Bytes, serde_json::Error> {
+    let data = serde_json::to_string(event)?;
+    Ok(Bytes::from(format!(
+        "id: {}\ndata: {}\n\n",
+        event.seq, data
+    )))
+}
+
+#[get("/stream")]
+pub async fn sse(
+    hub: Data<StreamHub>,
+    query: Query<StreamQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, SubscribeError> {
+    let last_event_id = query.last_event_id(&req)?;
+    let frames = hub.events(query.into_inner().filter, last_event_id).map(
+        |item| -> Result<Bytes, Box<dyn std::error::Error>> {
+            match item {
+                Ok(event) => Ok(sse_frame(&event)?),
+                Err(e) => {
+                    warn!("dropping sse client: {}", e);
+                    Err(e.into())
+                }
+            }
+        },
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(frames))
+}
+
+#[get("/ws")]
+pub async fn websocket(
+    hub: Data<StreamHub>,
+    query: Query<StreamQuery>,
+    req: HttpRequest,
+    payload: Payload,
+) -> Result<HttpResponse, SubscribeError> {
+    let last_event_id = query.last_event_id(&req)?;
+    let session = WsSession {
+        hub: hub.into_inner(),
+        filter: query.into_inner().filter,
+        last_event_id,
+    };
+    Ok(ws::start(session, &req, payload)?)
+}
+
+struct WsSession {
+    hub: Arc<StreamHub>,
+    filter: StreamFilter,
+    last_event_id: Option<u64>,
+}
+
+impl Actor for WsSession {
+    type Context = WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.add_stream(
+            self.hub
+                .events(self.filter.clone(), self.last_event_id.take()),
+        );
+    }
+}
+
+impl StreamHandler<Result<Arc<StreamEvent>, Lagged>> for WsSession {
+    fn handle(&mut self, item: Result<Arc<StreamEvent>, Lagged>, ctx: &mut Self::Context) {
+        match item.map(|event| serde_json::to_string(&*event)) {
+            Ok(Ok(text)) => ctx.text(text),
+            Ok(Err(e)) => error!("unable to serialize event: {}", e),
+            Err(e) => {
+                warn!("dropping ws client: {}", e);
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some(e.to_string()),
+                }));
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ProtocolError>> for WsSession {
+    fn handle(&mut self, item: Result<ws::Message, ProtocolError>, ctx: &mut Self::Context) {
+        match item {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("ws protocol error: {}", e);
+                ctx.stop();
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use minijinja::Environment;
+use mongodb::bson::doc;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use crate::config::TemplatesConfig;
+use crate::db::{CollOperation, Collection, DBResult};
+
+use super::PublishExpanded;
+
+pub const TEMPLATE_COLLECTION: &str = "templates";
+
+/// Extension of template files.
+const EXTENSION: &str = "j2";
+
+/// A template of messages of given topic in given locale.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StoredTemplate {
+    pub topic: String,
+    pub locale: String,
+    pub template: String,
+}
+
+fn template_name(topic: &str, locale: &str) -> String {
+    format!("{}.{}", topic, locale)
+}
+
+/// Data available to templates.
+///
+/// `payload` is the event payload, and `vtuber` contains `name` and `tags` of the vtuber.
+pub fn render_context(event: &PublishExpanded) -> serde_json::Value {
+    serde_json::json!({
+        "id": event.meta.id,
+        "occurred_at": event.meta.occurred_at,
+        "vtuber": {
+            "name": event.vtuber,
+            "tags": event.tags,
+        },
+        "source": event.source,
+        "topic": event.topic,
+        "replay": event.replay,
+        "payload": &*event.data,
+    })
+}
+
+/// Compiled templates keyed by topic and locale, shared by all arbiters.
+#[derive(Debug)]
+pub struct Templates {
+    env: RwLock<Environment<'static>>,
+    config: TemplatesConfig,
+}
+
+impl Templates {
+    pub fn new(config: TemplatesConfig) -> Self {
+        Self {
+            env: RwLock::new(Environment::new()),
+            config,
+        }
+    }
+
+    pub fn default_locale(&self) -> &str {
+        &self.config.default_locale
+    }
+
+    /// Replace all templates. Malformed ones are skipped.
+    pub fn replace(&self, templates: Vec<StoredTemplate>) {
+        let mut env = Environment::new();
+        for template in templates {
+            let name = template_name(&template.topic, &template.locale);
+            if let Err(e) = env.add_template_owned(name.clone(), template.template) {
+                warn!("skipping malformed template {}: {}", name, e);
+            }
+        }
+        *self.env.write() = env;
+    }
+
+    /// Render an event with the template of its topic in given locale, or the default locale if missing.
+    ///
+    /// Returns `None` if there's no template for the topic.
+    ///
+    /// # Errors
+    /// Raise an error if the template fails to render.
+    pub fn render(
+        &self,
+        event: &PublishExpanded,
+        locale: &str,
+    ) -> Result<Option<String>, minijinja::Error> {
+        let env = self.env.read();
+        let template = [locale, self.default_locale()]
+            .into_iter()
+            .find_map(|locale| env.get_template(&template_name(&event.topic, locale)).ok());
+        template
+            .map(|template| template.render(render_context(event)))
+            .transpose()
+    }
+
+    /// Load templates from configured sources.
+    ///
+    /// Templates in mongodb take precedence over ones in the directory.
+    ///
+    /// # Errors
+    /// Raise an error if any source can't be read.
+    pub async fn load(
+        &self,
+        collection: &Collection<StoredTemplate>,
+    ) -> Result<Vec<StoredTemplate>, TemplateError> {
+        let mut templates = BTreeMap::new();
+        if let Some(directory) = &self.config.directory {
+            for template in load_directory(directory.as_ref()).await? {
+                templates.insert((template.topic.clone(), template.locale.clone()), template);
+            }
+        }
+        if self.config.mongodb {
+            for template in LoadTemplatesOp.execute(collection).await? {
+                templates.insert((template.topic.clone(), template.locale.clone()), template);
+            }
+        }
+        Ok(templates.into_values().collect())
+    }
+
+    /// Reload templates periodically. Templates are kept if a reload fails.
+    pub async fn watch(self: Arc<Self>, collection: Collection<StoredTemplate>) {
+        let mut interval = tokio::time::interval(self.config.reload_interval);
+        let mut loaded = None;
+        loop {
+            interval.tick().await;
+            match self.load(&collection).await {
+                Ok(templates) if loaded.as_ref() != Some(&templates) => {
+                    info!("loaded {} templates", templates.len());
+                    self.replace(templates.clone());
+                    loaded = Some(templates);
+                }
+                Ok(_) => {}
+                Err(e) => error!("unable to reload templates: {}", e),
+            }
+        }
+    }
+}
+
+/// Load templates from files named `<topic>.<locale>.j2` in given directory.
+///
+/// # Errors
+/// Raise an error if the directory or any template file can't be read.
+pub async fn load_directory(directory: &Path) -> io::Result<Vec<StoredTemplate>> {
+    let mut templates = Vec::new();
+    let mut entries = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        // topics may contain dots, so locale is the last segment
+        let stem = path.file_stem().and_then(|stem| stem.to_str());
+        if let Some((topic, locale)) = stem.and_then(|stem| stem.rsplit_once('.')) {
+            templates.push(StoredTemplate {
+                topic: topic.to_string(),
+                locale: locale.to_string(),
+                template: tokio::fs::read_to_string(&path).await?,
+            });
+        } else {
+            warn!("skipping template without locale: {}", path.display());
+        }
+    }
+    Ok(templates)
+}
+
+#[derive(Debug)]
+pub struct LoadTemplatesOp;
+
+#[async_trait]
+impl CollOperation for LoadTemplatesOp {
+    type Result = Vec<StoredTemplate>;
+    type Item = StoredTemplate;
+
+    const DESC: &'static str = "LoadTemplates";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection.find(doc! {}, None).await?.try_collect().await
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("io error: {0}")]
+    IO(#[from] io::Error),
+    #[error("database error: {0}")]
+    DBError(#[from] mongodb::error::Error),
+}
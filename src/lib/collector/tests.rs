@@ -3,12 +3,16 @@ use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
 
+use actix::{Actor, Handler, Recipient};
+use async_trait::async_trait;
 use futures::StreamExt;
 use lapin::options::{
     BasicAckOptions, BasicConsumeOptions, ExchangeDeclareOptions, QueueBindOptions,
     QueueDeclareOptions,
 };
 use lapin::{Channel, Connection, ConnectionProperties, ExchangeKind};
+use mongodb::Database;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use testcontainers::clients::Cli;
 use testcontainers::images::generic::{GenericImage, WaitFor};
@@ -16,11 +20,21 @@ use testcontainers::{Docker, RunArgs};
 use tokio::sync::{mpsc, oneshot};
 use tokio_amqp::LapinTokioExt;
 use tracing_test::traced_test;
+use uuid::Uuid;
 
 use crate::collector::amqp::AMQPFactory;
 use crate::collector::debug::DebugCollectorFactory;
+use crate::collector::envelope::{Envelope, EventMeta, ENVELOPE_VERSION};
+use crate::collector::file::FileFactory;
+use crate::config::{FileCollectorConfig, FsyncPolicy, RetryPolicyConfig};
+use crate::db::connect_db;
+use crate::ArbiterContext;
 
-use super::{CollectorFactory, PublishExpanded};
+use super::priority::Priority;
+use super::{
+    CollectorActor, CollectorFactory, CollectorFactoryWrapped, CollectorStats, Delivery, GetStats,
+    PublishExpanded, Replay,
+};
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 struct TestMsg {
@@ -44,15 +58,22 @@ async fn must_debug_collector() {
         a: 1,
         b: String::from("test"),
     };
-    assert!(
+    assert_eq!(
         collector
             .send(PublishExpanded {
+                meta: EventMeta::default(),
                 vtuber: String::from("v"),
+                source: String::from("debug"),
                 topic: String::from("blabla"),
                 data: Arc::new(msg.clone()),
+                tags: Vec::new(),
+                replay: false,
+                text: None,
+                dead_letter: None,
             })
             .await
             .expect("mailbox error"),
+        Delivery::Sent,
         "unable to publish event"
     );
 
@@ -60,6 +81,267 @@ async fn must_debug_collector() {
     assert!(logs_contain(serde_json::to_string(&msg).unwrap().as_str()));
 }
 
+fn test_event(vtuber: &str, msg: &TestMsg) -> PublishExpanded {
+    PublishExpanded {
+        meta: EventMeta {
+            id: Uuid::new_v4(),
+            ..EventMeta::default()
+        },
+        vtuber: String::from(vtuber),
+        source: String::from("debug"),
+        topic: String::from("blabla"),
+        data: Arc::new(msg.clone()),
+        tags: Vec::new(),
+        replay: false,
+        text: None,
+        dead_letter: None,
+    }
+}
+
+#[actix::test]
+async fn must_file_collector() {
+    let dir = tempfile::tempdir().expect("unable to create temp dir");
+    let factory = FileFactory::new(FileCollectorConfig {
+        enabled: true,
+        path: dir
+            .path()
+            .join("{topic}/{vtuber}.jsonl")
+            .display()
+            .to_string(),
+        fsync: FsyncPolicy::Always,
+        ..Default::default()
+    });
+    let collector = factory.build().await.expect("unable to build collector");
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    for vtuber in ["a", "b", "a"] {
+        assert_eq!(
+            collector
+                .send(test_event(vtuber, &msg))
+                .await
+                .expect("mailbox error"),
+            Delivery::Sent,
+            "unable to publish event"
+        );
+    }
+
+    let content =
+        std::fs::read_to_string(dir.path().join("blabla/a.jsonl")).expect("unable to read output");
+    let lines: Vec<serde_json::Value> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("invalid json line"))
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["vtuber"], "a");
+    assert_eq!(lines[0]["topic"], "blabla");
+    assert_eq!(lines[0]["version"], ENVELOPE_VERSION);
+    assert!(lines[0]["emitted_at"].is_i64(), "missing timestamp");
+    assert_eq!(
+        serde_json::from_value::<TestMsg>(lines[0]["payload"].clone()).unwrap(),
+        msg
+    );
+    assert!(dir.path().join("blabla/b.jsonl").exists());
+}
+
+#[actix::test]
+async fn must_file_collector_rotate() {
+    let dir = tempfile::tempdir().expect("unable to create temp dir");
+    let factory = FileFactory::new(FileCollectorConfig {
+        enabled: true,
+        path: dir.path().join("{vtuber}.jsonl").display().to_string(),
+        max_size: Some(1),
+        compress: true,
+        ..Default::default()
+    });
+    let collector = factory.build().await.expect("unable to build collector");
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    for _ in 0..2 {
+        assert_eq!(
+            collector
+                .send(test_event("v", &msg))
+                .await
+                .expect("mailbox error"),
+            Delivery::Sent,
+            "unable to publish event"
+        );
+        // ensure rotated files have different names
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let names: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names.len(), 2, "unexpected files: {:?}", names);
+    assert!(names.iter().any(|name| name == "v.jsonl"));
+    assert!(names
+        .iter()
+        .any(|name| name.starts_with("v.jsonl.") && name.ends_with(".gz")));
+}
+
+#[actix::test]
+async fn must_share_files_among_collectors() {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    let dir = tempfile::tempdir().expect("unable to create temp dir");
+    let factory = FileFactory::new(FileCollectorConfig {
+        enabled: true,
+        path: dir.path().join("{vtuber}.jsonl").display().to_string(),
+        max_size: Some(1),
+        compress: true,
+        ..Default::default()
+    });
+    // one collector per arbiter
+    let collectors = [
+        factory.build().await.expect("unable to build collector"),
+        factory.build().await.expect("unable to build collector"),
+    ];
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    for collector in collectors.iter().cycle().take(6) {
+        assert_eq!(
+            collector
+                .send(test_event("v", &msg))
+                .await
+                .expect("mailbox error"),
+            Delivery::Sent,
+            "unable to publish event"
+        );
+        // ensure rotated files have different names
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut lines = Vec::new();
+    for entry in std::fs::read_dir(dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        let mut content = String::new();
+        if path.extension().is_some_and(|ext| ext == "gz") {
+            GzDecoder::new(std::fs::File::open(&path).unwrap())
+                .read_to_string(&mut content)
+                .unwrap();
+        } else {
+            content = std::fs::read_to_string(&path).unwrap();
+        }
+        // every file is rotated before it exceeds the max size
+        assert_eq!(content.lines().count(), 1, "{} oversized", path.display());
+        lines.extend(content.lines().map(String::from));
+    }
+    assert_eq!(lines.len(), 6, "lines lost across rotations");
+}
+
+#[derive(Debug, Default)]
+struct RecordingFactory {
+    ident: &'static str,
+    received: Arc<Mutex<Vec<PublishExpanded>>>,
+}
+
+impl RecordingFactory {
+    fn new(ident: &'static str) -> Self {
+        Self {
+            ident,
+            received: Arc::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl CollectorFactory for RecordingFactory {
+    fn ident(&self) -> String {
+        self.ident.to_string()
+    }
+
+    async fn build(&self) -> Option<Recipient<PublishExpanded>> {
+        Some(
+            RecordingCollector(self.received.clone())
+                .start()
+                .recipient(),
+        )
+    }
+}
+
+struct RecordingCollector(Arc<Mutex<Vec<PublishExpanded>>>);
+
+impl Actor for RecordingCollector {
+    type Context = actix::Context<Self>;
+}
+
+impl Handler<PublishExpanded> for RecordingCollector {
+    type Result = Delivery;
+
+    fn handle(&mut self, msg: PublishExpanded, _ctx: &mut Self::Context) -> Self::Result {
+        self.0.lock().push(msg);
+        Delivery::Sent
+    }
+}
+
+async fn test_db() -> Database {
+    // the driver connects lazily, so no server is needed as long as we don't touch the db
+    connect_db("mongodb://127.0.0.1:1", "stargazer_test")
+        .await
+        .expect("unable to create db handle")
+}
+
+#[actix::test]
+async fn must_replay_to_target() {
+    ArbiterContext::set(ArbiterContext::new(Uuid::new_v4()));
+
+    let factory_a = RecordingFactory::new("a");
+    let factory_b = RecordingFactory::new("b");
+    let (received_a, received_b) = (factory_a.received.clone(), factory_b.received.clone());
+    let addr =
+        CollectorActor::new(test_db().await, vec![factory_a.into(), factory_b.into()]).start();
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    let collectors = addr
+        .send(Replay {
+            events: vec![test_event("v", &msg), test_event("v", &msg)],
+            target: Some(String::from("a")),
+        })
+        .await
+        .expect("mailbox error");
+    assert_eq!(collectors, vec![String::from("a")]);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    {
+        let received_a = received_a.lock();
+        assert_eq!(received_a.len(), 2);
+        assert!(
+            received_a.iter().all(|event| event.replay),
+            "replay flag not set"
+        );
+    }
+    assert!(
+        received_b.lock().is_empty(),
+        "event replayed to wrong collector"
+    );
+
+    let collectors = addr
+        .send(Replay {
+            events: vec![],
+            target: Some(String::from("c")),
+        })
+        .await
+        .expect("mailbox error");
+    assert!(collectors.is_empty());
+}
+
 #[actix::test]
 async fn must_amqp_collector() {
     if option_env!("TEST_FAST").is_some() {
@@ -168,15 +450,26 @@ async fn must_amqp_publish(uri: &'static str) {
         a: 1,
         b: String::from("test"),
     };
-    assert!(
+    let id = Uuid::new_v4();
+    assert_eq!(
         collector
             .send(PublishExpanded {
-                vtuber: String::new(),
+                meta: EventMeta {
+                    id,
+                    ..Default::default()
+                },
+                vtuber: String::from("v"),
+                source: String::from("debug"),
                 topic: String::from("blabla"),
                 data: Arc::new(msg.clone()),
+                tags: Vec::new(),
+                replay: false,
+                text: None,
+                dead_letter: None,
             })
             .await
             .expect("mailbox error"),
+        Delivery::Sent,
         "unable to publish event"
     );
 
@@ -185,10 +478,1252 @@ async fn must_amqp_publish(uri: &'static str) {
     {
         let received_msgs_ref = received_msgs.borrow();
         assert_eq!(received_msgs_ref.len(), 1);
-        let received_msg: TestMsg = serde_json::from_slice(&*received_msgs_ref.first().unwrap())
-            .expect("unable to deserialize msg");
-        assert_eq!(msg, received_msg);
+        let received_msg: Envelope<TestMsg> =
+            serde_json::from_slice(received_msgs_ref.first().unwrap())
+                .expect("unable to deserialize msg");
+        assert_eq!(received_msg.version, ENVELOPE_VERSION);
+        assert_eq!(received_msg.id, id);
+        assert_eq!(received_msg.vtuber, "v");
+        assert_eq!(received_msg.topic, "blabla");
+        assert_eq!(received_msg.payload, msg);
     }
 
     handler.await.unwrap();
 }
+
+#[test]
+fn must_replay_raw_payloads() {
+    use mongodb::bson::{self, oid::ObjectId, DateTime};
+
+    use super::archive::ArchivedEvent;
+
+    let archived = |payload: &serde_json::Value, raw: bool| ArchivedEvent {
+        id: ObjectId::new(),
+        vtuber: String::from("v"),
+        meta: EventMeta::default(),
+        source: String::from("debug"),
+        topic: String::from("blabla"),
+        timestamp: DateTime::now(),
+        payload: bson::to_bson(payload).unwrap_or(bson::Bson::Null),
+        raw_payload: raw.then(|| payload.to_string()),
+    };
+    let replayed = |event: ArchivedEvent| {
+        serde_json::to_value(&*PublishExpanded::from(event).data).expect("unable to serialize")
+    };
+
+    // integers beyond i64 can't be stored in BSON
+    let payload = serde_json::json!({"id": u64::MAX, "price": 1.5});
+    assert_eq!(replayed(archived(&payload, true)), payload);
+
+    // events archived before raw payloads are kept fall back to BSON ones
+    let payload = serde_json::json!({"a": 1, "b": "test"});
+    assert_eq!(replayed(archived(&payload, false)), payload);
+}
+
+#[test]
+fn must_keep_raw_payloads_in_outbox() {
+    use mongodb::bson;
+
+    use super::outbox::OutboxEntry;
+
+    let persisted = |payload: serde_json::Value| {
+        let event = PublishExpanded {
+            data: Arc::new(payload),
+            ..test_event(
+                "v",
+                &TestMsg {
+                    a: 1,
+                    b: String::from("test"),
+                },
+            )
+        };
+        let entry = OutboxEntry::new(
+            String::from("a"),
+            Uuid::new_v4(),
+            None,
+            true,
+            Priority::Normal,
+            &event,
+        )
+        .expect("unable to persist event");
+        // round-trip through mongodb
+        bson::from_document::<OutboxEntry>(bson::to_document(&entry).unwrap()).unwrap()
+    };
+    let loaded = |entry: OutboxEntry| {
+        serde_json::to_value(&*PublishExpanded::from(entry).data).expect("unable to serialize")
+    };
+
+    // integers beyond i64 can't be stored in BSON
+    let payload = serde_json::json!({"id": u64::MAX, "price": 1.5});
+    assert_eq!(loaded(persisted(payload.clone())), payload);
+
+    // entries persisted before raw payloads are kept fall back to BSON ones
+    let payload = serde_json::json!({"a": 1, "b": "test"});
+    let mut entry = persisted(payload.clone());
+    entry.raw_payload = None;
+    entry.payload = Some(bson::to_bson(&payload).unwrap());
+    assert_eq!(loaded(entry), payload);
+}
+
+#[test]
+fn must_keep_raw_payloads_in_dead_letters() {
+    use mongodb::bson;
+
+    use super::dead_letter::DeadLetter;
+
+    // integers beyond i64 can't be stored in BSON, but such events can still be dead-lettered
+    let payload = serde_json::json!({"id": u64::MAX, "price": 1.5});
+    let event = PublishExpanded {
+        data: Arc::new(payload.clone()),
+        ..test_event(
+            "v",
+            &TestMsg {
+                a: 1,
+                b: String::from("test"),
+            },
+        )
+    };
+    let letter = DeadLetter::new(String::from("a"), &event, 5).expect("unable to build letter");
+    assert_eq!(letter.payload, bson::Bson::Null);
+    // round-trip through mongodb
+    let letter = bson::from_document::<DeadLetter>(bson::to_document(&letter).unwrap()).unwrap();
+    let replayed = PublishExpanded::from(letter);
+    assert_eq!(serde_json::to_value(&*replayed.data).unwrap(), payload);
+}
+
+#[test]
+fn must_build_events_query() {
+    use mongodb::bson::{doc, DateTime};
+
+    use super::archive::{EventsFilter, EventsQuery};
+
+    let (filter, limit) = EventsQuery::default().to_document().unwrap();
+    assert!(filter.is_empty());
+    assert_eq!(limit, 50);
+
+    let query = EventsQuery {
+        filter: EventsFilter {
+            vtuber: Some(String::from("v")),
+            topic: Some(String::from("twitter")),
+            since: Some(String::from("2022-02-24T00:00:00Z")),
+            until: None,
+        },
+        cursor: Some(String::from("62170b9a0000000000000000")),
+        limit: Some(10),
+    };
+    let (filter, limit) = query.to_document().unwrap();
+    assert_eq!(limit, 10);
+    assert_eq!(
+        filter,
+        doc! {
+            "vtuber": "v",
+            "topic": "twitter",
+            "timestamp": {"$gte": DateTime::from_millis(1_645_660_800_000)},
+            "_id": {"$lt": mongodb::bson::oid::ObjectId::parse_str("62170b9a0000000000000000").unwrap()}
+        }
+    );
+
+    for query in [
+        EventsQuery {
+            limit: Some(0),
+            ..Default::default()
+        },
+        EventsQuery {
+            cursor: Some(String::from("invalid")),
+            ..Default::default()
+        },
+        EventsQuery {
+            filter: EventsFilter {
+                until: Some(String::from("yesterday")),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    ] {
+        assert!(query.to_document().is_err(), "invalid query accepted");
+    }
+}
+
+#[actix::test]
+async fn must_stream_resume_and_filter() {
+    use super::stream::{StreamFilter, StreamHub};
+    use crate::config::StreamConfig;
+
+    let hub = Arc::new(StreamHub::new(StreamConfig {
+        enabled: true,
+        replay_buffer: 2,
+        client_buffer: 16,
+    }));
+    let collector = super::stream::StreamFactory::new(hub.clone())
+        .build()
+        .await
+        .expect("unable to build collector");
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    for vtuber in ["a", "b", "a"] {
+        assert_eq!(
+            collector
+                .send(test_event(vtuber, &msg))
+                .await
+                .expect("mailbox error"),
+            Delivery::Sent
+        );
+    }
+
+    // only the last two events are buffered
+    let mut resumed = Box::pin(hub.events(StreamFilter::default(), Some(0)));
+    for id in [2, 3] {
+        assert_eq!(resumed.next().await.unwrap().unwrap().seq, id);
+    }
+
+    let mut filtered = Box::pin(hub.events(
+        StreamFilter {
+            vtuber: Some(String::from("a")),
+            topic: None,
+        },
+        Some(1),
+    ));
+    let event = filtered.next().await.unwrap().unwrap();
+    assert_eq!((event.seq, event.envelope.vtuber.as_str()), (3, "a"));
+    assert_eq!(
+        serde_json::from_value::<TestMsg>(event.envelope.payload.clone()).unwrap(),
+        msg
+    );
+
+    collector.send(test_event("b", &msg)).await.unwrap();
+    collector.send(test_event("a", &msg)).await.unwrap();
+    assert_eq!(resumed.next().await.unwrap().unwrap().seq, 4);
+    assert_eq!(filtered.next().await.unwrap().unwrap().seq, 5);
+}
+
+#[actix::test]
+async fn must_stream_drop_slow_client() {
+    use super::stream::{StreamFilter, StreamHub};
+    use crate::config::StreamConfig;
+
+    let hub = StreamHub::new(StreamConfig {
+        enabled: true,
+        replay_buffer: 0,
+        client_buffer: 2,
+    });
+    let mut slow = Box::pin(hub.events(StreamFilter::default(), None));
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    let event = test_event("v", &msg);
+    for _ in 0..4 {
+        hub.publish(Envelope::new(&event).into_value().unwrap());
+    }
+
+    assert!(
+        slow.next().await.unwrap().is_err(),
+        "slow client not lagged"
+    );
+    assert!(slow.next().await.is_none(), "slow client not dropped");
+}
+
+#[derive(Debug)]
+struct BrokenFactory;
+
+#[async_trait]
+impl CollectorFactory for BrokenFactory {
+    fn ident(&self) -> String {
+        String::from("broken")
+    }
+
+    async fn build(&self) -> Option<Recipient<PublishExpanded>> {
+        None
+    }
+}
+
+#[actix::test]
+async fn must_count_dropped_events() {
+    ArbiterContext::set(ArbiterContext::new(Uuid::new_v4()));
+
+    let addr = CollectorActor::new(test_db().await, vec![BrokenFactory.into()]).start();
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    addr.send(Replay {
+        events: vec![test_event("v", &msg); 1030],
+        target: None,
+    })
+    .await
+    .expect("mailbox error");
+
+    let stats = addr.send(GetStats).await.expect("mailbox error");
+    assert_eq!(
+        stats["broken"],
+        CollectorStats {
+            queued: 1024,
+            delivered: 0,
+            spilled: 0,
+            dropped: 6,
+            dead_lettered: 0,
+            digested: 0,
+            sampled: 0,
+        }
+    );
+}
+
+#[test]
+fn must_convert_outbox_entry() {
+    use super::outbox::OutboxEntry;
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    let entry = OutboxEntry::new(
+        String::from("debug"),
+        Uuid::new_v4(),
+        Some(String::from("a")),
+        true,
+        Priority::High,
+        &test_event("v", &msg),
+    )
+    .expect("unable to convert event");
+    let entry: OutboxEntry = mongodb::bson::from_document(
+        mongodb::bson::to_document(&entry).expect("unable to serialize entry"),
+    )
+    .expect("unable to deserialize entry");
+    assert_eq!(entry.priority, Priority::High);
+
+    let event = PublishExpanded::from(entry);
+    assert_eq!(
+        (event.vtuber.as_str(), event.topic.as_str()),
+        ("v", "blabla")
+    );
+    assert!(!event.replay);
+    assert_eq!(
+        serde_json::from_value::<TestMsg>(serde_json::to_value(&*event.data).unwrap()).unwrap(),
+        msg
+    );
+}
+
+#[actix::test]
+async fn must_redeliver_outbox_events() {
+    use super::outbox::{Outbox, OutboxEntry};
+    use crate::tests::with_db;
+    use crate::utils::timestamp;
+
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        ArbiterContext::set(ArbiterContext::new(Uuid::new_v4()));
+        let collection = db.collection::<OutboxEntry>("outbox");
+        let outbox = |started_at| Outbox {
+            collection: collection.clone(),
+            lease: Duration::from_secs(60),
+            instance: Some(String::from("a")),
+            started_at,
+        };
+
+        // the broker is down, so the event is kept in the outbox
+        let factory = CollectorFactoryWrapped::from(RejectingFactory(Delivery::Failed));
+        let addr = CollectorActor::new(db.clone(), vec![factory])
+            .outbox(outbox(timestamp(std::time::SystemTime::now())))
+            .start();
+        let msg = TestMsg {
+            a: 1,
+            b: String::from("test"),
+        };
+        addr.send(Replay {
+            events: vec![test_event("v", &msg)],
+            target: None,
+        })
+        .await
+        .expect("mailbox error");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(collection.count_documents(None, None).await.unwrap(), 1);
+
+        // the instance restarts, and the event left by the previous run is delivered at once
+        let factory = RecordingFactory::new("rejecting");
+        let received = factory.received.clone();
+        let _addr = CollectorActor::new(db.clone(), vec![factory.into()])
+            .outbox(outbox(timestamp(std::time::SystemTime::now())))
+            .start();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(received.lock().len(), 1);
+        assert_eq!(
+            serde_json::from_value::<TestMsg>(
+                serde_json::to_value(&*received.lock()[0].data).unwrap()
+            )
+            .unwrap(),
+            msg
+        );
+        assert_eq!(
+            collection.count_documents(None, None).await.unwrap(),
+            0,
+            "event not acknowledged"
+        );
+    })
+    .await;
+}
+
+#[actix::test]
+async fn must_claim_idempotency_keys() {
+    use super::dedup::{ClaimKeyOp, IdempotencyKey, ReleaseKeyOp};
+    use crate::db::CollOperation;
+    use crate::tests::with_db;
+
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        let collection = db.collection::<IdempotencyKey>("idempotency_keys");
+        let claim = |key: &str, window| ClaimKeyOp {
+            key: key.to_string(),
+            window,
+        };
+        let window = Duration::from_secs(60);
+
+        assert!(claim("a", window).execute(&collection).await.unwrap());
+        assert!(
+            !claim("a", window).execute(&collection).await.unwrap(),
+            "key claimed twice within window"
+        );
+        assert!(claim("b", window).execute(&collection).await.unwrap());
+
+        // a released key can be claimed again
+        ReleaseKeyOp(String::from("a"))
+            .execute(&collection)
+            .await
+            .unwrap();
+        assert!(claim("a", window).execute(&collection).await.unwrap());
+
+        // so can an expired one, even if mongodb hasn't removed it yet
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(claim("b", Duration::from_millis(10))
+            .execute(&collection)
+            .await
+            .unwrap());
+        assert_eq!(collection.count_documents(None, None).await.unwrap(), 2);
+    })
+    .await;
+}
+
+#[actix::test]
+async fn must_cache_admitted_epochs() {
+    use super::envelope::FencingToken;
+    use mongodb::bson::doc;
+
+    use super::fencing::{Fence, Fencing};
+    use crate::config::FencingConfig;
+    use crate::tests::with_db;
+
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        let collection = db.collection::<Fence>("fences");
+        let token = |epoch| FencingToken {
+            entry: String::from("a"),
+            epoch,
+        };
+        let bump = |epoch: i64| {
+            let collection = collection.clone();
+            async move {
+                collection
+                    .update_one(doc! {"_id": "a"}, doc! {"$set": {"epoch": epoch}}, None)
+                    .await
+                    .unwrap();
+            }
+        };
+
+        let fencing = Fencing::new(
+            collection.clone(),
+            FencingConfig {
+                cache_ttl: Duration::from_secs(60),
+                ..Default::default()
+            },
+        );
+        assert!(fencing.admit(&token(1)).await.unwrap());
+        // a newer owner takes over behind the cache's back, but the admitted epoch is still trusted
+        bump(3).await;
+        assert!(fencing.admit(&token(1)).await.unwrap());
+        // a new epoch is checked against mongodb
+        assert!(!fencing.admit(&token(2)).await.unwrap());
+        assert!(fencing.admit(&token(3)).await.unwrap());
+        // older epochs are rejected without a round-trip
+        assert!(!fencing.admit(&token(1)).await.unwrap());
+
+        // the cached epoch is rechecked once it expires
+        let fencing = Fencing::new(
+            collection.clone(),
+            FencingConfig {
+                cache_ttl: Duration::from_millis(10),
+                ..Default::default()
+            },
+        );
+        assert!(fencing.admit(&token(3)).await.unwrap());
+        bump(4).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!fencing.admit(&token(3)).await.unwrap());
+    })
+    .await;
+}
+
+#[actix::test]
+async fn must_update_ttl_index() {
+    use super::dedup::{create_indexes, IdempotencyKey};
+    use crate::tests::with_db;
+
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        let collection = db.collection::<IdempotencyKey>("idempotency_keys");
+        let expiry = || async {
+            let indexes: Vec<_> = collection.list_indexes(None).await.unwrap().collect().await;
+            indexes
+                .into_iter()
+                .filter_map(|index| index.unwrap().options?.expire_after)
+                .collect::<Vec<_>>()
+        };
+
+        create_indexes(&db, &collection, Duration::from_secs(60))
+            .await
+            .expect("unable to create index");
+        assert_eq!(expiry().await, vec![Duration::from_secs(60)]);
+
+        // the window is changed in config, and the existing index is modified instead of conflicting
+        create_indexes(&db, &collection, Duration::from_secs(120))
+            .await
+            .expect("unable to update index");
+        assert_eq!(expiry().await, vec![Duration::from_secs(120)]);
+    })
+    .await;
+}
+
+#[actix::test]
+async fn must_load_high_priority_events_first() {
+    use super::outbox::{InsertOutboxOp, LoadOutboxOp, OutboxEntry};
+    use crate::db::CollOperation;
+    use crate::tests::with_db;
+
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        let collection = db.collection::<OutboxEntry>("outbox");
+        let owner = Uuid::new_v4();
+        let msg = TestMsg {
+            a: 1,
+            b: String::from("test"),
+        };
+        let entries = [
+            Priority::Low,
+            Priority::Normal,
+            Priority::High,
+            Priority::Normal,
+        ]
+        .into_iter()
+        .map(|priority| {
+            OutboxEntry::new(
+                String::from("c"),
+                owner,
+                None,
+                false,
+                priority,
+                &test_event("v", &msg),
+            )
+            .unwrap()
+        })
+        .collect();
+        InsertOutboxOp(entries).execute(&collection).await.unwrap();
+
+        let load = |limit| LoadOutboxOp {
+            collector: String::from("c"),
+            owner,
+            limit,
+        };
+        let loaded = load(2).execute(&collection).await.unwrap();
+        assert_eq!(
+            loaded
+                .iter()
+                .map(|entry| entry.priority)
+                .collect::<Vec<_>>(),
+            [Priority::High, Priority::Normal]
+        );
+        let loaded = load(2).execute(&collection).await.unwrap();
+        assert_eq!(
+            loaded
+                .iter()
+                .map(|entry| entry.priority)
+                .collect::<Vec<_>>(),
+            [Priority::Normal, Priority::Low]
+        );
+    })
+    .await;
+}
+
+/// Answers every event with the same outcome.
+#[derive(Debug)]
+struct RejectingFactory(Delivery);
+
+#[async_trait]
+impl CollectorFactory for RejectingFactory {
+    fn ident(&self) -> String {
+        String::from("rejecting")
+    }
+
+    async fn build(&self) -> Option<Recipient<PublishExpanded>> {
+        Some(RejectingCollector(self.0).start().recipient())
+    }
+}
+
+struct RejectingCollector(Delivery);
+
+impl Actor for RejectingCollector {
+    type Context = actix::Context<Self>;
+}
+
+impl Handler<PublishExpanded> for RejectingCollector {
+    type Result = Delivery;
+
+    fn handle(&mut self, _msg: PublishExpanded, _ctx: &mut Self::Context) -> Self::Result {
+        self.0
+    }
+}
+
+async fn rejected_stats(delivery: Delivery, policy: RetryPolicyConfig) -> CollectorStats {
+    ArbiterContext::set(ArbiterContext::new(Uuid::new_v4()));
+
+    let factory = CollectorFactoryWrapped::from(RejectingFactory(delivery)).retry(policy);
+    let addr = CollectorActor::new(test_db().await, vec![factory]).start();
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    addr.send(Replay {
+        events: vec![test_event("v", &msg)],
+        target: None,
+    })
+    .await
+    .expect("mailbox error");
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    addr.send(GetStats).await.expect("mailbox error")["rejecting"]
+}
+
+#[actix::test]
+async fn must_dead_letter_rejected_event() {
+    let stats = rejected_stats(
+        Delivery::Rejected,
+        RetryPolicyConfig {
+            initial_backoff: Duration::from_millis(1),
+            jitter: false,
+            max_attempts: 3,
+            breaker_threshold: 0,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!((stats.queued, stats.dead_lettered), (0, 1));
+}
+
+#[actix::test]
+async fn must_not_count_attempts_when_breaker_open() {
+    let stats = rejected_stats(
+        Delivery::Rejected,
+        RetryPolicyConfig {
+            initial_backoff: Duration::from_millis(1),
+            jitter: false,
+            max_attempts: 3,
+            breaker_threshold: 2,
+            breaker_cooldown: Duration::from_millis(1),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!((stats.queued, stats.dead_lettered), (1, 0));
+}
+
+#[actix::test]
+async fn must_keep_events_during_outage() {
+    // the default policy, only faster
+    let stats = rejected_stats(
+        Delivery::Failed,
+        RetryPolicyConfig {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            breaker_cooldown: Duration::from_millis(5),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!((stats.queued, stats.dead_lettered), (1, 0));
+}
+
+#[actix::test]
+async fn must_drop_discarded_event() {
+    let stats = rejected_stats(Delivery::Discarded, RetryPolicyConfig::default()).await;
+    assert_eq!(
+        (stats.queued, stats.dropped, stats.dead_lettered),
+        (0, 1, 0)
+    );
+}
+
+#[actix::test]
+async fn must_redeliver_archive_dead_letters() {
+    use super::archive::{ArchiveFactory, ArchivedEvent};
+    use super::dead_letter::{DeadLetter, InsertDeadLetterOp};
+    use crate::db::CollOperation;
+    use crate::tests::with_db;
+
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        ArbiterContext::set(ArbiterContext::new(Uuid::new_v4()));
+        let events = db.collection::<ArchivedEvent>("events");
+        let letters = db.collection::<DeadLetter>("dead_letters");
+        let factory = ArchiveFactory::new(events.clone());
+        let ident = factory.ident();
+
+        // given up by the archive, e.g. during an outage
+        let msg = TestMsg {
+            a: 1,
+            b: String::from("test"),
+        };
+        let letter = DeadLetter::new(ident.clone(), &test_event("v", &msg), 5).unwrap();
+        InsertDeadLetterOp(letter.clone())
+            .execute(&letters)
+            .await
+            .unwrap();
+
+        let addr = CollectorActor::new(db.clone(), vec![factory.into()]).start();
+        let collectors = addr
+            .send(Replay {
+                events: vec![letter.into()],
+                target: Some(ident.clone()),
+            })
+            .await
+            .expect("mailbox error");
+        assert_eq!(collectors, vec![ident]);
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let archived = events
+            .find_one(None, None)
+            .await
+            .unwrap()
+            .expect("dead letter not archived");
+        assert_eq!(archived.vtuber, "v");
+        assert_eq!(
+            serde_json::from_str::<TestMsg>(archived.raw_payload.as_deref().unwrap()).unwrap(),
+            msg
+        );
+        // removed once delivered
+        assert_eq!(letters.count_documents(None, None).await.unwrap(), 0);
+    })
+    .await;
+}
+
+#[test]
+fn must_parse_dead_letters_query() {
+    use super::dead_letter::DeadLettersQuery;
+
+    let query = actix_web::web::Query::<DeadLettersQuery>::from_query(
+        "collector=amqp&vtuber=v&since=2022-02-24T00:00:00Z&limit=10",
+    )
+    .expect("unable to parse query")
+    .into_inner();
+    assert_eq!(query.limit, Some(10));
+
+    let filter = query.filter.to_document().unwrap();
+    assert_eq!(filter.get_str("collector").unwrap(), "amqp");
+    assert_eq!(filter.get_str("vtuber").unwrap(), "v");
+    assert!(filter.contains_key("timestamp"));
+}
+
+#[test]
+fn must_route_events() {
+    use super::routing::Routing;
+    use crate::config::RouteConfig;
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    let event = |vtuber: &str, topic: &str, tags: &[&str]| PublishExpanded {
+        topic: String::from(topic),
+        tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        ..test_event(vtuber, &msg)
+    };
+
+    let all = Routing::default();
+    assert!(all.matches(&event("a", "bililive.superchat", &[])));
+
+    let routing = Routing::new(&RouteConfig {
+        topics: vec![String::from("bililive.*"), String::from("twitter")],
+        vtubers: vec![],
+        tags: vec![String::from("hololive")],
+    })
+    .expect("invalid route");
+    assert!(routing.matches(&event("a", "bililive.superchat", &["hololive"])));
+    assert!(routing.matches(&event("b", "twitter", &["niji", "hololive"])));
+    assert!(!routing.matches(&event("a", "bililive.superchat", &["niji"])));
+    assert!(!routing.matches(&event("a", "debug", &["hololive"])));
+
+    let routing = Routing::new(&RouteConfig {
+        vtubers: vec![String::from("a")],
+        ..Default::default()
+    })
+    .expect("invalid route");
+    assert!(routing.matches(&event("a", "debug", &[])));
+    assert!(!routing.matches(&event("b", "debug", &[])));
+
+    assert!(Routing::new(&RouteConfig {
+        topics: vec![String::from("[")],
+        ..Default::default()
+    })
+    .is_err());
+}
+
+#[test]
+fn must_render_routing_key() {
+    use super::amqp::render_routing_key;
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    let event = test_event("a", &msg);
+    assert_eq!(render_routing_key("{vtuber}.{topic}", &event), "a.blabla");
+    assert_eq!(
+        render_routing_key("stargazer.{source}.{vtuber}.{topic}", &event),
+        "stargazer.debug.a.blabla"
+    );
+    assert_eq!(render_routing_key("fixed", &event), "fixed");
+}
+
+#[test]
+fn must_wrap_event_in_envelope() {
+    use super::envelope::envelope_schema;
+    use crate::source::payload_schemas;
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    let event = test_event("a", &msg);
+    let envelope = serde_json::to_value(Envelope::new(&event)).unwrap();
+    assert_eq!(envelope["version"], ENVELOPE_VERSION);
+    assert_eq!(envelope["id"], event.meta.id.to_string());
+    assert_eq!(envelope["vtuber"], "a");
+    assert_eq!(envelope["source"], "debug");
+    assert_eq!(envelope["topic"], "blabla");
+    assert!(envelope["emitted_at"].is_i64(), "missing emission time");
+    assert!(envelope.get("replay").is_none(), "replay flag set");
+    assert_eq!(
+        serde_json::from_value::<TestMsg>(envelope["payload"].clone()).unwrap(),
+        msg
+    );
+
+    let schema = serde_json::to_value(envelope_schema()).unwrap();
+    let properties = schema["properties"].as_object().unwrap();
+    for field in envelope.as_object().unwrap().keys() {
+        assert!(
+            properties.contains_key(field),
+            "{} missing in schema",
+            field
+        );
+    }
+    let payloads = payload_schemas();
+    for topic in ["twitter", "bililive.*", "debug.tick"] {
+        assert!(payloads.contains_key(topic), "{} missing in schemas", topic);
+    }
+    let bililive = serde_json::to_value(&payloads["bililive.*"]).unwrap();
+    assert_eq!(bililive["required"], serde_json::json!(["cmd"]));
+}
+
+#[test]
+fn must_encode_payloads() {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::encoding::{content_encoding, content_type, encode};
+    use crate::config::{Compression, EncodingConfig, PayloadFormat};
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    let decode = |data: &[u8], format: PayloadFormat| -> TestMsg {
+        match format {
+            PayloadFormat::Json => serde_json::from_slice(data).unwrap(),
+            PayloadFormat::MsgPack => rmp_serde::from_slice(data).unwrap(),
+            PayloadFormat::Cbor => ciborium::de::from_reader(data).unwrap(),
+        }
+    };
+    for format in [
+        PayloadFormat::Json,
+        PayloadFormat::MsgPack,
+        PayloadFormat::Cbor,
+    ] {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let encoding = EncodingConfig {
+                format,
+                compression,
+            };
+            let data = encode(&msg, encoding, false).expect("unable to encode");
+            let data = match compression {
+                Compression::None => data,
+                Compression::Gzip => {
+                    let mut decoded = Vec::new();
+                    GzDecoder::new(data.as_slice())
+                        .read_to_end(&mut decoded)
+                        .unwrap();
+                    decoded
+                }
+                Compression::Zstd => zstd::decode_all(data.as_slice()).unwrap(),
+            };
+            assert_eq!(decode(&data, format), msg, "{:?}", encoding);
+        }
+    }
+
+    let line = encode(&msg, EncodingConfig::default(), true).unwrap();
+    assert_eq!(line.last(), Some(&b'\n'));
+    assert_eq!(content_type(PayloadFormat::MsgPack), "application/msgpack");
+    assert_eq!(content_encoding(Compression::None), None);
+    assert_eq!(content_encoding(Compression::Zstd), Some("zstd"));
+}
+
+#[test]
+fn must_carry_idempotency_key() {
+    use super::outbox::OutboxEntry;
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    let mut event = test_event("a", &msg);
+    let envelope = serde_json::to_value(Envelope::new(&event)).unwrap();
+    assert!(envelope.get("idempotency_key").is_none());
+
+    event.meta.idempotency_key = Some(String::from("42:1-2"));
+    let envelope = serde_json::to_value(Envelope::new(&event)).unwrap();
+    assert_eq!(envelope["idempotency_key"], "42:1-2");
+
+    // the key survives persistence
+    let entry = OutboxEntry::new(
+        String::from("c"),
+        Uuid::new_v4(),
+        None,
+        false,
+        Priority::Normal,
+        &event,
+    )
+    .unwrap();
+    let entry: OutboxEntry =
+        mongodb::bson::from_document(mongodb::bson::to_document(&entry).unwrap()).unwrap();
+    let event = PublishExpanded::from(entry);
+    assert_eq!(event.meta.idempotency_key.as_deref(), Some("42:1-2"));
+}
+
+#[test]
+fn must_carry_fencing_token() {
+    use super::envelope::FencingToken;
+    use super::outbox::OutboxEntry;
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    let mut event = test_event("a", &msg);
+    let envelope = serde_json::to_value(Envelope::new(&event)).unwrap();
+    assert!(envelope.get("fencing_token").is_none());
+
+    let token = FencingToken {
+        entry: String::from("62a3c1f0e4b0a1b2c3d4e5f6"),
+        epoch: 3,
+    };
+    event.meta.fencing_token = Some(token.clone());
+    let envelope = serde_json::to_value(Envelope::new(&event)).unwrap();
+    assert_eq!(
+        envelope["fencing_token"],
+        serde_json::json!({"entry": "62a3c1f0e4b0a1b2c3d4e5f6", "epoch": 3})
+    );
+
+    // the token survives persistence
+    let entry = OutboxEntry::new(
+        String::from("c"),
+        Uuid::new_v4(),
+        None,
+        false,
+        Priority::Normal,
+        &event,
+    )
+    .unwrap();
+    let entry: OutboxEntry =
+        mongodb::bson::from_document(mongodb::bson::to_document(&entry).unwrap()).unwrap();
+    let event = PublishExpanded::from(entry);
+    assert_eq!(event.meta.fencing_token, Some(token));
+}
+
+#[test]
+fn must_summarize_digest() {
+    use super::digest::{Aggregate, Digest, Summary, TopItem, DIGEST_TOPIC};
+    use crate::config::DigestConfig;
+
+    let config = DigestConfig {
+        topics: vec![String::from("gift*")],
+        max_events: 4,
+        total: Some(String::from("/price")),
+        item: Some(String::from("/name")),
+        top: 2,
+        ..Default::default()
+    };
+    let digest = Digest::new(std::slice::from_ref(&config)).expect("invalid digest");
+    assert!(digest.rule("gift").is_some());
+    assert!(digest.rule("superchat").is_none());
+    assert!(Digest::default().rule("gift").is_none());
+
+    let gift = |occurred_at: i64, name: &str, price: f64| PublishExpanded {
+        meta: EventMeta {
+            occurred_at,
+            ..EventMeta::default()
+        },
+        vtuber: String::from("a"),
+        source: String::from("bililive"),
+        topic: String::from("gift"),
+        data: Arc::new(serde_json::json!({ "name": name, "price": price })),
+        tags: vec![String::from("hololive")],
+        replay: false,
+        text: None,
+        dead_letter: None,
+    };
+    let mut aggregate = Aggregate::new(&gift(20, "", 0.));
+    assert!(!aggregate.add(&config, &gift(20, "rocket", 100.)));
+    assert!(!aggregate.add(&config, &gift(10, "flower", 1.)));
+    assert!(!aggregate.add(&config, &gift(30, "flower", 1.5)));
+    assert!(aggregate.add(&config, &gift(40, "cat", 0.5)));
+
+    let event = aggregate.finish(&config);
+    assert_eq!(event.topic, DIGEST_TOPIC);
+    assert_eq!(event.vtuber, "a");
+    assert_eq!(event.source, "bililive");
+    assert_eq!(event.tags, vec![String::from("hololive")]);
+    let summary: Summary = serde_json::from_value(serde_json::to_value(&*event.data).unwrap())
+        .expect("unable to parse summary");
+    assert_eq!(
+        summary,
+        Summary {
+            topic: String::from("gift"),
+            since: 10,
+            until: 40,
+            count: 4,
+            total: Some(103.),
+            top: vec![
+                TopItem {
+                    item: String::from("flower"),
+                    count: 2
+                },
+                TopItem {
+                    item: String::from("cat"),
+                    count: 1
+                },
+            ],
+        }
+    );
+}
+
+#[actix::test]
+async fn must_render_templates() {
+    use super::template::{load_directory, StoredTemplate, Templates};
+    use crate::config::TemplatesConfig;
+
+    let dir = tempfile::tempdir().expect("unable to create temp dir");
+    std::fs::write(
+        dir.path().join("bililive.live.en.j2"),
+        "{{ vtuber.name }} is live: {{ payload.b }}",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.path().join("bililive.live.ja.j2"),
+        "{{ vtuber.name }}が配信中",
+    )
+    .unwrap();
+    std::fs::write(dir.path().join("README.md"), "not a template").unwrap();
+    let mut loaded = load_directory(dir.path())
+        .await
+        .expect("unable to load templates");
+    loaded.sort_by(|a, b| a.locale.cmp(&b.locale));
+    assert_eq!(loaded.len(), 2);
+    assert_eq!(loaded[0].topic, "bililive.live");
+    assert_eq!(loaded[0].locale, "en");
+
+    let templates = Templates::new(TemplatesConfig::default());
+    loaded.push(StoredTemplate {
+        topic: String::from("broken"),
+        locale: String::from("en"),
+        template: String::from("{{ oops"),
+    });
+    templates.replace(loaded);
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("singing"),
+    };
+    let event = PublishExpanded {
+        topic: String::from("bililive.live"),
+        ..test_event("a", &msg)
+    };
+    assert_eq!(
+        templates.render(&event, "ja").unwrap().as_deref(),
+        Some("aが配信中")
+    );
+    // fall back to the default locale
+    assert_eq!(
+        templates.render(&event, "zh").unwrap().as_deref(),
+        Some("a is live: singing")
+    );
+    assert_eq!(
+        templates.render(&test_event("a", &msg), "en").unwrap(),
+        None
+    );
+
+    // collectors get the rendered text in the envelope
+    let factory = CollectorFactoryWrapped::from(DebugCollectorFactory)
+        .templates(Arc::new(templates), Some(String::from("ja")));
+    let envelope = serde_json::to_value(Envelope::new(&factory.render(event))).unwrap();
+    assert_eq!(envelope["text"], "aが配信中");
+    let envelope =
+        serde_json::to_value(Envelope::new(&factory.render(test_event("a", &msg)))).unwrap();
+    assert!(envelope.get("text").is_none());
+}
+
+#[test]
+fn must_drain_lanes_by_priority() {
+    use super::priority::{Lanes, Priorities};
+    use super::QueuedEvent;
+    use crate::config::PrioritiesConfig;
+
+    let priorities = Priorities::new(&PrioritiesConfig {
+        high: vec![String::from("*.live")],
+        low: vec![String::from("*.danmaku")],
+        ..Default::default()
+    })
+    .expect("invalid priorities");
+    assert_eq!(priorities.classify("bililive.live"), Priority::High);
+    assert_eq!(priorities.classify("bililive.danmaku"), Priority::Low);
+    assert_eq!(priorities.classify("twitter"), Priority::Normal);
+
+    let msg = TestMsg {
+        a: 1,
+        b: String::from("test"),
+    };
+    let queued = |topic: &str| {
+        let event = PublishExpanded {
+            topic: String::from(topic),
+            ..test_event("a", &msg)
+        };
+        QueuedEvent {
+            priority: priorities.classify(&event.topic),
+            event,
+            outbox_id: None,
+            attempts: 0,
+        }
+    };
+    let mut lanes = Lanes::default();
+    for topic in ["b.danmaku", "b.danmaku", "twitter", "twitter", "twitter"] {
+        lanes.push_back(queued(topic));
+    }
+    lanes.push_back(queued("b.live"));
+    assert_eq!(lanes.len(), 6);
+
+    let drain = |lanes: &mut Lanes| lanes.pop_front(2).map(|event| event.event.topic);
+    // high-priority events go out first, then two normal ones for each low one
+    assert_eq!(drain(&mut lanes).as_deref(), Some("b.live"));
+    assert_eq!(drain(&mut lanes).as_deref(), Some("twitter"));
+    assert_eq!(drain(&mut lanes).as_deref(), Some("twitter"));
+    assert_eq!(drain(&mut lanes).as_deref(), Some("b.danmaku"));
+    assert_eq!(drain(&mut lanes).as_deref(), Some("twitter"));
+    assert_eq!(drain(&mut lanes).as_deref(), Some("b.danmaku"));
+    assert!(drain(&mut lanes).is_none());
+
+    // low-priority events are evicted first when the queue is full
+    lanes.push_back(queued("b.live"));
+    lanes.push_back(queued("b.danmaku"));
+    assert!(lanes.evict(Priority::Normal));
+    assert_eq!(lanes.len(), 1);
+    assert!(!lanes.evict(Priority::Normal));
+    assert!(lanes.evict(Priority::High));
+    assert!(lanes.is_empty());
+}
+
+#[actix::test]
+async fn must_flush_digests_on_drain() {
+    use super::digest::{Digest, DIGEST_TOPIC};
+    use super::Publish;
+    use crate::config::DigestConfig;
+    use crate::db::DBRef;
+    use crate::server::Drain;
+    use crate::tests::with_db;
+    use mongodb::bson::{doc, oid::ObjectId};
+
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        ArbiterContext::set(ArbiterContext::new(Uuid::new_v4()));
+        let id = ObjectId::new();
+        db.collection("vtubers")
+            .insert_one(doc! {"_id": id, "name": "a"}, None)
+            .await
+            .expect("unable to insert vtuber");
+        let root = DBRef {
+            collection: String::from("vtubers"),
+            id,
+            db: None,
+        };
+
+        let factory = RecordingFactory::new("a");
+        let received = factory.received.clone();
+        let digest = Digest::new(&[DigestConfig {
+            topics: vec![String::from("gift")],
+            window: Duration::from_secs(3600),
+            ..Default::default()
+        }])
+        .expect("invalid digest");
+        let addr = CollectorActor::new(
+            db.clone(),
+            vec![CollectorFactoryWrapped::from(factory).digest(digest)],
+        )
+        .start();
+        for _ in 0..2 {
+            addr.send(Publish::new(root.clone(), "bililive", "gift", 1))
+                .await
+                .expect("mailbox error");
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(
+            received.lock().is_empty(),
+            "digest emitted before window ends"
+        );
+
+        // the window is far from over, but the digest must not be lost on shutdown
+        addr.send(Drain).await.expect("mailbox error");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let received = received.lock();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].topic, DIGEST_TOPIC);
+        assert_eq!(received[0].vtuber, "a");
+    })
+    .await;
+}
@@ -1,4 +1,6 @@
+use std::collections::BTreeMap;
 use std::net::{IpAddr, Ipv4Addr};
+use std::ops::Deref;
 use std::path::Path;
 use std::time::Duration;
 
@@ -14,10 +16,25 @@ mod provider;
 mod tests;
 
 pub type ScheduleConfig = Schedule;
+pub type ScheduleOverrideConfig = ScheduleOverride;
+pub type CrashLoopConfig = CrashLoop;
 pub type HTTPConfig = HTTP;
 pub type MongoDBConfig = MongoDB;
 pub type AMQPConfig = AMQP;
+pub type ExchangeKindConfig = ExchangeKind;
+pub type EncodingConfig = Encoding;
 pub type TwitterConfig = Twitter;
+pub type FileCollectorConfig = FileCollector;
+pub type ArchiveConfig = Archive;
+pub type StreamConfig = Stream;
+pub type OutboxConfig = Outbox;
+pub type DedupConfig = Dedup;
+pub type FencingConfig = Fencing;
+pub type RetryPolicyConfig = RetryPolicy;
+pub type RouteConfig = Route;
+pub type DigestConfig = Digest;
+pub type TemplatesConfig = Templates;
+pub type PrioritiesConfig = Priorities;
 
 /// Contains all configuration to run the application.
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Hash, Default)]
@@ -30,11 +47,14 @@ pub struct Config {
     pub source: Source,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Hash, Default)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Hash, Default)]
 pub struct Basic {
     /// Workers on instance. Non-set or zero value set the value to count of available logical cpu cores.
     #[serde(deserialize_with = "to_maybe_non_zero")]
     pub workers: Option<usize>,
+    /// Labels of the instance, e.g. its region. Entries may require or prefer instances with certain labels.
+    #[serde(default)]
+    pub labels: Vec<String>,
 }
 
 fn to_maybe_non_zero<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
@@ -57,6 +77,12 @@ pub struct Schedule {
     /// Max allowed duration for an entry to be an orphan.
     #[serde(with = "humantime_serde")]
     pub max_interval: Duration,
+    /// Handling of tasks failing repeatedly.
+    #[serde(default)]
+    pub crash_loop: CrashLoop,
+    /// Where leases of entries are kept.
+    #[serde(default)]
+    pub lease_store: LeaseStoreKind,
 }
 
 impl Default for Schedule {
@@ -65,6 +91,96 @@ impl Default for Schedule {
             schedule_interval: Duration::from_secs(5),
             balance_interval: Duration::from_secs(30),
             max_interval: Duration::from_secs(60),
+            crash_loop: CrashLoop::default(),
+            lease_store: LeaseStoreKind::default(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaseStoreKind {
+    /// In the source collections, shared by all instances.
+    #[default]
+    Mongo,
+    /// In memory, for single-instance deployments.
+    ///
+    /// Entries are still read from mongodb, but leases, failures, health and task states are lost on restart.
+    Memory,
+}
+
+/// Cool-down and quarantine of entries whose tasks keep failing.
+///
+/// Failures are consecutive if the task fails within `max_interval` since it's acquired.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Hash)]
+#[serde(default)]
+pub struct CrashLoop {
+    /// Delay before an entry can be acquired again after a failure. Doubled on each consecutive failure.
+    #[serde(with = "humantime_serde")]
+    pub initial_cooldown: Duration,
+    /// Upper bound of the cool-down.
+    #[serde(with = "humantime_serde")]
+    pub max_cooldown: Duration,
+    /// Consecutive failures before an entry is quarantined until cleared manually. Zero disables quarantine.
+    ///
+    /// Only a permanent failure, e.g. a bad uid, puts an entry into quarantine. Others just cool it down.
+    pub quarantine_after: u32,
+}
+
+impl Default for CrashLoop {
+    fn default() -> Self {
+        Self {
+            initial_cooldown: Duration::from_secs(30),
+            max_cooldown: Duration::from_secs(30 * 60),
+            quarantine_after: 10,
+        }
+    }
+}
+
+/// Schedule parameters of a source. Unset ones fall back to the global `[schedule]`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Hash, Default)]
+#[serde(default)]
+pub struct ScheduleOverride {
+    #[serde(with = "humantime_serde")]
+    pub schedule_interval: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub balance_interval: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub max_interval: Option<Duration>,
+    pub crash_loop: CrashLoopOverride,
+}
+
+impl ScheduleOverride {
+    /// Apply the overrides on given schedule config.
+    pub fn apply(&self, base: Schedule) -> Schedule {
+        Schedule {
+            schedule_interval: self.schedule_interval.unwrap_or(base.schedule_interval),
+            balance_interval: self.balance_interval.unwrap_or(base.balance_interval),
+            max_interval: self.max_interval.unwrap_or(base.max_interval),
+            crash_loop: self.crash_loop.apply(base.crash_loop),
+            lease_store: base.lease_store,
+        }
+    }
+}
+
+/// Crash-loop parameters of a source. Unset ones fall back to the global `[schedule.crash_loop]`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Hash, Default)]
+#[serde(default)]
+pub struct CrashLoopOverride {
+    #[serde(with = "humantime_serde")]
+    pub initial_cooldown: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub max_cooldown: Option<Duration>,
+    pub quarantine_after: Option<u32>,
+}
+
+impl CrashLoopOverride {
+    /// Apply the overrides on given crash-loop config.
+    pub fn apply(&self, base: CrashLoop) -> CrashLoop {
+        CrashLoop {
+            initial_cooldown: self.initial_cooldown.unwrap_or(base.initial_cooldown),
+            max_cooldown: self.max_cooldown.unwrap_or(base.max_cooldown),
+            quarantine_after: self.quarantine_after.unwrap_or(base.quarantine_after),
         }
     }
 }
@@ -114,13 +230,98 @@ impl Default for MongoDB {
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Hash, Default)]
 pub struct Collector {
-    pub amqp: AMQP,
+    pub amqp: AMQPCollectors,
     pub debug: DebugCollector,
+    pub file: FileCollector,
+    pub archive: Archive,
+    pub stream: Stream,
+    pub outbox: Outbox,
+    pub dedup: Dedup,
+    pub fencing: Fencing,
+    pub templates: Templates,
+    pub priorities: Priorities,
+    /// Default retry policy of collectors.
+    pub retry: RetryPolicy,
+    /// Retry policies overriding the default one, keyed by collector kind (e.g. `amqp`, `file`) or named instance
+    /// (e.g. `amqp.archive`).
+    pub retry_overrides: BTreeMap<String, RetryPolicy>,
+    /// Routing rules of collectors, keyed by collector kind or named instance. Collectors without rules receive all
+    /// events.
+    pub routes: BTreeMap<String, Route>,
+    /// Digest rules of collectors, keyed by collector kind or named instance. Matching events are summarized instead
+    /// of sent.
+    pub digests: BTreeMap<String, Vec<Digest>>,
+    /// Locales of messages rendered from templates, keyed by collector kind or named instance. Collectors without a
+    /// locale use the default locale of templates.
+    pub locales: BTreeMap<String, String>,
+}
+
+impl Collector {
+    /// Get the routing rules of given collector, keyed by its kind or instance, e.g. `amqp` or `amqp.archive`.
+    pub fn route(&self, key: &str) -> Route {
+        lookup(&self.routes, key).cloned().unwrap_or_default()
+    }
+
+    /// Get the digest rules of given collector, keyed by its kind or instance.
+    pub fn digest(&self, key: &str) -> Vec<Digest> {
+        lookup(&self.digests, key).cloned().unwrap_or_default()
+    }
+
+    /// Get the retry policy of given collector, keyed by its kind or instance.
+    pub fn retry_policy(&self, key: &str) -> RetryPolicy {
+        lookup(&self.retry_overrides, key)
+            .copied()
+            .unwrap_or(self.retry)
+    }
+
+    /// Get the locale of messages rendered for given collector, keyed by its kind or instance.
+    pub fn locale(&self, key: &str) -> Option<String> {
+        lookup(&self.locales, key).cloned()
+    }
+
+    /// Reject AMQP instances which can't be told apart.
+    fn validate(&self) -> Result<(), String> {
+        let mut instances = Vec::new();
+        let mut names = Vec::new();
+        for amqp in self.amqp.iter() {
+            if let AMQP::Enabled {
+                uri,
+                exchange,
+                name,
+                ..
+            } = amqp
+            {
+                if instances.contains(&(uri, exchange)) {
+                    return Err(format!(
+                        "duplicated amqp collector: uri={}, exchange={}",
+                        uri, exchange
+                    ));
+                }
+                instances.push((uri, exchange));
+                if let Some(name) = name {
+                    if names.contains(&name) {
+                        return Err(format!("duplicated amqp collector name: {}", name));
+                    }
+                    names.push(name);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Look up settings of a collector instance, e.g. `amqp.archive`, falling back to the ones of its kind.
+fn lookup<'a, T>(map: &'a BTreeMap<String, T>, key: &str) -> Option<&'a T> {
+    map.get(key)
+        .or_else(|| key.split_once('.').and_then(|(kind, _)| map.get(kind)))
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Twitter {
-    Enabled { token: String },
+    Enabled {
+        token: String,
+        schedule: ScheduleOverride,
+    },
     Disabled,
 }
 
@@ -131,19 +332,26 @@ impl Default for Twitter {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Hash)]
+#[serde(default)]
 pub struct Bililive {
     pub enabled: bool,
+    pub schedule: ScheduleOverride,
 }
 
 impl Default for Bililive {
     fn default() -> Self {
-        Self { enabled: true }
+        Self {
+            enabled: true,
+            schedule: ScheduleOverride::default(),
+        }
     }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Hash, Default)]
+#[serde(default)]
 pub struct DebugSource {
     pub enabled: bool,
+    pub schedule: ScheduleOverride,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Hash, Default)]
@@ -153,6 +361,26 @@ pub struct Source {
     pub debug: DebugSource,
 }
 
+/// AMQP collector instances.
+///
+/// Accepts either a single instance or a list of instances.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct AMQPCollectors(pub Vec<AMQP>);
+
+impl Default for AMQPCollectors {
+    fn default() -> Self {
+        Self(vec![AMQP::default()])
+    }
+}
+
+impl Deref for AMQPCollectors {
+    type Target = [AMQP];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
 // TODO workaround before https://github.com/serde-rs/serde/pull/2056 is merged
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 // #[serde(tag = "enabled")]
@@ -160,23 +388,371 @@ pub enum AMQP {
     // #[serde(rename = false)]
     Disabled,
     // #[serde(rename = true)]
-    Enabled { uri: String, exchange: String },
+    Enabled {
+        /// Name of the instance. Settings keyed by `amqp.<name>` override the ones keyed by `amqp`.
+        name: Option<String>,
+        uri: String,
+        exchange: String,
+        /// Kind of the exchange to declare. Defaults to `topic`.
+        exchange_kind: ExchangeKind,
+        /// Routing key template. `{vtuber}`, `{source}` and `{topic}` will be substituted.
+        routing_key: String,
+        /// Encoding of message bodies.
+        encoding: Encoding,
+    },
 }
 
+pub const DEFAULT_ROUTING_KEY: &str = "{vtuber}.{topic}";
+
 impl Default for AMQP {
     fn default() -> Self {
         Self::Enabled {
+            name: None,
             uri: String::from("amqp://127.0.0.1"),
             exchange: String::from("stargazer"),
+            exchange_kind: ExchangeKind::default(),
+            routing_key: String::from(DEFAULT_ROUTING_KEY),
+            encoding: Encoding::default(),
         }
     }
 }
 
+/// How events are encoded by a collector.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Encoding {
+    pub format: PayloadFormat,
+    pub compression: Compression,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExchangeKind {
+    Direct,
+    Fanout,
+    Headers,
+    #[default]
+    Topic,
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
 pub struct DebugCollector {
     pub enabled: bool,
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileCollector {
+    pub enabled: bool,
+    /// Path template of output files. `{date}`, `{vtuber}` and `{topic}` will be substituted.
+    pub path: String,
+    /// Rotate the file when it grows beyond given size in bytes.
+    pub max_size: Option<u64>,
+    /// Rotate the file when it has been written for longer than given duration.
+    #[serde(with = "humantime_serde")]
+    pub max_age: Option<Duration>,
+    /// Compress rotated files with gzip.
+    pub compress: bool,
+    /// When to flush written events to disk.
+    pub fsync: FsyncPolicy,
+    /// Interval between syncs if `fsync` is set to `periodic`.
+    #[serde(with = "humantime_serde")]
+    pub fsync_interval: Duration,
+    /// Encoding of written events. Json events are written one per line, and each event is compressed on its own.
+    pub encoding: Encoding,
+}
+
+impl Default for FileCollector {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: String::from("events/{date}/{vtuber}.jsonl"),
+            max_size: None,
+            max_age: None,
+            compress: false,
+            fsync: FsyncPolicy::default(),
+            fsync_interval: Duration::from_secs(1),
+            encoding: Encoding::default(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// Sync after every written event.
+    Always,
+    /// Sync periodically.
+    #[default]
+    Periodic,
+    /// Leave it to the operating system.
+    Never,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Archive {
+    pub enabled: bool,
+    /// How long archived events are kept.
+    #[serde(with = "humantime_serde")]
+    pub ttl: Duration,
+}
+
+impl Default for Archive {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Stream {
+    pub enabled: bool,
+    /// Count of recent events kept for `Last-Event-ID` resumption.
+    pub replay_buffer: usize,
+    /// Count of events a client may fall behind before it's dropped.
+    pub client_buffer: usize,
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            replay_buffer: 1024,
+            client_buffer: 256,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Dedup {
+    /// Drop events whose idempotency key has been seen recently, on any instance.
+    pub enabled: bool,
+    /// How long idempotency keys are remembered.
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+}
+
+impl Default for Dedup {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Fencing {
+    /// Reject events emitted by stale owners of entries, i.e. ones with a lease epoch older than the latest seen.
+    pub enabled: bool,
+    /// How long an admitted epoch of an entry is trusted before it's checked against mongodb again.
+    ///
+    /// Events of a stale owner may be admitted within this period after a new owner takes over.
+    #[serde(with = "humantime_serde")]
+    pub cache_ttl: Duration,
+}
+
+impl Default for Fencing {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            cache_ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Priority classes of topics in collector queues.
+///
+/// Topics matching neither `high` nor `low` are of normal priority.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Priorities {
+    /// Glob patterns of high-priority topics. They are always sent first.
+    pub high: Vec<String>,
+    /// Glob patterns of low-priority topics.
+    pub low: Vec<String>,
+    /// Normal-priority events sent for each low-priority one when both are queued.
+    pub normal_weight: u32,
+    /// Percentage of low-priority events kept when the queue is under pressure. Zero drops all of them.
+    pub low_sample_percent: u32,
+    /// Queue length from which low-priority events are sampled.
+    pub pressure_threshold: usize,
+}
+
+impl Default for Priorities {
+    fn default() -> Self {
+        Self {
+            high: Vec::new(),
+            low: Vec::new(),
+            normal_weight: 4,
+            low_sample_percent: 100,
+            pressure_threshold: 512,
+        }
+    }
+}
+
+/// Message templates rendering human-readable text of events for collectors.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Templates {
+    /// Load templates from files named `<topic>.<locale>.j2` in this directory.
+    pub directory: Option<String>,
+    /// Load templates from mongodb. They take precedence over ones in the directory.
+    pub mongodb: bool,
+    /// Interval between template reloads.
+    #[serde(with = "humantime_serde")]
+    pub reload_interval: Duration,
+    /// Locale used when a template isn't available in the requested one.
+    pub default_locale: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            mongodb: false,
+            reload_interval: Duration::from_secs(30),
+            default_locale: String::from("en"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Outbox {
+    /// Persist queued events in mongodb, so that they survive broker outages and restarts.
+    pub enabled: bool,
+    /// Events whose owner hasn't been seen for this long are claimed by other instances.
+    #[serde(with = "humantime_serde")]
+    pub lease: Duration,
+    /// Stable name of this instance, unique in the cluster.
+    /// Events left by a previous run under the same name are reclaimed on startup instead of after `lease`.
+    pub instance: Option<String>,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lease: Duration::from_secs(60),
+            instance: None,
+        }
+    }
+}
+
+/// Decides which events a collector receives.
+///
+/// An event is accepted if it matches every non-empty condition.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Route {
+    /// Glob patterns of accepted topics.
+    pub topics: Vec<String>,
+    /// Names of accepted vtubers.
+    pub vtubers: Vec<String>,
+    /// Accept vtubers with any of these tags.
+    pub tags: Vec<String>,
+}
+
+/// Aggregates events of a vtuber and topic into periodic `digest` events.
+///
+/// A digest is emitted when the window elapses or enough events are collected, whichever comes first.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Digest {
+    /// Glob patterns of summarized topics.
+    pub topics: Vec<String>,
+    /// How long events are collected before a digest is emitted.
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    /// Emit the digest early when this many events are collected. Zero means no limit.
+    pub max_events: u64,
+    /// JSON pointer into payloads of the numeric value to be summed, e.g. `/data/price`.
+    pub total: Option<String>,
+    /// JSON pointer into payloads of the item to be ranked, e.g. `/data/giftName`.
+    pub item: Option<String>,
+    /// Count of most frequent items included in the digest.
+    pub top: usize,
+}
+
+impl Default for Digest {
+    fn default() -> Self {
+        Self {
+            topics: Vec::new(),
+            window: Duration::from_secs(5 * 60),
+            max_events: 0,
+            total: None,
+            item: None,
+            top: 5,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Delay before the first retry.
+    #[serde(with = "humantime_serde")]
+    pub initial_backoff: Duration,
+    /// Upper bound of delay between retries.
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+    /// Factor the delay grows by after each failure.
+    pub multiplier: u32,
+    /// Randomize delays to avoid retrying in lockstep.
+    pub jitter: bool,
+    /// Rejections before an event is moved to the dead-letter store. Zero means retrying forever.
+    ///
+    /// Only failures caused by the event itself, e.g. nacks, count. Events are kept while the collector is unavailable.
+    pub max_attempts: u32,
+    /// Consecutive failures before the circuit breaker opens. Zero disables the breaker.
+    ///
+    /// Failed attempts while the breaker is open don't count towards `max_attempts`,
+    /// so this should be greater than `max_attempts` to let poison events be dead-lettered.
+    pub breaker_threshold: u32,
+    /// Delay between attempts while the circuit breaker is open.
+    #[serde(with = "humantime_serde")]
+    pub breaker_cooldown: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+            multiplier: 2,
+            jitter: true,
+            max_attempts: 5,
+            breaker_threshold: 10,
+            breaker_cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
 impl Config {
     /// Construct a new [`Config`](Config).
     ///
@@ -211,7 +787,9 @@ impl Config {
             )
             .merge(Env::prefixed("STARGAZER_").split("_"));
 
-        config.extract()
+        let config: Self = config.extract()?;
+        config.collector.validate().map_err(Error::from)?;
+        Ok(config)
     }
 }
 
@@ -281,6 +859,37 @@ impl<'de> Deserialize<'de> for HTTP {
     }
 }
 
+impl Serialize for AMQPCollectors {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        // A single instance is kept as a table so that it can be overwritten field by field.
+        match self.0.as_slice() {
+            [amqp] => amqp.serialize(serializer),
+            instances => instances.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AMQPCollectors {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(AMQP),
+            Many(Vec<AMQP>),
+        }
+        Ok(Self(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(amqp) => vec![amqp],
+            OneOrMany::Many(instances) => instances,
+        }))
+    }
+}
+
 impl Serialize for AMQP {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -290,7 +899,15 @@ impl Serialize for AMQP {
         #[serde(untagged)]
         enum Body {
             Disabled,
-            Enabled { uri: String, exchange: String },
+            Enabled {
+                #[serde(skip_serializing_if = "Option::is_none")]
+                name: Option<String>,
+                uri: String,
+                exchange: String,
+                exchange_kind: ExchangeKind,
+                routing_key: String,
+                encoding: Encoding,
+            },
         }
         #[derive(Serialize)]
         struct Tagged {
@@ -303,11 +920,22 @@ impl Serialize for AMQP {
                 enabled: false,
                 body: Body::Disabled,
             },
-            AMQP::Enabled { uri, exchange } => Tagged {
+            AMQP::Enabled {
+                name,
+                uri,
+                exchange,
+                exchange_kind,
+                routing_key,
+                encoding,
+            } => Tagged {
                 enabled: true,
                 body: Body::Enabled {
+                    name: name.clone(),
                     uri: uri.clone(),
                     exchange: exchange.clone(),
+                    exchange_kind: *exchange_kind,
+                    routing_key: routing_key.clone(),
+                    encoding: *encoding,
                 },
             },
         }
@@ -330,6 +958,11 @@ impl<'de> Deserialize<'de> for AMQP {
 
         Ok(if enabled {
             Self::Enabled {
+                name: value
+                    .get("name")
+                    .map(Deserialize::deserialize)
+                    .transpose()
+                    .map_err(de::Error::custom)?,
                 uri: value
                     .get("uri")
                     .ok_or_else(|| de::Error::missing_field("uri"))
@@ -340,6 +973,24 @@ impl<'de> Deserialize<'de> for AMQP {
                     .ok_or_else(|| de::Error::missing_field("exchange"))
                     .map(Deserialize::deserialize)?
                     .map_err(de::Error::custom)?,
+                exchange_kind: value
+                    .get("exchange_kind")
+                    .map(Deserialize::deserialize)
+                    .transpose()
+                    .map_err(de::Error::custom)?
+                    .unwrap_or_default(),
+                routing_key: value
+                    .get("routing_key")
+                    .map(Deserialize::deserialize)
+                    .transpose()
+                    .map_err(de::Error::custom)?
+                    .unwrap_or_else(|| String::from(DEFAULT_ROUTING_KEY)),
+                encoding: value
+                    .get("encoding")
+                    .map(Deserialize::deserialize)
+                    .transpose()
+                    .map_err(de::Error::custom)?
+                    .unwrap_or_default(),
             }
         } else {
             Self::Disabled
@@ -356,7 +1007,10 @@ impl Serialize for Twitter {
         #[serde(untagged)]
         enum Body {
             Disabled,
-            Enabled { token: String },
+            Enabled {
+                token: String,
+                schedule: ScheduleOverride,
+            },
         }
         #[derive(Serialize)]
         struct Tagged {
@@ -369,10 +1023,11 @@ impl Serialize for Twitter {
                 enabled: false,
                 body: Body::Disabled,
             },
-            Twitter::Enabled { token } => Tagged {
+            Twitter::Enabled { token, schedule } => Tagged {
                 enabled: true,
                 body: Body::Enabled {
                     token: token.clone(),
+                    schedule: *schedule,
                 },
             },
         }
@@ -400,6 +1055,12 @@ impl<'de> Deserialize<'de> for Twitter {
                     .ok_or_else(|| de::Error::missing_field("token"))
                     .map(Deserialize::deserialize)?
                     .map_err(de::Error::custom)?,
+                schedule: value
+                    .get("schedule")
+                    .map(Deserialize::deserialize)
+                    .transpose()
+                    .map_err(de::Error::custom)?
+                    .unwrap_or_default(),
             }
         } else {
             Self::Disabled
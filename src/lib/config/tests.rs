@@ -1,6 +1,11 @@
 use figment::Jail;
 
-use super::{Config, HTTP};
+use std::time::Duration;
+
+use super::{
+    Compression, Config, CrashLoop, Encoding, ExchangeKind, PayloadFormat, Schedule, Twitter, AMQP,
+    DEFAULT_ROUTING_KEY, HTTP,
+};
 
 #[test]
 fn must_load_specified() {
@@ -24,3 +29,178 @@ fn must_env_overwrite() {
         Ok(())
     });
 }
+
+#[test]
+// the error type is dictated by `Jail`
+#[allow(clippy::result_large_err)]
+fn must_load_amqp_list() {
+    Jail::expect_with(|jail| {
+        jail.create_file("config.toml", include_str!("../../../tests/config.toml"))?;
+        let config = Config::new(Some("config.toml".as_ref()))?;
+        assert_eq!(config.collector.amqp.len(), 1);
+
+        jail.create_file(
+            "config.toml",
+            r#"
+            [[collector.amqp]]
+            enabled = true
+            uri = "amqp://127.0.0.1"
+            exchange = "stargazer"
+
+            [[collector.amqp]]
+            enabled = true
+            name = "archive"
+            uri = "amqp://10.0.0.1"
+            exchange = "archive"
+            exchange_kind = "fanout"
+            encoding = { format = "msgpack", compression = "zstd" }
+
+            [collector.retry_overrides.amqp]
+            max_attempts = 3
+
+            [collector.retry_overrides."amqp.archive"]
+            max_attempts = 10
+            "#,
+        )?;
+        let config = Config::new(Some("config.toml".as_ref()))?;
+        assert_eq!(
+            config.collector.amqp.0,
+            vec![
+                AMQP::Enabled {
+                    name: None,
+                    uri: String::from("amqp://127.0.0.1"),
+                    exchange: String::from("stargazer"),
+                    exchange_kind: ExchangeKind::Topic,
+                    routing_key: String::from(DEFAULT_ROUTING_KEY),
+                    encoding: Encoding::default(),
+                },
+                AMQP::Enabled {
+                    name: Some(String::from("archive")),
+                    uri: String::from("amqp://10.0.0.1"),
+                    exchange: String::from("archive"),
+                    exchange_kind: ExchangeKind::Fanout,
+                    routing_key: String::from(DEFAULT_ROUTING_KEY),
+                    encoding: Encoding {
+                        format: PayloadFormat::MsgPack,
+                        compression: Compression::Zstd,
+                    },
+                },
+            ]
+        );
+        // named instances have settings of their own, and fall back to the ones of their kind
+        assert_eq!(config.collector.retry_policy("amqp").max_attempts, 3);
+        assert_eq!(
+            config.collector.retry_policy("amqp.archive").max_attempts,
+            10
+        );
+        assert_eq!(config.collector.retry_policy("amqp.other").max_attempts, 3);
+        Ok(())
+    });
+}
+
+#[test]
+// the error type is dictated by `Jail`
+#[allow(clippy::result_large_err)]
+fn must_reject_duplicated_amqp() {
+    Jail::expect_with(|jail| {
+        let instance = r#"
+            [[collector.amqp]]
+            enabled = true
+            name = "a"
+            uri = "amqp://127.0.0.1"
+            exchange = "stargazer"
+        "#;
+        jail.create_file("config.toml", &instance.repeat(2))?;
+        assert!(Config::new(Some("config.toml".as_ref())).is_err());
+
+        jail.create_file(
+            "config.toml",
+            &format!("{}{}", instance, instance.replace("stargazer", "archive")),
+        )?;
+        assert!(
+            Config::new(Some("config.toml".as_ref())).is_err(),
+            "duplicated name accepted"
+        );
+
+        jail.create_file(
+            "config.toml",
+            &format!(
+                "{}{}",
+                instance,
+                instance
+                    .replace("stargazer", "archive")
+                    .replace("\"a\"", "\"b\"")
+            ),
+        )?;
+        assert_eq!(
+            Config::new(Some("config.toml".as_ref()))?
+                .collector
+                .amqp
+                .len(),
+            2
+        );
+        Ok(())
+    });
+}
+
+#[test]
+// the error type is dictated by `Jail`
+#[allow(clippy::result_large_err)]
+fn must_override_source_schedule() {
+    Jail::expect_with(|jail| {
+        jail.create_file("config.toml", include_str!("../../../tests/config.toml"))?;
+        let config = Config::new(Some("config.toml".as_ref()))?;
+        assert_eq!(
+            config.source.bililive.schedule.apply(config.schedule),
+            Schedule {
+                schedule_interval: Duration::from_secs(2),
+                balance_interval: Duration::from_secs(30),
+                max_interval: Duration::from_secs(15),
+                ..config.schedule
+            }
+        );
+        assert_eq!(
+            config.source.debug.schedule.apply(config.schedule),
+            config.schedule
+        );
+
+        jail.create_file(
+            "config.toml",
+            r#"
+            [schedule]
+            max_interval = "2m"
+
+            [source.twitter]
+            enabled = true
+            token = "token"
+
+            [source.twitter.schedule]
+            schedule_interval = "1m"
+            max_interval = "10m"
+
+            [source.twitter.schedule.crash_loop]
+            quarantine_after = 3
+            "#,
+        )?;
+        let config = Config::new(Some("config.toml".as_ref()))?;
+        assert_eq!(config.schedule.max_interval, Duration::from_secs(120));
+        let schedule = match config.source.twitter {
+            Twitter::Enabled { schedule, .. } => schedule.apply(config.schedule),
+            Twitter::Disabled => panic!("twitter must be enabled"),
+        };
+        assert_eq!(
+            schedule,
+            Schedule {
+                schedule_interval: Duration::from_secs(60),
+                balance_interval: Duration::from_secs(30),
+                max_interval: Duration::from_secs(600),
+                crash_loop: CrashLoop {
+                    quarantine_after: 3,
+                    ..config.schedule.crash_loop
+                },
+                ..config.schedule
+            }
+        );
+        Ok(())
+    });
+}
@@ -6,13 +6,14 @@ use async_trait::async_trait;
 use mongodb::bson::oid::ObjectId;
 pub use mongodb::bson::{doc, Document};
 pub use mongodb::error::Result as DBResult;
+use mongodb::options::IndexOptions;
 pub use mongodb::Collection;
-use mongodb::{Client, Database};
+use mongodb::{Client, Database, IndexModel};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tracing::{trace, warn};
 
-use crate::utils::{CancelOnDrop, CustomGuard};
+use crate::utils::{CancelOnDrop, CustomGuard, DBErrorExt};
 
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Hash)]
 pub struct DBRef {
@@ -171,3 +172,48 @@ pub async fn connect_db(db_uri: &str, db_name: &str) -> DBResult<Database> {
         .await
         .map(|client| client.database(db_name))
 }
+
+/// Error code raised when an index with the same keys but different options exists.
+const INDEX_OPTIONS_CONFLICT: i32 = 85;
+
+/// Create a TTL index on given keys.
+///
+/// If the index exists with another expiry, e.g. the window is changed in config, it's modified in place.
+///
+/// # Errors
+/// Pass errors raised by mongodb driver.
+pub async fn create_ttl_index<T>(
+    db: &Database,
+    collection: &Collection<T>,
+    keys: Document,
+    ttl: Duration,
+) -> DBResult<()> {
+    let index = IndexModel::builder()
+        .keys(keys.clone())
+        .options(IndexOptions::builder().expire_after(ttl).build())
+        .build();
+    match collection.create_index(index, None).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.command() == Some(INDEX_OPTIONS_CONFLICT) => {
+            warn!(
+                "updating expiry of index {:?} on {} to {:?}",
+                keys,
+                collection.name(),
+                ttl
+            );
+            db.run_command(
+                doc! {
+                    "collMod": collection.name(),
+                    "index": {
+                        "keyPattern": keys,
+                        "expireAfterSeconds": i64::try_from(ttl.as_secs()).unwrap_or(i64::MAX),
+                    },
+                },
+                None,
+            )
+            .await
+            .map(|_| ())
+        }
+        Err(e) => Err(e),
+    }
+}
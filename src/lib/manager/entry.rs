@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use actix_web::web::{Data, Json, Path};
 use actix_web::HttpResponse;
 use erased_serde::Serialize;
@@ -5,21 +7,28 @@ use frunk_core::hlist::HMappable;
 use frunk_core::traits::Poly;
 use futures::future;
 use hmap_serde::HLabelledMap;
+use mongodb::bson::oid::ObjectId;
 use mongodb::{Collection, Database};
 use serde::de::DeserializeOwned;
 
-use crate::db::{CollOperation, DBOperation};
+use crate::db::{CollOperation, DBOperation, Document};
 use crate::manager::errors::CrudError;
-use crate::manager::ops::{CreateVtuberOp, DeleteVtuberOp, GetVtuberOp};
+use crate::manager::models::{
+    EntryHealth, FailedEntry, QuarantinedEntry, ReportedHealth, SourceKeys, VtuberFields,
+};
+use crate::manager::ops::{
+    ClearQuarantinesOp, CreateVtuberOp, DeleteVtuberOp, GetVtuberOp, GetVtubersByIdOp,
+    ListHealthOp, ListQuarantinedOp, SetTagsOp,
+};
 use crate::manager::utils::{IntoDisplay, OptionLiftF, ToOptionHList};
-use crate::manager::Vtuber;
+use crate::manager::{Vtuber, RESERVED_NAMES};
 use crate::utils::BoolExt;
 
 pub async fn get<L, LO, LD>(
     name: Path<String>,
     coll: Data<Collection<Vtuber>>,
     db: Data<Database>,
-) -> Result<Json<HLabelledMap<LD>>, CrudError>
+) -> Result<Json<VtuberFields<HLabelledMap<LD>>>, CrudError>
 where
     L: ToOptionHList<OptionHList = LO>,
     LO: HMappable<Poly<OptionLiftF<IntoDisplay>>, Output = LD>,
@@ -34,7 +43,10 @@ where
     .ok_or(CrudError::MissingVtuber)?;
     let flatten = vtuber.flatten::<L::OptionHList>(&*db.into_inner()).await?;
     let wrapped = flatten.fields.0.map(Poly(OptionLiftF(IntoDisplay)));
-    Ok(Json(HLabelledMap(wrapped)))
+    Ok(Json(VtuberFields {
+        fields: HLabelledMap(wrapped),
+        health: flatten.health,
+    }))
 }
 
 pub async fn delete(
@@ -79,6 +91,9 @@ pub async fn create(
     name: String,
     coll: Data<Collection<Vtuber>>,
 ) -> Result<HttpResponse, CrudError> {
+    if RESERVED_NAMES.contains(&name.as_str()) {
+        return Err(CrudError::ReservedName(name));
+    }
     Ok(
         if (CreateVtuberOp { name })
             .execute(&*coll.into_inner())
@@ -90,3 +105,110 @@ pub async fn create(
         },
     )
 }
+
+pub async fn get_tags(
+    name: Path<String>,
+    coll: Data<Collection<Vtuber>>,
+) -> Result<Json<Vec<String>>, CrudError> {
+    let vtuber = GetVtuberOp {
+        name: name.into_inner(),
+    }
+    .execute(&*coll.into_inner())
+    .await?
+    .ok_or(CrudError::MissingVtuber)?;
+    Ok(Json(vtuber.tags))
+}
+
+pub async fn set_tags(
+    name: Path<String>,
+    tags: Json<Vec<String>>,
+    coll: Data<Collection<Vtuber>>,
+) -> Result<HttpResponse, CrudError> {
+    SetTagsOp {
+        name: name.into_inner(),
+        tags: tags.into_inner(),
+    }
+    .execute(&*coll.into_inner())
+    .await?
+    .true_or(CrudError::MissingVtuber)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn quarantined(
+    keys: Data<SourceKeys>,
+    coll: Data<Collection<Vtuber>>,
+    db: Data<Database>,
+) -> Result<Json<Vec<QuarantinedEntry>>, CrudError> {
+    let mut entries: Vec<(&'static str, FailedEntry)> = Vec::new();
+    for key in &keys.0 {
+        let found = ListQuarantinedOp
+            .execute(&db.collection::<FailedEntry>(key))
+            .await?;
+        entries.extend(found.into_iter().map(|entry| (*key, entry)));
+    }
+
+    let vtubers = vtuber_names(&coll, entries.iter().map(|(_, entry)| entry.root.id)).await?;
+    Ok(Json(
+        entries
+            .into_iter()
+            .map(|(source, entry)| QuarantinedEntry {
+                vtuber: vtubers.get(&entry.root.id).cloned(),
+                source,
+                failures: entry.failures,
+                last_error: entry.last_error,
+            })
+            .collect(),
+    ))
+}
+
+/// Lift all quarantines, e.g. after an outage of the upstream.
+pub async fn clear_quarantined(
+    keys: Data<SourceKeys>,
+    db: Data<Database>,
+) -> Result<HttpResponse, CrudError> {
+    for key in &keys.0 {
+        ClearQuarantinesOp
+            .execute(&db.collection::<Document>(key))
+            .await?;
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn health(
+    keys: Data<SourceKeys>,
+    coll: Data<Collection<Vtuber>>,
+    db: Data<Database>,
+) -> Result<Json<Vec<EntryHealth>>, CrudError> {
+    let mut entries: Vec<(&'static str, ReportedHealth)> = Vec::new();
+    for key in &keys.0 {
+        let found = ListHealthOp
+            .execute(&db.collection::<ReportedHealth>(key))
+            .await?;
+        entries.extend(found.into_iter().map(|entry| (*key, entry)));
+    }
+
+    let vtubers = vtuber_names(&coll, entries.iter().map(|(_, entry)| entry.root.id)).await?;
+    Ok(Json(
+        entries
+            .into_iter()
+            .map(|(source, entry)| EntryHealth {
+                vtuber: vtubers.get(&entry.root.id).cloned(),
+                source,
+                health: entry.health,
+            })
+            .collect(),
+    ))
+}
+
+/// Names of vtubers with given ids.
+async fn vtuber_names(
+    coll: &Collection<Vtuber>,
+    ids: impl Iterator<Item = ObjectId>,
+) -> Result<HashMap<ObjectId, String>, CrudError> {
+    Ok(GetVtubersByIdOp { ids: ids.collect() }
+        .execute(coll)
+        .await?
+        .into_iter()
+        .map(|vtuber| (vtuber.doc_id, vtuber.name))
+        .collect())
+}
@@ -12,6 +12,8 @@ pub enum CrudError {
     MissingVtuber,
     #[error("missing field")]
     MissingField,
+    #[error("reserved name: {0}")]
+    ReservedName(String),
     #[error("invalid value: {value} - {source}")]
     InvalidValue {
         value: String,
@@ -26,7 +28,7 @@ impl ResponseError for CrudError {
         match self {
             CrudError::MissingVtuber | CrudError::MissingField => StatusCode::NOT_FOUND,
             CrudError::DBError(_) | CrudError::Inconsistency => StatusCode::INTERNAL_SERVER_ERROR,
-            CrudError::InvalidValue { .. } => StatusCode::BAD_REQUEST,
+            CrudError::InvalidValue { .. } | CrudError::ReservedName(_) => StatusCode::BAD_REQUEST,
         }
     }
 }
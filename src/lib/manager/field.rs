@@ -1,11 +1,12 @@
-use actix_web::web::{Data, Path};
+use actix_web::web::{Data, Json, Path};
 use actix_web::{web, HttpResponse, Scope};
 use frunk_core::traits::Func;
 use hmap_serde::Labelled;
 use mongodb::{Collection, Database};
 
-use crate::db::{CollOperation, DBOperation, DBRef};
-use crate::manager::ops::{CreateFieldOp, LinkRefOp};
+use crate::db::{CollOperation, DBOperation, DBRef, Document};
+use crate::manager::ops::{ClearQuarantineOp, CreateFieldOp, LinkRefOp, SetPausedOp};
+use crate::scheduler::pause::PAUSED_FIELD;
 use crate::scheduler::{Entry, Task};
 use crate::utils::{BoolExt, FromStrE};
 
@@ -64,7 +65,11 @@ async fn put<T: Task>(
         id: vtuber.doc_id,
         db: None,
     };
-    let payload = Entry { root, data };
+    let payload = Entry {
+        root,
+        health: None,
+        data,
+    };
 
     let db_ref = vtuber.fields.get(T::Entry::KEY);
     if let Some(db_ref) = db_ref {
@@ -117,6 +122,95 @@ async fn delete<T: Task>(
     Ok(HttpResponse::NoContent().finish())
 }
 
+async fn get_paused<T: Task>(
+    name: Path<String>,
+    coll: Data<Collection<Vtuber>>,
+    db: Data<Database>,
+) -> Result<Json<bool>, CrudError> {
+    let vtuber = GetVtuberOp {
+        name: name.into_inner(),
+    }
+    .execute(&*coll.into_inner())
+    .await?
+    .ok_or(CrudError::MissingVtuber)?;
+    let db_ref = vtuber
+        .fields
+        .get(T::Entry::KEY)
+        .ok_or(CrudError::MissingField)?;
+    let doc = db_ref
+        .get::<Document>()
+        .execute(&db)
+        .await?
+        .ok_or(CrudError::Inconsistency)?;
+    Ok(Json(doc.get_bool(PAUSED_FIELD).unwrap_or(false)))
+}
+
+async fn set_paused<T: Task>(
+    name: String,
+    coll: &Collection<Vtuber>,
+    db: &Database,
+    paused: bool,
+) -> Result<HttpResponse, CrudError> {
+    let vtuber = GetVtuberOp { name }
+        .execute(coll)
+        .await?
+        .ok_or(CrudError::MissingVtuber)?;
+    let db_ref = vtuber
+        .fields
+        .get(T::Entry::KEY)
+        .ok_or(CrudError::MissingField)?;
+
+    SetPausedOp {
+        id: db_ref.id,
+        paused,
+    }
+    .execute(&db.collection::<Document>(&db_ref.collection))
+    .await?
+    .true_or(CrudError::Inconsistency)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn pause<T: Task>(
+    name: Path<String>,
+    coll: Data<Collection<Vtuber>>,
+    db: Data<Database>,
+) -> Result<HttpResponse, CrudError> {
+    set_paused::<T>(name.into_inner(), &coll, &db, true).await
+}
+
+async fn resume<T: Task>(
+    name: Path<String>,
+    coll: Data<Collection<Vtuber>>,
+    db: Data<Database>,
+) -> Result<HttpResponse, CrudError> {
+    set_paused::<T>(name.into_inner(), &coll, &db, false).await
+}
+
+async fn clear_quarantine<T: Task>(
+    name: Path<String>,
+    coll: Data<Collection<Vtuber>>,
+    db: Data<Database>,
+) -> Result<HttpResponse, CrudError> {
+    let vtuber = GetVtuberOp {
+        name: name.into_inner(),
+    }
+    .execute(&coll)
+    .await?
+    .ok_or(CrudError::MissingVtuber)?;
+    let db_ref = vtuber
+        .fields
+        .get(T::Entry::KEY)
+        .ok_or(CrudError::MissingField)?;
+
+    ClearQuarantineOp { id: db_ref.id }
+        .execute(&db.collection::<Document>(&db_ref.collection))
+        .await?
+        .true_or(CrudError::Inconsistency)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
 impl<T: Task> Func<(Scope, Source<T>)> for FoldFieldEp {
     type Output = Scope;
 
@@ -127,5 +221,15 @@ impl<T: Task> Func<(Scope, Source<T>)> for FoldFieldEp {
                 .route(web::put().to(put::<T>))
                 .route(web::delete().to(delete::<T>)),
         )
+        .service(
+            web::resource(format!("/{}/paused", T::Entry::KEY))
+                .route(web::get().to(get_paused::<T>))
+                .route(web::put().to(pause::<T>))
+                .route(web::delete().to(resume::<T>)),
+        )
+        .service(
+            web::resource(format!("/{}/quarantine", T::Entry::KEY))
+                .route(web::delete().to(clear_quarantine::<T>)),
+        )
     }
 }
@@ -11,7 +11,8 @@ use serde::Serialize;
 use tap::Pipe;
 
 use field::FoldFieldEp;
-pub use models::Vtuber;
+use models::SourceKeys;
+pub use models::{EntryHealth, QuarantinedEntry, Vtuber};
 use utils::ToOptionHList;
 
 use crate::manager::utils::{IntoDisplay, OptionLiftF};
@@ -22,8 +23,16 @@ mod errors;
 mod field;
 mod models;
 mod ops;
+#[cfg(test)]
+mod tests;
 mod utils;
 
+/// Paths under the manager which can't be used as vtuber names.
+const RESERVED_NAMES: &[&str] = &["quarantine", "health"];
+
+/// Keys of vtuber fields and paths under a vtuber which can't be used as source keys.
+const RESERVED_KEYS: &[&str] = &["health", "tags"];
+
 #[derive(Debug)]
 pub struct Source<T>(PhantomData<T>);
 
@@ -47,6 +56,7 @@ impl<T> Copy for Source<T> {}
 
 pub struct Manager<L> {
     sources: L,
+    keys: SourceKeys,
     db: Database,
     coll: Collection<Vtuber>,
 }
@@ -55,6 +65,7 @@ impl Manager<HNil> {
     pub const fn new(db: Database, coll: Collection<Vtuber>) -> Self {
         Self {
             sources: HNil,
+            keys: SourceKeys(Vec::new()),
             db,
             coll,
         }
@@ -62,12 +73,26 @@ impl Manager<HNil> {
 }
 
 impl<L> Manager<L> {
-    pub fn register<H: Task>(self) -> Manager<HCons<Source<H>, L>> {
+    /// Register a source.
+    ///
+    /// # Panics
+    /// Panics if the key of the source is reserved.
+    pub fn register<H: Task>(mut self) -> Manager<HCons<Source<H>, L>> {
+        assert!(
+            !RESERVED_KEYS.contains(&H::Entry::KEY),
+            "reserved source key: {}",
+            H::Entry::KEY
+        );
+        // keys are also names of source collections, which are listed once each
+        if !self.keys.0.contains(&H::Entry::KEY) {
+            self.keys.0.push(H::Entry::KEY);
+        }
         Manager {
             sources: HCons {
                 head: Source::default(),
                 tail: self.sources,
             },
+            keys: self.keys,
             db: self.db,
             coll: self.coll,
         }
@@ -85,6 +110,11 @@ where
     pub fn build(self, prefix: &str) -> Scope {
         let vtuber_scope = web::scope("/{vtuber}")
             .pipe(|scope| self.sources.foldl(Poly(FoldFieldEp), scope))
+            .service(
+                web::resource("/tags")
+                    .route(web::get().to(entry::get_tags))
+                    .route(web::put().to(entry::set_tags)),
+            )
             .service(
                 web::resource("")
                     .route(web::get().to(entry::get::<L, _, _>))
@@ -93,7 +123,15 @@ where
         Scope::new(prefix)
             .app_data(Data::new(self.db))
             .app_data(Data::new(self.coll))
+            .app_data(Data::new(self.keys))
             .service(web::resource("").route(web::post().to(entry::create)))
+            // registered before vtubers so that they're not taken as vtuber names
+            .service(
+                web::resource("/quarantine")
+                    .route(web::get().to(entry::quarantined))
+                    .route(web::delete().to(entry::clear_quarantined)),
+            )
+            .service(web::resource("/health").route(web::get().to(entry::health)))
             .service(vtuber_scope)
     }
 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use hmap_serde::HLabelledMap;
 use mongodb::bson::oid::ObjectId;
@@ -7,6 +7,7 @@ use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use crate::db::{DBOperation, DBRef, DBResult, Document};
+use crate::scheduler::health::{TaskHealth, HEALTH_FIELD};
 
 use super::utils::deserialize_maybe_hashmap;
 
@@ -17,6 +18,8 @@ pub struct Vtuber {
     pub name: String,
     #[serde(default, deserialize_with = "deserialize_maybe_hashmap")]
     pub fields: HashMap<String, DBRef>,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 impl Vtuber {
@@ -30,13 +33,23 @@ impl Vtuber {
         HLabelledMap<L>: DeserializeOwned,
     {
         let mut erased_fields = Document::new();
+        let mut health = BTreeMap::new();
         for (key, db_ref) in &self.fields {
-            erased_fields.insert(key, db_ref.get::<Document>().execute(db).await?);
+            let field = db_ref.get::<Document>().execute(db).await?;
+            if let Some(reported) = field
+                .as_ref()
+                .and_then(|field| field.get(HEALTH_FIELD))
+                .and_then(|reported| mongodb::bson::from_bson(reported.clone()).ok())
+            {
+                health.insert(key.clone(), reported);
+            }
+            erased_fields.insert(key, field);
         }
 
         Ok(VtuberFlatten {
             name: self.name.clone(),
             fields: mongodb::bson::from_document(erased_fields)?,
+            health,
         })
     }
 }
@@ -49,4 +62,55 @@ pub struct VtuberFlatten<L> {
         deserialize = "HLabelledMap<L>: Deserialize<'de>"
     ))]
     pub fields: HLabelledMap<L>,
+    /// Health of tasks keyed by source.
+    #[serde(default)]
+    pub health: BTreeMap<String, TaskHealth>,
 }
+
+/// Fields of a vtuber, along with health of their tasks keyed by source.
+#[derive(Debug, Clone, Serialize)]
+pub struct VtuberFields<T> {
+    #[serde(flatten)]
+    pub fields: T,
+    pub health: BTreeMap<String, TaskHealth>,
+}
+
+/// A source entry with the health reported by its task.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReportedHealth {
+    pub root: DBRef,
+    pub health: TaskHealth,
+}
+
+/// Health of the task of an entry.
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryHealth {
+    /// Name of the vtuber. `None` if the entry is dangling.
+    pub vtuber: Option<String>,
+    pub source: &'static str,
+    pub health: TaskHealth,
+}
+
+/// A source entry with its failures.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FailedEntry {
+    pub root: DBRef,
+    #[serde(default)]
+    pub failures: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+/// An entry quarantined for failing repeatedly.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantinedEntry {
+    /// Name of the vtuber. `None` if the entry is dangling.
+    pub vtuber: Option<String>,
+    pub source: &'static str,
+    pub failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// Keys of sources registered to the manager.
+#[derive(Debug, Clone, Default)]
+pub struct SourceKeys(pub Vec<&'static str>);
@@ -2,14 +2,18 @@ use std::collections::HashMap;
 
 use async_trait::async_trait;
 use erased_serde::private::serde::Serialize;
+use futures::TryStreamExt;
 use mongodb::bson;
 use mongodb::bson::doc;
 use mongodb::bson::oid::ObjectId;
 
 use crate::db::{CollOperation, Collection, DBRef, DBResult, Document};
+use crate::scheduler::health::HEALTH_FIELD;
+use crate::scheduler::pause::PAUSED_FIELD;
+use crate::scheduler::quarantine::{FAILURES_FIELD, QUARANTINED_FIELD, RETRY_AT_FIELD};
 use crate::utils::DBErrorExt;
 
-use super::models::Vtuber;
+use super::models::{FailedEntry, ReportedHealth, Vtuber};
 
 #[derive(Debug)]
 pub struct GetVtuberOp {
@@ -47,6 +51,7 @@ impl CollOperation for CreateVtuberOp {
                     doc_id: ObjectId::new(),
                     name: self.name,
                     fields: HashMap::new(),
+                    tags: Vec::new(),
                 },
                 None,
             )
@@ -56,6 +61,31 @@ impl CollOperation for CreateVtuberOp {
     }
 }
 
+#[derive(Debug)]
+pub struct SetTagsOp {
+    pub name: String,
+    pub tags: Vec<String>,
+}
+
+#[async_trait]
+impl CollOperation for SetTagsOp {
+    type Result = bool;
+    type Item = Vtuber;
+
+    const DESC: &'static str = "SetTags";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .update_one(
+                doc! {"name": self.name},
+                doc! {"$set": {"tags": self.tags}},
+                None,
+            )
+            .await
+            .map(|res| res.matched_count > 0)
+    }
+}
+
 #[derive(Debug)]
 pub struct DeleteVtuberOp {
     pub name: String,
@@ -154,3 +184,133 @@ impl CollOperation for LinkRefOp {
             .map(|_| ())
     }
 }
+
+/// Pause or resume a source entry. Other fields are kept.
+#[derive(Debug)]
+pub struct SetPausedOp {
+    pub id: ObjectId,
+    pub paused: bool,
+}
+
+#[async_trait]
+impl CollOperation for SetPausedOp {
+    type Result = bool;
+    type Item = Document;
+    const DESC: &'static str = "SetPaused";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        let update = if self.paused {
+            doc! {"$set": {PAUSED_FIELD: true}}
+        } else {
+            doc! {"$unset": {PAUSED_FIELD: ""}}
+        };
+        collection
+            .update_one(doc! {"_id": self.id}, update, None)
+            .await
+            .map(|res| res.matched_count > 0)
+    }
+}
+
+#[derive(Debug)]
+pub struct GetVtubersByIdOp {
+    pub ids: Vec<ObjectId>,
+}
+
+#[async_trait]
+impl CollOperation for GetVtubersByIdOp {
+    type Result = Vec<Vtuber>;
+    type Item = Vtuber;
+    const DESC: &'static str = "GetVtubersById";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .find(doc! {"_id": {"$in": self.ids}}, None)
+            .await?
+            .try_collect()
+            .await
+    }
+}
+
+/// Find source entries quarantined for failing repeatedly.
+#[derive(Debug)]
+pub struct ListQuarantinedOp;
+
+#[async_trait]
+impl CollOperation for ListQuarantinedOp {
+    type Result = Vec<FailedEntry>;
+    type Item = FailedEntry;
+    const DESC: &'static str = "ListQuarantined";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .find(doc! {QUARANTINED_FIELD: true}, None)
+            .await?
+            .try_collect()
+            .await
+    }
+}
+
+/// Find source entries with health reported.
+#[derive(Debug)]
+pub struct ListHealthOp;
+
+#[async_trait]
+impl CollOperation for ListHealthOp {
+    type Result = Vec<ReportedHealth>;
+    type Item = ReportedHealth;
+    const DESC: &'static str = "ListHealth";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .find(doc! {HEALTH_FIELD: {"$exists": true}}, None)
+            .await?
+            .try_collect()
+            .await
+    }
+}
+
+/// Lift the quarantine of a source entry, and reset its failures so that it can be acquired right away.
+#[derive(Debug)]
+pub struct ClearQuarantineOp {
+    pub id: ObjectId,
+}
+
+#[async_trait]
+impl CollOperation for ClearQuarantineOp {
+    type Result = bool;
+    type Item = Document;
+    const DESC: &'static str = "ClearQuarantine";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .update_one(
+                doc! {"_id": self.id},
+                doc! {"$unset": {QUARANTINED_FIELD: "", FAILURES_FIELD: "", RETRY_AT_FIELD: ""}},
+                None,
+            )
+            .await
+            .map(|res| res.matched_count > 0)
+    }
+}
+
+/// Lift the quarantine of all entries in a source collection. Returns the count of cleared entries.
+#[derive(Debug)]
+pub struct ClearQuarantinesOp;
+
+#[async_trait]
+impl CollOperation for ClearQuarantinesOp {
+    type Result = u64;
+    type Item = Document;
+    const DESC: &'static str = "ClearQuarantines";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .update_many(
+                doc! {QUARANTINED_FIELD: true},
+                doc! {"$unset": {QUARANTINED_FIELD: "", FAILURES_FIELD: "", RETRY_AT_FIELD: ""}},
+                None,
+            )
+            .await
+            .map(|res| res.modified_count)
+    }
+}
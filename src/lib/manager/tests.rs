@@ -0,0 +1,219 @@
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::App;
+use mongodb::bson::doc;
+use serde_json::Value;
+
+use crate::db::Document;
+use crate::scheduler::health::HEALTH_FIELD;
+use crate::scheduler::quarantine::{FAILURES_FIELD, QUARANTINED_FIELD};
+use crate::source::bililive::BililiveActor;
+use crate::source::twitter::TwitterActor;
+use crate::tests::with_db;
+
+use super::Manager;
+
+macro_rules! call {
+    ($app: expr, $req: expr) => {
+        call_service(&$app, $req.to_request()).await
+    };
+}
+
+#[actix::test]
+async fn must_list_quarantined_entries() {
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        let manager = Manager::new(db.clone(), db.collection("vtuber"))
+            .register::<BililiveActor>()
+            .register::<TwitterActor>();
+        let app = init_service(App::new().service(manager.build("/manage"))).await;
+
+        let resp = call!(app, TestRequest::post().uri("/manage").set_payload("v"));
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        for (source, payload) in [("bililive", "1"), ("twitter", "2")] {
+            let resp = call!(
+                app,
+                TestRequest::put()
+                    .uri(&format!("/manage/v/{}", source))
+                    .set_payload(payload)
+            );
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+            db.collection::<Document>(source)
+                .update_many(
+                    doc! {},
+                    doc! {"$set": {QUARANTINED_FIELD: true, FAILURES_FIELD: 10}},
+                    None,
+                )
+                .await
+                .expect("unable to quarantine entry");
+        }
+
+        let resp = call!(app, TestRequest::get().uri("/manage/quarantine"));
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = read_body_json(resp).await;
+        let mut sources: Vec<_> = body
+            .as_array()
+            .expect("not a list")
+            .iter()
+            .map(|entry| {
+                assert_eq!(entry["vtuber"], "v");
+                assert_eq!(entry["failures"], 10);
+                entry["source"].as_str().expect("no source").to_string()
+            })
+            .collect();
+        sources.sort();
+        assert_eq!(sources, ["bililive", "twitter"]);
+
+        let resp = call!(
+            app,
+            TestRequest::post().uri("/manage").set_payload("quarantine")
+        );
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // all quarantines are lifted at once
+        let resp = call!(app, TestRequest::delete().uri("/manage/quarantine"));
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = call!(app, TestRequest::get().uri("/manage/quarantine"));
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body, serde_json::json!([]));
+    })
+    .await;
+}
+
+#[actix::test]
+async fn must_list_task_health() {
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        let manager = Manager::new(db.clone(), db.collection("vtuber"))
+            .register::<BililiveActor>()
+            .register::<TwitterActor>();
+        let app = init_service(App::new().service(manager.build("/manage"))).await;
+
+        let resp = call!(app, TestRequest::post().uri("/manage").set_payload("v"));
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        for (source, payload) in [("bililive", "1"), ("twitter", "2")] {
+            let resp = call!(
+                app,
+                TestRequest::put()
+                    .uri(&format!("/manage/v/{}", source))
+                    .set_payload(payload)
+            );
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+            db.collection::<Document>(source)
+                .update_many(
+                    doc! {},
+                    doc! {"$set": {HEALTH_FIELD: {"state": "active", "events": 1}}},
+                    None,
+                )
+                .await
+                .expect("unable to report health");
+        }
+
+        let resp = call!(app, TestRequest::get().uri("/manage/health"));
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = read_body_json(resp).await;
+        let mut sources: Vec<_> = body
+            .as_array()
+            .expect("not a list")
+            .iter()
+            .map(|entry| {
+                assert_eq!(entry["vtuber"], "v");
+                assert_eq!(entry["health"]["state"], "active");
+                entry["source"].as_str().expect("no source").to_string()
+            })
+            .collect();
+        sources.sort();
+        assert_eq!(sources, ["bililive", "twitter"]);
+
+        // health of both sources is shown along with the vtuber
+        let resp = call!(app, TestRequest::get().uri("/manage/v"));
+        let body: Value = read_body_json(resp).await;
+        assert_eq!(body["health"]["bililive"]["events"], 1);
+        assert_eq!(body["health"]["twitter"]["events"], 1);
+
+        let resp = call!(
+            app,
+            TestRequest::post().uri("/manage").set_payload("health")
+        );
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    })
+    .await;
+}
+
+#[actix::test]
+async fn must_migrate_legacy_twitter_entries() {
+    use mongodb::bson::oid::ObjectId;
+
+    use crate::source::twitter::migrate_legacy_entries;
+
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        let vtubers = db.collection("vtuber");
+        let (twitter_id, debug_id) = (ObjectId::new(), ObjectId::new());
+        db.collection::<Document>("debug")
+            .insert_many(
+                [
+                    doc! {"_id": twitter_id, "uid": 1_i64, "since": null},
+                    doc! {"_id": debug_id, "id": 2_i64},
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+        db.collection::<Document>("vtuber")
+            .insert_many(
+                [
+                    doc! {"name": "a", "fields": {"debug": {"$ref": "debug", "$id": twitter_id}}},
+                    doc! {"name": "b", "fields": {"debug": {"$ref": "debug", "$id": debug_id}}},
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(migrate_legacy_entries(&db, &vtubers).await.unwrap(), 1);
+        // nothing left to migrate
+        assert_eq!(migrate_legacy_entries(&db, &vtubers).await.unwrap(), 0);
+
+        let twitter = db.collection::<Document>("twitter");
+        assert!(twitter
+            .find_one(doc! {"_id": twitter_id}, None)
+            .await
+            .unwrap()
+            .is_some());
+        let debug = db.collection::<Document>("debug");
+        assert_eq!(debug.count_documents(None, None).await.unwrap(), 1);
+
+        let vtuber = |name: &'static str| {
+            let vtubers = db.collection::<Document>("vtuber");
+            async move {
+                vtubers
+                    .find_one(doc! {"name": name}, None)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .get_document("fields")
+                    .unwrap()
+                    .clone()
+            }
+        };
+        assert_eq!(
+            vtuber("a").await,
+            doc! {"twitter": {"$ref": "twitter", "$id": twitter_id}}
+        );
+        assert_eq!(
+            vtuber("b").await,
+            doc! {"debug": {"$ref": "debug", "$id": debug_id}}
+        );
+    })
+    .await;
+}
@@ -1,14 +1,16 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+use std::mem;
 use std::sync::Arc;
 
 use actix::{
     Actor, ActorFutureExt, Addr, AsyncContext, AtomicResponse, Context, Handler, ResponseActFuture,
-    ResponseFuture, WrapFuture,
+    ResponseFuture, SystemService, WrapFuture,
 };
 use actix_signal::{AddrSignalExt, SignalHandler};
 use futures::FutureExt;
 use itertools::Itertools;
+use mongodb::bson;
 use serde::Serialize;
 use tracing::{info, info_span, warn};
 use tracing_actix::ActorInstrument;
@@ -16,15 +18,18 @@ use uuid::Uuid;
 
 use crate::common::ResponseWrapper;
 use crate::config::ScheduleConfig;
-use crate::db::{CollOperation, Collection, DBResult, Document};
+use crate::db::{DBResult, Document};
+use crate::server::{Drain, KillerActor, RegisterDrain};
 
 use super::builder::{ScheduleActorBuilder, BN};
 use super::driver::{RegisterScheduler, ScheduleDriverActor};
+use super::health::HEALTH_FIELD;
 use super::messages::{ActorsIter, GetId, TriggerGC, TrySchedule};
-use super::messages::{CheckOwnership, UpdateAll, UpdateEntry};
-use super::models::SchedulerMeta;
-use super::ops::ScheduleOp;
-use super::ops::{CheckOwnershipOp, ScheduleMode, UpdateEntryOp};
+use super::messages::{CheckOwnership, ReportFailure, StopEntry, UpdateAll, UpdateEntry};
+use super::models::{Failure, SchedulerMeta};
+use super::ops::{ScheduleMode, ScheduleOp};
+use super::quarantine::{cooldown, is_quarantined};
+use super::store::LeaseStore;
 use super::Task;
 use super::TaskInfo;
 
@@ -54,11 +59,15 @@ pub struct ScheduleActor<T>
 where
     T: Task,
 {
-    pub(crate) collection: Collection<Document>,
+    pub(crate) store: Arc<dyn LeaseStore>,
     pub(crate) ctor_builder: Arc<dyn Fn() -> T::Ctor + Send + Sync>,
     pub(crate) config: ScheduleConfig,
     pub(crate) ctx: ScheduleContext<T>,
     pub(crate) driver: Addr<ScheduleDriverActor<T>>,
+    /// Labels of the instance, matched against placement constraints of entries.
+    pub(crate) labels: Vec<String>,
+    /// Whether tasks are released for shutdown. No more tasks are acquired once set.
+    pub(crate) draining: bool,
 }
 
 impl<T: Task> ScheduleActor<T> {
@@ -70,10 +79,12 @@ impl<T: Task> ScheduleActor<T> {
 impl<T: Task> Debug for ScheduleActor<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("StealActor")
-            .field("collection", &self.collection)
+            .field("store", &self.store)
             .field("ctor_builder", &"<func>")
             .field("config", &self.config)
             .field("ctx", &self.ctx)
+            .field("labels", &self.labels)
+            .field("draining", &self.draining)
             .finish()
     }
 }
@@ -86,7 +97,10 @@ where
     type Result = AtomicResponse<Self, DBResult<Option<(TaskInfo, Addr<T>)>>>;
 
     fn handle(&mut self, _msg: TrySchedule<T>, ctx: &mut Self::Context) -> Self::Result {
-        let collection = self.collection.clone();
+        if self.draining {
+            return AtomicResponse::new(Box::pin(actix::fut::ready(Ok(None))));
+        }
+        let store = self.store.clone();
         let config = self.config;
         let ctor_builder = self.ctor_builder.clone();
 
@@ -94,16 +108,18 @@ where
         let scheduler_addr = ctx.address();
 
         let scheduler_id = self.ctx.id;
+        let labels = self.labels.clone();
 
         AtomicResponse::new(Box::pin(
             async move {
                 ScheduleOp::new(
                     ScheduleMode::Auto,
-                    T::query(),
+                    T::eligible_query(&labels),
+                    &labels,
                     ctx_meta,
                     config.max_interval,
                 )
-                .execute(&collection)
+                .execute(&*store)
                 .await
                 .map(|maybe_res| {
                     maybe_res.map(|(info, entry)| {
@@ -136,9 +152,8 @@ where
     type Result = ResponseFuture<DBResult<bool>>;
 
     fn handle(&mut self, msg: CheckOwnership, _ctx: &mut Self::Context) -> Self::Result {
-        let collection = self.collection.clone();
-        let op = CheckOwnershipOp { info: msg.info };
-        Box::pin(async move { op.execute(&collection).await })
+        let store = self.store.clone();
+        Box::pin(async move { store.check_ownership(msg.info).await })
     }
 }
 
@@ -150,13 +165,17 @@ where
     type Result = AtomicResponse<Self, DBResult<bool>>;
 
     fn handle(&mut self, msg: UpdateEntry<U>, _ctx: &mut Self::Context) -> Self::Result {
-        let collection = self.collection.clone();
-        let op = UpdateEntryOp {
-            info: msg.info,
-            body: msg.body,
-        };
+        let store = self.store.clone();
         AtomicResponse::new(Box::pin(
-            async move { op.execute(&collection).await }.into_actor(self),
+            async move {
+                let mut body = msg.body.as_ref().map(bson::to_document).transpose()?;
+                if let Some(health) = &msg.health {
+                    body.get_or_insert_with(Document::new)
+                        .insert(HEALTH_FIELD, bson::to_bson(health)?);
+                }
+                store.renew(msg.info, body, msg.weight).await
+            }
+            .into_actor(self),
         ))
     }
 }
@@ -168,24 +187,16 @@ where
     type Result = ResponseActFuture<Self, ()>;
 
     fn handle(&mut self, msg: UpdateAll, _ctx: &mut Self::Context) -> Self::Result {
-        let collection = self.collection.clone();
+        let store = self.store.clone();
         let entries = self
             .ctx
             .actors
             .iter()
-            .map(|(info, addr)| {
-                (
-                    UpdateEntryOp::<()> {
-                        info: *info,
-                        body: None,
-                    },
-                    addr.clone(),
-                )
-            })
-            .map(move |(op, addr)| {
-                let collection = collection.clone();
+            .map(|(info, addr)| (*info, addr.clone()))
+            .map(move |(info, addr)| {
+                let store = store.clone();
                 async move {
-                    let succ = op.execute(&collection).await.unwrap_or(false);
+                    let succ = store.renew(info, None, None).await.unwrap_or(false);
                     if !succ && msg.evict {
                         addr.stop();
                     }
@@ -201,6 +212,116 @@ where
     }
 }
 
+impl<T> Handler<Drain> for ScheduleActor<T>
+where
+    T: 'static + Task + Unpin,
+{
+    type Result = AtomicResponse<Self, ()>;
+
+    fn handle(&mut self, _msg: Drain, _ctx: &mut Self::Context) -> Self::Result {
+        self.draining = true;
+        let store = self.store.clone();
+        let releases = mem::take(&mut self.ctx.actors)
+            .into_iter()
+            .map(|(info, addr)| {
+                // stop the task first so that it won't touch the entry after release
+                addr.stop();
+                let store = store.clone();
+                // the release is seen by change streams of other drivers, which acquire the entry at once
+                async move {
+                    if let Err(e) = store.release(info).await {
+                        warn!("unable to release {}: {}", info.uuid, e);
+                    }
+                }
+            })
+            .collect_vec();
+        let count = releases.len();
+        AtomicResponse::new(Box::pin(
+            futures::future::join_all(releases)
+                .map(move |_| info!("released {} entries", count))
+                .into_actor(self)
+                .actor_instrument(info_span!("drain", id=?self.ctx.id)),
+        ))
+    }
+}
+
+impl<T> Handler<StopEntry> for ScheduleActor<T>
+where
+    T: 'static + Task + Unpin,
+{
+    type Result = AtomicResponse<Self, ()>;
+
+    fn handle(&mut self, msg: StopEntry, _ctx: &mut Self::Context) -> Self::Result {
+        let info = self
+            .ctx
+            .actors
+            .keys()
+            .find(|info| info.doc_id == msg.doc_id)
+            .copied();
+        let addr = info.and_then(|info| self.ctx.actors.remove(&info));
+        let store = self.store.clone();
+        AtomicResponse::new(Box::pin(
+            async move {
+                if let (Some(info), Some(addr)) = (info, addr) {
+                    info!("entry changed, stopping {}", info.uuid);
+                    addr.stop();
+                    if msg.release {
+                        if let Err(e) = store.release(info).await {
+                            warn!("unable to release {}: {}", info.uuid, e);
+                        }
+                    }
+                }
+            }
+            .into_actor(self)
+            .actor_instrument(info_span!("scheduler", id=?self.ctx.id)),
+        ))
+    }
+}
+
+impl<T> Handler<ReportFailure> for ScheduleActor<T>
+where
+    T: 'static + Task + Unpin,
+{
+    type Result = AtomicResponse<Self, ()>;
+
+    fn handle(&mut self, msg: ReportFailure, _ctx: &mut Self::Context) -> Self::Result {
+        let info = msg.info;
+        if let Some(addr) = self.ctx.actors.remove(&info) {
+            addr.stop();
+        }
+        let store = self.store.clone();
+        let failure = Failure {
+            error: msg.error,
+            permanent: msg.permanent,
+            healthy_after: self.config.max_interval,
+            policy: self.config.crash_loop,
+        };
+        AtomicResponse::new(Box::pin(
+            async move {
+                warn!("task {} failed: {}", info.uuid, failure.error);
+                let (policy, permanent) = (failure.policy, failure.permanent);
+                match store.record_failure(info, failure).await {
+                    Ok(Some(failures)) if permanent && is_quarantined(failures, &policy) => {
+                        warn!(
+                            "entry {} quarantined after {} failures",
+                            info.doc_id, failures
+                        );
+                    }
+                    Ok(Some(failures)) => info!(
+                        "entry {} cooling down for {:?}",
+                        info.doc_id,
+                        cooldown(failures, &policy)
+                    ),
+                    Ok(None) => {}
+                    Err(e) => warn!("unable to record failure of {}: {}", info.uuid, e),
+                }
+            }
+            .into_actor(self)
+            .actor_instrument(info_span!("scheduler", id=?self.ctx.id)),
+        ))
+    }
+}
+
 impl<T> Handler<GetId> for ScheduleActor<T>
 where
     T: 'static + Task + Unpin,
@@ -259,6 +380,7 @@ where
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.driver.do_send(RegisterScheduler(ctx.address()));
+        KillerActor::from_registry().do_send(RegisterDrain(ctx.address().recipient()));
         ctx.run_interval(self.config.max_interval / 2, |act, ctx| {
             ctx.spawn(
                 ctx.address()
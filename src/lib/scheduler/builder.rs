@@ -3,23 +3,24 @@ use std::sync::Arc;
 
 use actix::Addr;
 use hmap_serde::Labelled;
-use mongodb::{Collection, Database};
+use mongodb::Database;
 
 use crate::db::Document;
 use crate::scheduler::driver::ScheduleDriverActor;
-use crate::scheduler::{ScheduleActor, Task};
+use crate::scheduler::{LeaseStore, MongoLeaseStore, ScheduleActor, Task};
 use crate::ScheduleConfig;
 
 #[derive(Clone)]
-pub struct ScheduleActorBuilder<T, COLL, CTOR, CONF, DRV>
+pub struct ScheduleActorBuilder<T, STORE, CTOR, CONF, DRV>
 where
     T: Task,
 {
-    collection: Option<Collection<Document>>,
+    store: Option<Arc<dyn LeaseStore>>,
     ctor_builder: Option<Arc<dyn Fn() -> T::Ctor + Send + Sync>>,
     config: Option<ScheduleConfig>,
     driver: Option<Addr<ScheduleDriverActor<T>>>,
-    _marker: PhantomData<(COLL, CTOR, CONF, DRV)>,
+    labels: Vec<String>,
+    _marker: PhantomData<(STORE, CTOR, CONF, DRV)>,
 }
 
 pub struct BN;
@@ -29,61 +30,78 @@ pub struct BF;
 impl<T: Task> Default for ScheduleActorBuilder<T, BN, BN, BN, BN> {
     fn default() -> Self {
         Self {
-            collection: None,
+            store: None,
             ctor_builder: None,
             config: None,
             driver: None,
+            labels: Vec::new(),
             _marker: PhantomData,
         }
     }
 }
 
-impl<T, COLL, CTOR, CONF, DRV> ScheduleActorBuilder<T, COLL, CTOR, CONF, DRV>
+impl<T, STORE, CTOR, CONF, DRV> ScheduleActorBuilder<T, STORE, CTOR, CONF, DRV>
 where
     T: Task,
 {
+    /// Store leases in the source collection in given database.
     pub fn db(self, db: &Database) -> ScheduleActorBuilder<T, BF, CTOR, CONF, DRV> {
+        self.store(Arc::new(MongoLeaseStore::new(
+            db.collection::<Document>(T::Entry::KEY),
+        )))
+    }
+    /// Store leases in given store.
+    pub fn store(self, store: Arc<dyn LeaseStore>) -> ScheduleActorBuilder<T, BF, CTOR, CONF, DRV> {
         ScheduleActorBuilder {
-            collection: Some(db.collection(T::Entry::KEY)),
+            store: Some(store),
             ctor_builder: self.ctor_builder,
             config: self.config,
             driver: self.driver,
+            labels: self.labels,
             _marker: PhantomData,
         }
     }
     pub fn ctor_builder(
         self,
         f: impl Fn() -> T::Ctor + Send + Sync + 'static,
-    ) -> ScheduleActorBuilder<T, COLL, BF, CONF, DRV> {
+    ) -> ScheduleActorBuilder<T, STORE, BF, CONF, DRV> {
         ScheduleActorBuilder {
-            collection: self.collection,
+            store: self.store,
             ctor_builder: Some(Arc::new(f) as Arc<dyn Fn() -> T::Ctor + Send + Sync>),
             config: self.config,
             driver: self.driver,
+            labels: self.labels,
             _marker: PhantomData,
         }
     }
-    pub fn config(self, config: ScheduleConfig) -> ScheduleActorBuilder<T, COLL, CTOR, BF, DRV> {
+    pub fn config(self, config: ScheduleConfig) -> ScheduleActorBuilder<T, STORE, CTOR, BF, DRV> {
         ScheduleActorBuilder {
-            collection: self.collection,
+            store: self.store,
             ctor_builder: self.ctor_builder,
             config: Some(config),
             driver: self.driver,
+            labels: self.labels,
             _marker: PhantomData,
         }
     }
     pub fn driver(
         self,
         driver: Addr<ScheduleDriverActor<T>>,
-    ) -> ScheduleActorBuilder<T, COLL, CTOR, CONF, BF> {
+    ) -> ScheduleActorBuilder<T, STORE, CTOR, CONF, BF> {
         ScheduleActorBuilder {
-            collection: self.collection,
+            store: self.store,
             ctor_builder: self.ctor_builder,
             config: self.config,
             driver: Some(driver),
+            labels: self.labels,
             _marker: PhantomData,
         }
     }
+    /// Set labels of the instance. Defaults to none.
+    pub fn labels(mut self, labels: Vec<String>) -> Self {
+        self.labels = labels;
+        self
+    }
 }
 
 impl<T> ScheduleActorBuilder<T, BF, BF, BF, BF>
@@ -93,11 +111,13 @@ where
     pub fn build(self) -> ScheduleActor<T> {
         // SAFETY ensured by type parameters
         ScheduleActor {
-            collection: self.collection.unwrap(),
+            store: self.store.unwrap(),
             ctor_builder: self.ctor_builder.unwrap(),
             config: self.config.unwrap(),
             ctx: Default::default(),
             driver: self.driver.unwrap(),
+            labels: self.labels,
+            draining: false,
         }
     }
 }
@@ -6,8 +6,10 @@ use rand::{thread_rng, Rng};
 use tracing::info_span;
 use tracing_actix::ActorInstrument;
 
-use crate::scheduler::messages::{TrySchedule, UpdateAll};
+use crate::db::{Collection, Document};
+use crate::scheduler::messages::{StopEntry, TrySchedule, UpdateAll};
 use crate::scheduler::ops::ScheduleMode;
+use crate::scheduler::watch::{watch, EntryChange};
 use crate::scheduler::{ScheduleActor, Task};
 use crate::ScheduleConfig;
 
@@ -15,6 +17,8 @@ use crate::ScheduleConfig;
 pub struct ScheduleDriverActor<T: Task> {
     config: ScheduleConfig,
     addrs: Vec<Addr<ScheduleActor<T>>>,
+    /// Source collection watched for entry changes.
+    collection: Option<Collection<Document>>,
 }
 
 impl<T: Task> ScheduleDriverActor<T> {
@@ -22,8 +26,18 @@ impl<T: Task> ScheduleDriverActor<T> {
         Self {
             config,
             addrs: vec![],
+            collection: None,
         }
     }
+
+    /// React to entry changes in given collection as soon as they happen, if the deployment supports change streams.
+    ///
+    /// Polling is kept either way.
+    #[must_use]
+    pub fn watch(mut self, collection: Collection<Document>) -> Self {
+        self.collection = Some(collection);
+        self
+    }
 }
 
 impl_stop_on_panic!(ScheduleDriverActor<T: Task>);
@@ -40,6 +54,14 @@ impl<T: Task> Actor for ScheduleDriverActor<T> {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(collection) = self.collection.clone() {
+            actix::spawn(watch(
+                collection,
+                T::state_fields(),
+                ctx.address().recipient(),
+                self.config.schedule_interval,
+            ));
+        }
         let mut skip_once = true;
         ctx.run_interval(self.config.schedule_interval, |_, ctx| {
             let delay: u64 = thread_rng().gen_range(0..1000);
@@ -109,6 +131,38 @@ where
     new_addrs
 }
 
+impl<T> Handler<EntryChange> for ScheduleDriverActor<T>
+where
+    T: Task,
+    ScheduleActor<T>: Actor<Context = Context<ScheduleActor<T>>>,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: EntryChange, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            EntryChange::Available => ctx.notify(ScheduleAll(ScheduleMode::OutdatedOnly)),
+            EntryChange::Modified(doc_id) => {
+                for addr in &self.addrs {
+                    addr.do_send(StopEntry {
+                        doc_id,
+                        release: true,
+                    });
+                }
+                // acquire the entry again with the new config
+                ctx.notify(ScheduleAll(ScheduleMode::OutdatedOnly));
+            }
+            EntryChange::Deleted(doc_id) => {
+                for addr in &self.addrs {
+                    addr.do_send(StopEntry {
+                        doc_id,
+                        release: false,
+                    });
+                }
+            }
+        }
+    }
+}
+
 impl<T> Handler<RegisterScheduler<T>> for ScheduleDriverActor<T>
 where
     T: Task,
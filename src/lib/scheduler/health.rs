@@ -0,0 +1,84 @@
+//! Health of tasks.
+//!
+//! Tasks keep their health in memory and report it to schedulers, which persist it in the `health` field of
+//! entries alongside the timestamp.
+
+use std::time::{Duration, SystemTime};
+
+use actix::{AsyncContext, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::timestamp;
+
+use super::messages::UpdateEntry;
+use super::Task;
+
+/// Field of the health reported by the task holding an entry.
+pub const HEALTH_FIELD: &str = "health";
+
+/// Interval between health reports of tasks not reporting it along with their own updates.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthState {
+    /// The task is connecting to its source.
+    #[default]
+    Connecting,
+    /// The task is connected, and the last attempt to receive events succeeded.
+    Active,
+    /// The task is running, but the last attempt to receive events failed.
+    Degraded,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct TaskHealth {
+    pub state: HealthState,
+    /// Time of the last emitted event, in milliseconds.
+    pub last_event_at: Option<i64>,
+    pub last_error: Option<String>,
+    /// Events emitted by tasks of the entry.
+    pub events: u64,
+    /// Times tasks of the entry connected to the source again, e.g. after restarts or moving to another worker.
+    pub reconnects: u64,
+}
+
+impl TaskHealth {
+    /// Health of a new task of an entry, carrying over counters reported by previous tasks.
+    pub fn resume(previous: Option<&Self>) -> Self {
+        previous.map_or_else(Self::default, |previous| Self {
+            state: HealthState::Connecting,
+            last_event_at: previous.last_event_at,
+            last_error: previous.last_error.clone(),
+            events: previous.events,
+            reconnects: previous.reconnects + 1,
+        })
+    }
+
+    pub fn connected(&mut self) {
+        self.state = HealthState::Active;
+    }
+
+    pub fn record_event(&mut self) {
+        self.state = HealthState::Active;
+        self.last_event_at = Some(timestamp(SystemTime::now()));
+        self.events += 1;
+    }
+
+    pub fn record_error(&mut self, error: impl ToString) {
+        self.state = HealthState::Degraded;
+        self.last_error = Some(error.to_string());
+    }
+}
+
+/// Report health of a task to its scheduler every [`REPORT_INTERVAL`](REPORT_INTERVAL).
+pub fn report_periodically<T>(ctx: &mut Context<T>, health: fn(&T) -> &TaskHealth)
+where
+    T: 'static + Task + Unpin,
+{
+    ctx.run_interval(REPORT_INTERVAL, move |act, _ctx| {
+        act.get_scheduler()
+            .do_send(UpdateEntry::empty_payload(act.get_info()).health(health(act).clone()));
+    });
+}
@@ -0,0 +1,507 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{self, Bson, Document};
+use parking_lot::Mutex;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::db::{Collection, DBResult};
+use crate::utils::timestamp;
+
+use super::models::{Failure, Lease, TaskInfo, WeightedTask, WorkerInfo};
+use super::ops::{RELEASED_AT_FIELD, REPORTED_WEIGHT_FIELD, WEIGHT_FIELD};
+use super::pause::active_filter;
+use super::quarantine::{
+    cooldown, is_quarantined, ACQUIRED_AT_FIELD, FAILURES_FIELD, LAST_ERROR_FIELD,
+    QUARANTINED_FIELD, RETRY_AT_FIELD,
+};
+use super::store::LeaseStore;
+use super::watch::BOOKKEEPING_FIELDS;
+
+/// Leases kept in memory, for single-node deployments and tests.
+///
+/// Expiry is computed from the local clock. Queries support the subset of mongodb operators used by schedulers,
+/// i.e. `$and`, `$or`, `$nor`, `$not`, `$eq`, `$ne`, `$gt`, `$gte`, `$lt`, `$lte`, `$in`, `$nin`, `$exists`,
+/// `$size` and `$elemMatch`. Queries using other operators are rejected with an error.
+#[derive(Debug, Default)]
+pub struct MemoryLeaseStore {
+    entries: Mutex<BTreeMap<ObjectId, Document>>,
+}
+
+impl MemoryLeaseStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add an entry, assigning an id if it has none. Returns the id of the entry.
+    pub fn insert(&self, mut entry: Document) -> ObjectId {
+        let id = entry
+            .get_object_id("_id")
+            .unwrap_or_else(|_| ObjectId::new());
+        entry.insert("_id", id);
+        self.entries.lock().insert(id, entry);
+        id
+    }
+
+    /// Remove an entry. Returns the removed entry if any.
+    pub fn remove(&self, id: ObjectId) -> Option<Document> {
+        self.entries.lock().remove(&id)
+    }
+
+    /// Get a copy of an entry.
+    pub fn get(&self, id: ObjectId) -> Option<Document> {
+        self.entries.lock().get(&id).cloned()
+    }
+
+    /// Replace entries with given docs, keeping bookkeeping and given state fields written to memory.
+    ///
+    /// Entries missing in `docs` are removed.
+    pub fn sync(&self, docs: Vec<Document>, state_fields: &[&str]) {
+        let mut entries = self.entries.lock();
+        let mut synced = BTreeMap::new();
+        for mut doc in docs {
+            let id = match doc.get_object_id("_id") {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            if let Some(entry) = entries.remove(&id) {
+                for (field, value) in entry {
+                    if BOOKKEEPING_FIELDS.contains(&field.as_str())
+                        || state_fields.contains(&field.as_str())
+                    {
+                        doc.insert(field, value);
+                    }
+                }
+            }
+            synced.insert(id, doc);
+        }
+        *entries = synced;
+    }
+
+    /// Keep entries in sync with given collection, reloading them every `interval`.
+    ///
+    /// Stops when the store is dropped elsewhere.
+    pub async fn mirror(
+        self: Arc<Self>,
+        collection: Collection<Document>,
+        state_fields: &'static [&'static str],
+        interval: Duration,
+    ) {
+        while Arc::strong_count(&self) > 1 {
+            let docs: DBResult<Vec<Document>> =
+                async { collection.find(None, None).await?.try_collect().await }.await;
+            match docs {
+                Ok(docs) => self.sync(docs, state_fields),
+                Err(e) => warn!("unable to load entries of {}: {}", collection.name(), e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+fn now() -> i64 {
+    timestamp(SystemTime::now())
+}
+
+fn acquire(entry: &mut Document, lease: Lease) {
+    let epoch = entry.get("epoch").and_then(as_i64).unwrap_or(0);
+    entry.insert("timestamp", now());
+    entry.insert(ACQUIRED_AT_FIELD, now());
+    entry.insert("uuid", bson::Uuid::from(lease.uuid));
+    entry.insert("parent_uuid", bson::Uuid::from(lease.parent_uuid));
+    entry.insert("epoch", epoch + 1);
+}
+
+fn is_fresh(entry: &Document, ago: Duration) -> bool {
+    let since = now() - ago.as_millis() as i64;
+    entry
+        .get("timestamp")
+        .and_then(as_i64)
+        .is_some_and(|ts| ts >= since)
+}
+
+fn is_cooled_down(entry: &Document) -> bool {
+    entry
+        .get(RETRY_AT_FIELD)
+        .and_then(as_i64)
+        .is_none_or(|retry_at| retry_at <= now())
+}
+
+fn is_held_by(entry: &Document, info: TaskInfo) -> bool {
+    entry.get("uuid") == Some(&bson::Uuid::from(info.uuid).into())
+        && entry.get("parent_uuid") == Some(&bson::Uuid::from(info.parent_uuid).into())
+}
+
+fn parent_of(entry: &Document) -> Option<Uuid> {
+    match entry.get("parent_uuid") {
+        Some(Bson::Binary(binary)) => binary.to_uuid().ok().map(Uuid::from),
+        _ => None,
+    }
+}
+
+/// Effective weight of an entry, the same as the one computed by mongodb.
+fn weight_of(entry: &Document) -> u64 {
+    let field = |name| entry.get(name).filter(|value| **value != Bson::Null);
+    field(REPORTED_WEIGHT_FIELD)
+        .or_else(|| field(WEIGHT_FIELD))
+        .map_or(Some(1), |weight| match weight {
+            Bson::String(weight) => weight.parse().ok(),
+            weight => as_i64(weight),
+        })
+        .map_or(1, |weight: i64| weight.max(0) as u64)
+}
+
+fn as_i64(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(v) => Some(i64::from(*v)),
+        Bson::Int64(v) => Some(*v),
+        Bson::Double(v) => Some(*v as i64),
+        _ => None,
+    }
+}
+
+fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) => {
+            as_i64(a).zip(as_i64(b)).map(|(a, b)| a.cmp(&b))
+        }
+        (
+            Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_),
+            Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_),
+        ) => {
+            let to_f64 = |v: &Bson| match v {
+                Bson::Double(v) => *v,
+                v => as_i64(v).unwrap_or_default() as f64,
+            };
+            to_f64(a).partial_cmp(&to_f64(b))
+        }
+        (a, b) => (a == b).then_some(Ordering::Equal),
+    }
+}
+
+fn equals(a: &Bson, b: &Bson) -> bool {
+    compare(a, b) == Some(Ordering::Equal)
+}
+
+/// Resolve a dotted path in a doc.
+fn lookup<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let (head, rest) = path
+        .split_once('.')
+        .map_or((path, None), |(h, r)| (h, Some(r)));
+    let value = doc.get(head)?;
+    match (rest, value) {
+        (None, value) => Some(value),
+        (Some(rest), Bson::Document(doc)) => lookup(doc, rest),
+        _ => None,
+    }
+}
+
+const LOGICAL_OPERATORS: &[&str] = &["$and", "$or", "$nor"];
+const FIELD_OPERATORS: &[&str] = &[
+    "$eq",
+    "$ne",
+    "$gt",
+    "$gte",
+    "$lt",
+    "$lte",
+    "$in",
+    "$nin",
+    "$exists",
+    "$size",
+    "$elemMatch",
+    "$not",
+];
+
+fn unsupported(op: &str) -> mongodb::error::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("query operator {} isn't supported by the memory store", op),
+    )
+    .into()
+}
+
+/// Ensure that a query only uses operators the memory store understands.
+///
+/// # Errors
+/// Raise an error on the first unsupported operator, e.g. `$expr` or `$regex`.
+pub fn validate(query: &Document) -> DBResult<()> {
+    query.iter().try_for_each(|(key, cond)| match cond {
+        Bson::Array(queries) if LOGICAL_OPERATORS.contains(&key.as_str()) => {
+            queries.iter().try_for_each(|query| {
+                query
+                    .as_document()
+                    .map_or_else(|| Err(unsupported(key)), validate)
+            })
+        }
+        _ if key.starts_with('$') => Err(unsupported(key)),
+        cond => validate_field(cond),
+    })
+}
+
+fn validate_field(cond: &Bson) -> DBResult<()> {
+    operators(cond).map_or(Ok(()), |ops| {
+        ops.iter().try_for_each(|(op, arg)| match op.as_str() {
+            "$not" => validate_field(arg),
+            "$elemMatch" => match arg {
+                arg if operators(arg).is_some() => validate_field(arg),
+                Bson::Document(query) => validate(query),
+                _ => Ok(()),
+            },
+            op if FIELD_OPERATORS.contains(&op) => Ok(()),
+            op => Err(unsupported(op)),
+        })
+    })
+}
+
+/// Check whether a doc matches a query, which should have been validated.
+pub fn matches(doc: &Document, query: &Document) -> bool {
+    query.iter().all(|(key, cond)| match (key.as_str(), cond) {
+        ("$and", Bson::Array(queries)) => queries.iter().all(|q| matches_bson(doc, q)),
+        ("$or", Bson::Array(queries)) => queries.iter().any(|q| matches_bson(doc, q)),
+        ("$nor", Bson::Array(queries)) => !queries.iter().any(|q| matches_bson(doc, q)),
+        (path, cond) => matches_field(lookup(doc, path), cond),
+    })
+}
+
+fn matches_bson(doc: &Document, query: &Bson) -> bool {
+    query.as_document().is_some_and(|query| matches(doc, query))
+}
+
+fn operators(cond: &Bson) -> Option<&Document> {
+    cond.as_document()
+        .filter(|cond| !cond.is_empty() && cond.keys().all(|key| key.starts_with('$')))
+}
+
+fn matches_field(value: Option<&Bson>, cond: &Bson) -> bool {
+    if let Some(ops) = operators(cond) {
+        ops.iter().all(|(op, arg)| matches_operator(value, op, arg))
+    } else {
+        matches_operator(value, "$eq", cond)
+    }
+}
+
+/// Values compared by operators. Arrays are compared element-wise besides as a whole.
+fn candidates(value: Option<&Bson>) -> Vec<&Bson> {
+    match value {
+        Some(Bson::Array(items)) => items.iter().chain(value).collect(),
+        Some(value) => vec![value],
+        None => vec![],
+    }
+}
+
+fn matches_operator(value: Option<&Bson>, op: &str, arg: &Bson) -> bool {
+    let cmp = |pred: fn(Ordering) -> bool| {
+        candidates(value)
+            .into_iter()
+            .any(|v| compare(v, arg).is_some_and(pred))
+    };
+    let in_list = |list: &Bson| {
+        list.as_array().is_some_and(|list| {
+            list.iter().any(|item| match value {
+                None => *item == Bson::Null,
+                Some(_) => candidates(value).into_iter().any(|v| equals(v, item)),
+            })
+        })
+    };
+    match op {
+        "$eq" => match value {
+            None => *arg == Bson::Null,
+            Some(_) => candidates(value).into_iter().any(|v| equals(v, arg)),
+        },
+        "$ne" => !matches_operator(value, "$eq", arg),
+        "$gt" => cmp(Ordering::is_gt),
+        "$gte" => cmp(Ordering::is_ge),
+        "$lt" => cmp(Ordering::is_lt),
+        "$lte" => cmp(Ordering::is_le),
+        "$in" => in_list(arg),
+        "$nin" => !in_list(arg),
+        "$exists" => value.is_some() == arg.as_bool().unwrap_or(true),
+        "$size" => match value {
+            Some(Bson::Array(items)) => as_i64(arg) == Some(items.len() as i64),
+            _ => false,
+        },
+        "$elemMatch" => match value {
+            Some(Bson::Array(items)) => items.iter().any(|item| match (item, arg) {
+                (item, arg) if operators(arg).is_some() => matches_field(Some(item), arg),
+                (Bson::Document(item), Bson::Document(query)) => matches(item, query),
+                _ => false,
+            }),
+            _ => false,
+        },
+        "$not" => !matches_field(value, arg),
+        _ => unreachable!("unsupported operators are rejected by validation"),
+    }
+}
+
+#[async_trait]
+impl LeaseStore for MemoryLeaseStore {
+    async fn acquire_outdated(
+        &self,
+        query: &Document,
+        ago: Duration,
+        lease: Lease,
+    ) -> DBResult<Option<Document>> {
+        validate(query)?;
+        let mut entries = self.entries.lock();
+        let entry = entries
+            .values_mut()
+            .find(|entry| matches(entry, query) && !is_fresh(entry, ago) && is_cooled_down(entry));
+        Ok(entry.map(|entry| {
+            acquire(entry, lease);
+            entry.clone()
+        }))
+    }
+
+    async fn steal(&self, victim: TaskInfo, lease: Lease) -> DBResult<Option<Document>> {
+        let mut entries = self.entries.lock();
+        let entry = entries
+            .get_mut(&victim.doc_id)
+            .filter(|entry| is_held_by(entry, victim));
+        Ok(entry.map(|entry| {
+            acquire(entry, lease);
+            entry.clone()
+        }))
+    }
+
+    async fn renew(
+        &self,
+        info: TaskInfo,
+        body: Option<Document>,
+        weight: Option<u64>,
+    ) -> DBResult<bool> {
+        let mut entries = self.entries.lock();
+        let entry = entries.get_mut(&info.doc_id).filter(|entry| {
+            entry.get("uuid") == Some(&bson::Uuid::from(info.uuid).into())
+                && matches(entry, &active_filter())
+        });
+        Ok(entry.is_some_and(|entry| {
+            entry.extend(body.unwrap_or_default());
+            entry.insert("timestamp", now());
+            if let Some(weight) = weight {
+                entry.insert(REPORTED_WEIGHT_FIELD, weight as i64);
+            }
+            true
+        }))
+    }
+
+    async fn release(&self, info: TaskInfo) -> DBResult<bool> {
+        let mut entries = self.entries.lock();
+        let entry = entries
+            .get_mut(&info.doc_id)
+            .filter(|entry| entry.get("uuid") == Some(&bson::Uuid::from(info.uuid).into()));
+        Ok(entry.is_some_and(|entry| {
+            entry.insert(RELEASED_AT_FIELD, now());
+            for field in ["uuid", "parent_uuid", "timestamp"] {
+                entry.remove(field);
+            }
+            true
+        }))
+    }
+
+    async fn record_failure(&self, info: TaskInfo, failure: Failure) -> DBResult<Option<u32>> {
+        let mut entries = self.entries.lock();
+        let entry = entries
+            .get_mut(&info.doc_id)
+            .filter(|entry| entry.get("uuid") == Some(&bson::Uuid::from(info.uuid).into()));
+        Ok(entry.map(|entry| {
+            let now = now();
+            let running = now - entry.get(ACQUIRED_AT_FIELD).and_then(as_i64).unwrap_or(0);
+            let failures = if running < failure.healthy_after.as_millis() as i64 {
+                entry.get(FAILURES_FIELD).and_then(as_i64).unwrap_or(0) as u32 + 1
+            } else {
+                1
+            };
+            let cooldown = cooldown(failures, &failure.policy).as_millis() as i64;
+            entry.insert(FAILURES_FIELD, failures as i32);
+            entry.insert(LAST_ERROR_FIELD, failure.error);
+            entry.insert(RETRY_AT_FIELD, now + cooldown);
+            entry.insert(
+                QUARANTINED_FIELD,
+                failure.permanent && is_quarantined(failures, &failure.policy),
+            );
+            for field in ["uuid", "parent_uuid", "timestamp"] {
+                entry.remove(field);
+            }
+            failures
+        }))
+    }
+
+    async fn check_ownership(&self, info: TaskInfo) -> DBResult<bool> {
+        Ok(self
+            .entries
+            .lock()
+            .get(&info.doc_id)
+            .is_some_and(|entry| is_held_by(entry, info)))
+    }
+
+    async fn total_weight(&self, query: &Document) -> DBResult<u64> {
+        validate(query)?;
+        Ok(self
+            .entries
+            .lock()
+            .values()
+            .filter(|entry| matches(entry, query))
+            .map(weight_of)
+            .sum())
+    }
+
+    async fn workers(
+        &self,
+        query: &Document,
+        ago: Duration,
+        exclude: Uuid,
+    ) -> DBResult<Vec<WorkerInfo>> {
+        validate(query)?;
+        let mut workers: BTreeMap<Uuid, WorkerInfo> = BTreeMap::new();
+        for entry in self.entries.lock().values() {
+            if !matches(entry, query) || !is_fresh(entry, ago) {
+                continue;
+            }
+            if let Some(id) = parent_of(entry).filter(|id| *id != exclude) {
+                let worker = workers.entry(id).or_insert(WorkerInfo {
+                    id,
+                    count: 0,
+                    weight: 0,
+                });
+                worker.count += 1;
+                worker.weight += weight_of(entry);
+            }
+        }
+        Ok(workers.into_values().collect())
+    }
+
+    async fn tasks_on_worker(
+        &self,
+        query: &Document,
+        ago: Duration,
+        worker: Uuid,
+    ) -> DBResult<Vec<WeightedTask>> {
+        validate(query)?;
+        Ok(self
+            .entries
+            .lock()
+            .values()
+            .filter(|entry| {
+                matches(entry, query) && is_fresh(entry, ago) && parent_of(entry) == Some(worker)
+            })
+            .filter_map(|entry| {
+                let info: TaskInfo = bson::from_document(entry.clone()).ok()?;
+                Some(WeightedTask {
+                    doc_id: info.doc_id,
+                    uuid: info.uuid,
+                    parent_uuid: info.parent_uuid,
+                    weight: weight_of(entry),
+                })
+            })
+            .collect())
+    }
+}
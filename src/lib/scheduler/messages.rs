@@ -2,8 +2,10 @@ use std::collections::HashMap;
 use std::marker::PhantomData;
 
 use actix::{Actor, Addr, Message, ResponseFuture};
+use mongodb::bson::oid::ObjectId;
 
 use crate::db::DBResult;
+use crate::scheduler::health::TaskHealth;
 use crate::scheduler::models::TaskInfo;
 use crate::scheduler::ops::ScheduleMode;
 
@@ -37,6 +39,9 @@ pub struct CheckOwnership {
 pub struct UpdateEntry<T> {
     pub info: TaskInfo,
     pub body: Option<T>,
+    /// Load of the task, used to balance tasks among workers. Overrides the static weight in the entry.
+    pub weight: Option<u64>,
+    pub health: Option<TaskHealth>,
 }
 
 impl<T> UpdateEntry<T> {
@@ -44,13 +49,73 @@ impl<T> UpdateEntry<T> {
         Self {
             info,
             body: Some(body),
+            weight: None,
+            health: None,
         }
     }
+
+    /// Report the load of the task.
+    #[must_use]
+    pub const fn weight(mut self, weight: u64) -> Self {
+        self.weight = Some(weight);
+        self
+    }
+
+    /// Report the health of the task.
+    #[must_use]
+    pub fn health(mut self, health: TaskHealth) -> Self {
+        self.health = Some(health);
+        self
+    }
 }
 
 impl UpdateEntry<()> {
     pub const fn empty_payload(info: TaskInfo) -> Self {
-        Self { info, body: None }
+        Self {
+            info,
+            body: None,
+            weight: None,
+            health: None,
+        }
+    }
+}
+
+/// Stop the task running given entry, if any.
+#[derive(Debug, Copy, Clone, Message)]
+#[rtype("()")]
+pub struct StopEntry {
+    pub doc_id: ObjectId,
+    /// Release the entry so that it can be acquired again right away.
+    pub release: bool,
+}
+
+/// Report that a task stops abnormally, so that its entry cools down before it's acquired again.
+#[derive(Debug, Clone, Message)]
+#[rtype("()")]
+pub struct ReportFailure {
+    pub info: TaskInfo,
+    pub error: String,
+    /// Whether the error won't go away by retrying, e.g. the uid doesn't exist.
+    /// Only permanent failures may put the entry into quarantine.
+    pub permanent: bool,
+}
+
+impl ReportFailure {
+    /// Report a failure which may be resolved by itself, e.g. a network error or rate limit.
+    pub fn new(info: TaskInfo, error: impl ToString) -> Self {
+        Self {
+            info,
+            error: error.to_string(),
+            permanent: false,
+        }
+    }
+
+    /// Report a failure which won't be resolved by retrying, e.g. a bad uid or a banned account.
+    pub fn permanent(info: TaskInfo, error: impl ToString) -> Self {
+        Self {
+            permanent: true,
+            ..Self::new(info, error)
+        }
     }
 }
 
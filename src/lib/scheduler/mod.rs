@@ -7,23 +7,38 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::Span;
 
 pub use actor::ScheduleActor;
-pub use models::TaskInfo;
+pub use memory::MemoryLeaseStore;
+pub use models::{Failure, Lease, TaskInfo, WeightedTask, WorkerInfo};
+pub use store::{open_store, LeaseStore, MongoLeaseStore};
+
+use mongodb::bson::doc;
 
 use crate::db::{DBRef, Document};
+use crate::scheduler::health::TaskHealth;
 use crate::utils::{FromStrE, Scheduler};
 
 pub mod actor;
 mod builder;
 pub mod driver;
+pub mod health;
+mod memory;
 pub mod messages;
 mod models;
 mod ops;
+pub mod pause;
+pub mod placement;
+pub mod quarantine;
+mod store;
 #[cfg(test)]
 mod tests;
+pub mod watch;
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Hash)]
 pub struct Entry<T> {
     pub root: DBRef,
+    /// Health reported by the last task of the entry. Written by schedulers only.
+    #[serde(default, skip_serializing)]
+    pub health: Option<TaskHealth>,
     #[serde(flatten, bound(deserialize = "T: Deserialize<'de>"))]
     pub data: T,
 }
@@ -54,6 +69,23 @@ pub trait Task: TaskFieldGetter + Actor<Context = Context<Self>> + SignalHandler
         + Display;
     type Ctor;
     fn query() -> Document;
+    /// Query of entries which may run on an instance with given labels.
+    ///
+    /// Paused or quarantined entries and entries requiring labels the instance doesn't have are excluded.
+    fn eligible_query(labels: &[String]) -> Document {
+        doc! {
+            "$and": [
+                Self::query(),
+                placement::required_filter(labels),
+                pause::active_filter(),
+                quarantine::unquarantined_filter(),
+            ]
+        }
+    }
+    /// Fields of the entry written by the task itself. Changing them doesn't restart the task.
+    fn state_fields() -> &'static [&'static str] {
+        &[]
+    }
     fn construct(
         entry: Entry<Self::Entry>,
         ctor: Self::Ctor,
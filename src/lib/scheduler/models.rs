@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use actix::Actor;
 use actix_signal::SignalHandler;
 use mongodb::bson::oid::ObjectId;
@@ -5,6 +7,8 @@ use mongodb::bson::serde_helpers::uuid_as_binary;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::config::CrashLoopConfig;
+
 use super::actor::ScheduleContext;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
@@ -15,19 +19,71 @@ pub struct TaskInfo {
     pub uuid: Uuid,
     #[serde(with = "uuid_as_binary")]
     pub parent_uuid: Uuid,
+    /// Lease epoch of the entry when it's acquired, used as the fencing token of emitted events.
+    ///
+    /// Not serialized, so that it doesn't take part in ownership filters.
+    #[serde(default, skip_serializing)]
+    pub epoch: u64,
+}
+
+/// Ownership assigned to an entry when it's acquired.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Lease {
+    pub uuid: Uuid,
+    pub parent_uuid: Uuid,
+}
+
+/// Abnormal stop of a task, recorded in its entry.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Failure {
+    pub error: String,
+    /// Whether the failure may put the entry into quarantine.
+    pub permanent: bool,
+    /// Failures are consecutive if the task fails within this duration since it's acquired.
+    pub healthy_after: Duration,
+    pub policy: CrashLoopConfig,
+}
+
+/// Entries held by a worker.
+#[derive(Debug, Copy, Clone, Deserialize, Eq, PartialEq)]
+pub struct WorkerInfo {
+    #[serde(rename = "_id", with = "uuid_as_binary")]
+    pub id: Uuid,
+    pub count: u64,
+    /// Total weight of entries on the worker.
+    pub weight: u64,
+}
+
+/// An entry running on a worker, with its effective weight.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct WeightedTask {
+    #[serde(rename = "_id")]
+    pub doc_id: ObjectId,
+    #[serde(with = "uuid_as_binary")]
+    pub uuid: Uuid,
+    #[serde(with = "uuid_as_binary")]
+    pub parent_uuid: Uuid,
+    pub weight: u64,
+}
+
+impl WeightedTask {
+    pub const fn info(&self) -> TaskInfo {
+        TaskInfo {
+            doc_id: self.doc_id,
+            uuid: self.uuid,
+            parent_uuid: self.parent_uuid,
+            epoch: 0,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 pub struct SchedulerMeta {
     pub id: Uuid,
-    pub actor_count: usize,
 }
 
 impl<T: Actor + SignalHandler> From<&ScheduleContext<T>> for SchedulerMeta {
     fn from(ctx: &ScheduleContext<T>) -> Self {
-        Self {
-            id: ctx.id,
-            actor_count: ctx.actors.len(),
-        }
+        Self { id: ctx.id }
     }
 }
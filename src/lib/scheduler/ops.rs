@@ -1,22 +1,26 @@
 use std::marker::PhantomData;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
 use async_trait::async_trait;
-use bson::serde_helpers::uuid_as_binary;
 use futures::{StreamExt, TryStreamExt};
-use mongodb::bson::{self, bson, doc, Document};
+use mongodb::bson::{self, bson, doc, Bson, Document};
 use mongodb::error::Result as DBResult;
 use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
 use rand::seq::SliceRandom;
 use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::db::{CollOperation, Collection};
-use crate::utils::timestamp;
 
-use super::models::{SchedulerMeta, TaskInfo};
+use super::models::{Failure, Lease, SchedulerMeta, TaskInfo, WeightedTask, WorkerInfo};
+use super::pause::active_filter;
+use super::placement::preferred_filter;
+use super::quarantine::{
+    ACQUIRED_AT_FIELD, FAILURES_FIELD, LAST_ERROR_FIELD, QUARANTINED_FIELD, RETRY_AT_FIELD,
+};
+use super::store::LeaseStore;
 
 #[derive(Debug, Copy, Clone)]
 pub struct CheckOwnershipOp {
@@ -32,16 +36,71 @@ impl CollOperation for CheckOwnershipOp {
 
     async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
         collection
-            .find_one(bson::to_document(&self.info).unwrap(), None)
+            .find_one(bson::to_document(&self.info)?, None)
             .await
             .map(|maybe| maybe.is_some())
     }
 }
 
+/// Field of the weight reported by the running task.
+pub const REPORTED_WEIGHT_FIELD: &str = "reported_weight";
+/// Field of the weight set statically in the entry doc.
+pub const WEIGHT_FIELD: &str = "weight";
+/// Field of the time an entry is released by its owner, in milliseconds.
+///
+/// Written on release so that change streams of other drivers pick the entry up immediately.
+pub const RELEASED_AT_FIELD: &str = "released_at";
+
+/// Aggregation expression of the current mongodb server time, in milliseconds.
+///
+/// Server time is used instead of local clocks so that clock skew among instances won't break ownership.
+fn server_now() -> Bson {
+    bson!({"$toLong": "$$NOW"})
+}
+
+/// Filter of entries refreshed within given duration, by server time.
+fn fresh_filter(ago: Duration) -> Document {
+    doc! {"$expr": {"$gte": ["$timestamp", {"$subtract": [server_now(), ago.as_millis() as i64]}]}}
+}
+
+/// Filter of entries not refreshed within given duration, by server time.
+///
+/// Entries never acquired have no timestamp, which compares less than any number.
+fn outdated_filter(ago: Duration) -> Document {
+    doc! {"$expr": {"$lt": ["$timestamp", {"$subtract": [server_now(), ago.as_millis() as i64]}]}}
+}
+
+/// Filter of entries whose failure cool-down has ended, by server time.
+///
+/// Entries never failed have no `retry_at`, which compares less than any number.
+fn cooled_down_filter() -> Document {
+    doc! {"$expr": {"$not": [{"$gt": [format!("${}", RETRY_AT_FIELD), server_now()]}]}}
+}
+
+/// Aggregation expression of the effective weight of an entry.
+///
+/// The weight reported by the task takes precedence over the static one. Entries without weights weigh 1, and
+/// so do ones with malformed weights, e.g. strings typed by hand, instead of failing the whole aggregation.
+/// Weights are truncated to non-negative integers.
+fn weight_expr() -> Bson {
+    bson!({
+        "$max": [0_i64, {"$convert": {
+            "input": {"$ifNull": [
+                format!("${}", REPORTED_WEIGHT_FIELD),
+                format!("${}", WEIGHT_FIELD)
+            ]},
+            "to": "long",
+            "onError": 1_i64,
+            "onNull": 1_i64
+        }}]
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct UpdateEntryOp<T> {
     pub info: TaskInfo,
     pub body: Option<T>,
+    pub weight: Option<u64>,
 }
 
 #[async_trait]
@@ -52,17 +111,52 @@ impl<T: Serialize + Send> CollOperation for UpdateEntryOp<T> {
     const DESC: &'static str = "UpdateEntry";
 
     async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
-        let mut body = if let Some(body) = &self.body {
+        // values are wrapped in `$literal` so that they won't be evaluated by the update pipeline
+        let mut body: Document = if let Some(body) = &self.body {
             bson::to_document(body)?
+                .into_iter()
+                .map(|(k, v)| (k, bson!({ "$literal": v })))
+                .collect()
         } else {
             Document::new()
         };
-        body.insert("timestamp", timestamp(SystemTime::now()));
+        body.insert("timestamp", server_now());
+        if let Some(weight) = self.weight {
+            body.insert(REPORTED_WEIGHT_FIELD, weight as i64);
+        }
 
+        let mut filter = doc! {"_id": self.info.doc_id, "uuid": self.info.uuid};
+        // paused entries aren't renewed, so that their owners stop
+        filter.extend(active_filter());
+        Ok(collection
+            .update_one(filter, vec![doc! {"$set": body}], None)
+            .await?
+            .modified_count
+            > 0)
+    }
+}
+
+/// Give up an entry so that other workers can acquire it immediately.
+#[derive(Debug, Copy, Clone)]
+pub struct ReleaseEntryOp {
+    pub info: TaskInfo,
+}
+
+#[async_trait]
+impl CollOperation for ReleaseEntryOp {
+    type Result = bool;
+    type Item = Document;
+
+    const DESC: &'static str = "ReleaseEntry";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
         Ok(collection
             .update_one(
-                doc! {"_id": self.info.doc_id, "uuid": self.info.uuid},
-                doc! {"$set": body},
+                doc! {"_id": self.info.doc_id, "uuid": bson::Uuid::from(self.info.uuid)},
+                vec![
+                    doc! {"$set": {RELEASED_AT_FIELD: server_now()}},
+                    doc! {"$unset": ["uuid", "parent_uuid", "timestamp"]},
+                ],
                 None,
             )
             .await?
@@ -71,6 +165,74 @@ impl<T: Serialize + Send> CollOperation for UpdateEntryOp<T> {
     }
 }
 
+/// Record a failure of the task holding an entry, and release the entry.
+#[derive(Debug, Clone)]
+pub struct RecordFailureOp {
+    pub info: TaskInfo,
+    pub failure: Failure,
+}
+
+#[async_trait]
+impl CollOperation for RecordFailureOp {
+    type Result = Option<u32>;
+    type Item = Document;
+
+    const DESC: &'static str = "RecordFailure";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        let Failure {
+            error,
+            permanent,
+            healthy_after,
+            policy,
+        } = self.failure;
+        let failures = format!("${}", FAILURES_FIELD);
+        // failures are consecutive unless the task has been running for a while
+        let count = doc! {
+            "$set": {
+                FAILURES_FIELD: {"$cond": [
+                    {"$lt": [
+                        {"$subtract": [server_now(), {"$ifNull": [format!("${}", ACQUIRED_AT_FIELD), 0_i64]}]},
+                        healthy_after.as_millis() as i64
+                    ]},
+                    {"$add": [{"$toInt": {"$ifNull": [&failures, 0]}}, 1]},
+                    1
+                ]},
+                LAST_ERROR_FIELD: {"$literal": error},
+            }
+        };
+        let cooldown = doc! {
+            "$set": {
+                RETRY_AT_FIELD: {"$add": [server_now(), {"$toLong": {"$min": [
+                    policy.max_cooldown.as_millis() as i64,
+                    {"$multiply": [
+                        policy.initial_cooldown.as_millis() as i64,
+                        {"$pow": [2_i64, {"$subtract": [&failures, 1]}]}
+                    ]}
+                ]}}]},
+                QUARANTINED_FIELD: if permanent && policy.quarantine_after > 0 {
+                    bson!({"$gte": [&failures, policy.quarantine_after]})
+                } else {
+                    Bson::Boolean(false)
+                },
+            }
+        };
+        let release = doc! {"$unset": ["uuid", "parent_uuid", "timestamp"]};
+
+        Ok(collection
+            .find_one_and_update(
+                doc! {"_id": self.info.doc_id, "uuid": bson::Uuid::from(self.info.uuid)},
+                vec![count, cooldown, release],
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?
+            .and_then(|entry| entry.get(FAILURES_FIELD).and_then(Bson::as_i32))
+            .map(|failures| failures.max(0) as u32))
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ScheduleMode {
@@ -92,11 +254,20 @@ enum ScheduleResult<T> {
     Conflict,
 }
 
-#[derive(Debug, Copy, Clone, Deserialize, Eq, PartialEq)]
-pub struct WorkerInfo {
-    #[serde(rename = "_id", with = "uuid_as_binary")]
-    id: Uuid,
-    count: u64,
+/// Pick tasks worth stealing from a victim worker.
+///
+/// Moving a task is only worth it if the thief stays lighter than the victim was, otherwise the load just bounces back.
+/// `victim_weight` is the total load of the victim, including tasks which can't be stolen.
+pub fn steal_candidates(
+    self_weight: u64,
+    victim_weight: u64,
+    victim_tasks: &[WeightedTask],
+) -> Vec<WeightedTask> {
+    victim_tasks
+        .iter()
+        .filter(|task| self_weight + task.weight < victim_weight)
+        .copied()
+        .collect()
 }
 
 impl Default for ScheduleMode {
@@ -106,28 +277,38 @@ impl Default for ScheduleMode {
 }
 
 #[derive(Debug, Clone)]
-pub struct GetAllTasksCount {
+pub struct GetTotalWeightOp {
     pub base_query: Document,
 }
 
 #[async_trait]
-impl CollOperation for GetAllTasksCount {
+impl CollOperation for GetTotalWeightOp {
     type Result = u64;
     type Item = Document;
 
-    const DESC: &'static str = "GetAllTasksCount";
+    const DESC: &'static str = "GetTotalWeight";
 
     async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
-        collection
-            .count_documents(self.base_query.clone(), None)
-            .await
+        let sum_weight = doc! {
+            "$group": {
+                "_id": null, "weight": {"$sum": weight_expr()}
+            }
+        };
+        Ok(collection
+            .aggregate([doc! {"$match": self.base_query}, sum_weight], None)
+            .await?
+            .try_next()
+            .await?
+            .and_then(|doc| doc.get("weight").and_then(Bson::as_i64))
+            .map_or(0, |weight| weight.max(0) as u64))
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct GetWorkerInfoOp {
     pub base_query: Document,
-    pub since_ts: i64,
+    /// Workers are alive if they refreshed entries within this duration.
+    pub ago: Duration,
     pub parent_id: Uuid,
 }
 
@@ -142,21 +323,21 @@ impl CollOperation for GetWorkerInfoOp {
         let filter_query = doc! {
             "$match": {
                 "$and": [
-                    {"parent_uuid": {"$ne": self.parent_id.to_string()}},   // exclude current worker
-                    {"timestamp": {"$gte": self.since_ts}},   // updated recently
+                    {"parent_uuid": {"$ne": bson::Uuid::from(self.parent_id)}},   // exclude current worker
+                    fresh_filter(self.ago),   // updated recently
                     self.base_query.clone()
                 ]
             }
         };
         let count_parent_uuid = doc! {
             "$group": {
-                "_id": "$parent_uuid", "count": {"$sum": 1}
+                "_id": "$parent_uuid", "count": {"$sum": 1}, "weight": {"$sum": weight_expr()}
             }
         };
         collection
             .aggregate([filter_query, count_parent_uuid], None)
             .await?
-            .map(|doc| doc.map(|doc| -> WorkerInfo { bson::from_document(doc).unwrap() }))
+            .map(|doc| doc.and_then(|doc| Ok(bson::from_document::<WorkerInfo>(doc)?)))
             .try_collect()
             .await
     }
@@ -165,216 +346,238 @@ impl CollOperation for GetWorkerInfoOp {
 #[derive(Debug, Clone)]
 pub struct GetTasksOnWorkerOp {
     pub base_query: Document,
-    pub since_ts: i64,
+    pub ago: Duration,
     pub worker: Uuid,
 }
 
 #[async_trait]
 impl CollOperation for GetTasksOnWorkerOp {
-    type Result = Vec<TaskInfo>;
-    type Item = TaskInfo;
+    type Result = Vec<WeightedTask>;
+    type Item = Document;
 
     const DESC: &'static str = "GetTasksOnWorker";
 
     async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
         let filter_query = doc! {
-            "$and": [
-                {"parent_uuid": self.worker.to_string()},   // select worker
-                {"timestamp": {"$gte": self.since_ts}},   // updated recently
-                self.base_query.clone()
-            ]
+            "$match": {
+                "$and": [
+                    {"parent_uuid": bson::Uuid::from(self.worker)},   // select worker
+                    fresh_filter(self.ago),   // updated recently
+                    self.base_query.clone()
+                ]
+            }
+        };
+        let project_weight = doc! {
+            "$project": {
+                "uuid": 1, "parent_uuid": 1, "weight": weight_expr()
+            }
         };
         collection
-            .find(filter_query, None)
+            .aggregate([filter_query, project_weight], None)
             .await?
+            .map(|doc| doc.and_then(|doc| Ok(bson::from_document::<WeightedTask>(doc)?)))
             .try_collect()
             .await
     }
 }
 
+/// Ownership fields set on an entry when it's acquired.
+///
+/// The lease epoch is bumped on every acquire or steal, and serves as the fencing token of the new owner.
+fn acquire_update(lease: Lease) -> Vec<Document> {
+    vec![doc! {
+        "$set": {
+            "timestamp": server_now(),
+            "uuid": bson::Uuid::from(lease.uuid),
+            "parent_uuid": bson::Uuid::from(lease.parent_uuid),
+            "epoch": {"$add": [{"$toLong": {"$ifNull": ["$epoch", 0_i64]}}, 1_i64]},
+            ACQUIRED_AT_FIELD: server_now()
+        }
+    }]
+}
+
+#[derive(Debug, Clone)]
+pub struct AcquireOutdatedOp {
+    pub query: Document,
+    pub ago: Duration,
+    pub lease: Lease,
+}
+
+#[async_trait]
+impl CollOperation for AcquireOutdatedOp {
+    type Result = Option<Document>;
+    type Item = Document;
+
+    const DESC: &'static str = "AcquireOutdated";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .find_one_and_update(
+                doc! {"$and": [self.query, outdated_filter(self.ago), cooled_down_filter()]},
+                acquire_update(self.lease),
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct StealOp {
+    pub victim: TaskInfo,
+    pub lease: Lease,
+}
+
+#[async_trait]
+impl CollOperation for StealOp {
+    type Result = Option<Document>;
+    type Item = Document;
+
+    const DESC: &'static str = "Steal";
+
+    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
+        collection
+            .find_one_and_update(
+                bson::to_document(&self.victim)?,
+                acquire_update(self.lease),
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ScheduleOp<T> {
     mode: ScheduleMode,
     query: Document,
-    update: Document,
-    since_ts: i64,
+    /// Entries preferring this instance, or without preference.
+    preferred: Document,
+    lease: Lease,
+    ago: Duration,
+    /// Entries preferring other instances are acquired only if they are outdated for this long.
+    fallback_ago: Duration,
     parent_meta: SchedulerMeta,
     __marker: PhantomData<T>,
 }
 
-impl<T> ScheduleOp<T> {
+impl<T: DeserializeOwned> ScheduleOp<T> {
     pub fn new(
         mode: ScheduleMode,
         query: Document,
+        labels: &[String],
         parent_meta: SchedulerMeta,
         ago: Duration,
     ) -> Self {
-        let since_ts = timestamp(SystemTime::now() - ago);
-        let now_ts = timestamp(SystemTime::now());
-        let uuid = Uuid::new_v4();
-        let update = doc! {
-            "$set": {
-                "timestamp": now_ts,
-                "uuid": bson::Uuid::from(uuid),
-                "parent_uuid": bson::Uuid::from(parent_meta.id)
-            }
-        };
         Self {
             mode,
             query,
-            update,
-            since_ts,
+            preferred: preferred_filter(labels),
+            lease: Lease {
+                uuid: Uuid::new_v4(),
+                parent_uuid: parent_meta.id,
+            },
+            ago,
+            fallback_ago: ago * 2,
             parent_meta,
             __marker: PhantomData::default(),
         }
     }
 
     // Try to acquire an outdated entry.
-    async fn do_acquire(&self, collection: &Collection<Document>) -> DBResult<Option<Document>> {
-        let mut query = self.query.clone();
-        query.insert(
-            "$or",
-            bson!([
-                {
-                    "timestamp": {
-                        "$lt": self.since_ts
-                    }
-                },
-                {
-                    "timestamp": {
-                        "$exists": false
-                    }
-                }
-            ]),
-        );
-        collection
-            .find_one_and_update(
-                query,
-                self.update.clone(),
-                Some(
-                    FindOneAndUpdateOptions::builder()
-                        .return_document(ReturnDocument::After)
-                        .build(),
-                ),
-            )
-            .await
-    }
-
-    async fn do_schedule_once(
-        &self,
-        collection: &Collection<Document>,
-    ) -> DBResult<ScheduleResult<Document>> {
-        let entries_count = GetAllTasksCount {
-            base_query: self.query.clone(),
-        }
-        .execute(collection)
-        .await?;
-        let workers = GetWorkerInfoOp {
-            base_query: self.query.clone(),
-            since_ts: self.since_ts,
-            parent_id: self.parent_meta.id,
+    // Entries preferring other instances are left to them for another interval before we take over.
+    async fn do_acquire(&self, store: &dyn LeaseStore) -> DBResult<Option<Document>> {
+        let preferred = doc! {"$and": [self.query.clone(), self.preferred.clone()]};
+        for (query, ago) in [(&preferred, self.ago), (&self.query, self.fallback_ago)] {
+            let acquired = store.acquire_outdated(query, ago, self.lease).await?;
+            if acquired.is_some() {
+                return Ok(acquired);
+            }
         }
-        .execute(collection)
-        .await?;
-
-        // allowed entries per worker is [expected, expected+1].
-        let self_count = self.parent_meta.actor_count as u64;
-        let workers_count = workers.len() as u64;
-        let expected = entries_count / (workers_count + 1);
-        let threshold = if self_count < expected {
-            // If we are underloaded (self < expected), we may steal from those workers.
-            expected
-        } else {
-            // Those are the overloaded ones, steal them if (self == expected).
-            expected + 1
-        };
-
-        let victim_worker = if self_count <= expected {
-            workers
-                .into_iter()
-                .filter(|worker| worker.count > threshold)
-                .collect::<Vec<_>>()
-                .choose(&mut rand::thread_rng())
-                .copied()
-        } else {
-            None
-        };
+        Ok(None)
+    }
 
-        Ok(if let Some(victim_worker) = victim_worker {
-            let tasks = GetTasksOnWorkerOp {
-                base_query: self.query.clone(),
-                since_ts: self.since_ts,
-                worker: victim_worker.id,
-            }
-            .execute(collection)
+    async fn do_schedule_once(&self, store: &dyn LeaseStore) -> DBResult<ScheduleResult<Document>> {
+        let total_weight = store.total_weight(&self.query).await?;
+        let workers = store
+            .workers(&self.query, self.ago, self.parent_meta.id)
             .await?;
+        let self_weight: u64 = store
+            .tasks_on_worker(&self.query, self.ago, self.parent_meta.id)
+            .await?
+            .iter()
+            .map(|task| task.weight)
+            .sum();
 
-            if tasks.len() as u64 > threshold {
-                info!("steal one entry");
-                let victim_task = tasks.choose(&mut rand::thread_rng()).unwrap();
-                collection
-                    .find_one_and_update(
-                        bson::to_document(victim_task).unwrap(),
-                        self.update.clone(),
-                        FindOneAndUpdateOptions::builder()
-                            .return_document(ReturnDocument::After)
-                            .build(),
-                    )
+        // We may steal from workers heavier than the average if we are not.
+        let expected = total_weight / (workers.len() as u64 + 1);
+        if self_weight > expected {
+            // no need to steal anything
+            return Ok(ScheduleResult::None);
+        }
+        let mut victims: Vec<_> = workers
+            .into_iter()
+            .filter(|worker| worker.weight > expected && worker.weight > self_weight)
+            .collect();
+        victims.shuffle(&mut rand::thread_rng());
+
+        // don't steal tasks preferring other instances
+        let stealable = doc! {"$and": [self.query.clone(), self.preferred.clone()]};
+        for victim_worker in victims {
+            let tasks = store
+                .tasks_on_worker(&stealable, self.ago, victim_worker.id)
+                .await?;
+
+            // the victim may hold a single heavy task which can't be moved without overloading us
+            let victim_task = steal_candidates(self_weight, victim_worker.weight, &tasks)
+                .choose(&mut rand::thread_rng())
+                .copied();
+            if let Some(victim_task) = victim_task {
+                info!("steal one entry of weight {}", victim_task.weight);
+                return Ok(store
+                    .steal(victim_task.info(), self.lease)
                     .await?
-                    .map_or(ScheduleResult::Conflict, ScheduleResult::Some)
-            } else {
-                // target worker has insufficient tasks, indicating a steal conflict
-                ScheduleResult::Conflict
+                    // the task has been taken by someone else, indicating a steal conflict
+                    .map_or(ScheduleResult::Conflict, ScheduleResult::Some));
             }
-        } else {
-            // no need to steal anything
-            ScheduleResult::None
-        })
+        }
+        Ok(ScheduleResult::None)
     }
-}
-
-#[async_trait]
-impl<T: DeserializeOwned + Send + Sync> CollOperation for ScheduleOp<T> {
-    type Result = Option<(TaskInfo, T)>;
-    type Item = Document;
 
-    const DESC: &'static str = "Schedule";
-
-    async fn execute_impl(self, collection: &Collection<Self::Item>) -> DBResult<Self::Result> {
-        Ok(match self.mode {
+    /// Acquire an outdated entry, or steal one from other workers, depending on the mode.
+    ///
+    /// # Errors
+    /// Pass errors raised by the lease store.
+    pub async fn execute(self, store: &dyn LeaseStore) -> DBResult<Option<(TaskInfo, T)>> {
+        let res = match self.mode {
             ScheduleMode::Auto => {
-                if let Some(res) = self.do_acquire(collection).await? {
+                if let Some(res) = self.do_acquire(store).await? {
                     Some(res)
                 } else {
-                    loop {
-                        match self.do_schedule_once(collection).await? {
-                            ScheduleResult::Conflict => {
-                                warn!("steal conflict, retry");
-                                continue;
-                            }
-                            ScheduleResult::Some(res) => break Some(res),
-                            ScheduleResult::None => break None,
-                        }
-                    }
+                    self.do_steal(store).await?
                 }
             }
-            ScheduleMode::OutdatedOnly => self.do_acquire(collection).await?,
-            ScheduleMode::StealOnly => loop {
-                match self.do_schedule_once(collection).await? {
-                    ScheduleResult::Conflict => {
-                        warn!("steal conflict, retry");
-                        continue;
-                    }
-                    ScheduleResult::Some(res) => break Some(res),
-                    ScheduleResult::None => break None,
+            ScheduleMode::OutdatedOnly => self.do_acquire(store).await?,
+            ScheduleMode::StealOnly => self.do_steal(store).await?,
+        };
+        Ok(match res {
+            Some(res) => Some((bson::from_document(res.clone())?, bson::from_document(res)?)),
+            None => None,
+        })
+    }
+
+    async fn do_steal(&self, store: &dyn LeaseStore) -> DBResult<Option<Document>> {
+        loop {
+            match self.do_schedule_once(store).await? {
+                ScheduleResult::Conflict => {
+                    warn!("steal conflict, retry");
+                    continue;
                 }
-            },
+                ScheduleResult::Some(res) => break Ok(Some(res)),
+                ScheduleResult::None => break Ok(None),
+            }
         }
-        .map(|res| {
-            (
-                bson::from_document(res.clone()).unwrap(),
-                bson::from_document(res).unwrap(),
-            )
-        }))
     }
 }
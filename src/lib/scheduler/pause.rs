@@ -0,0 +1,14 @@
+//! Paused entries.
+//!
+//! An entry with `paused: true` is neither acquired nor renewed, so its owner stops it at the next renewal.
+//! Other fields of the entry, e.g. persisted task state, are kept untouched while paused.
+
+use mongodb::bson::{doc, Document};
+
+/// Field marking an entry as paused.
+pub const PAUSED_FIELD: &str = "paused";
+
+/// Match entries which aren't paused.
+pub fn active_filter() -> Document {
+    doc! {PAUSED_FIELD: {"$ne": true}}
+}
@@ -0,0 +1,30 @@
+//! Placement constraints of entries.
+//!
+//! An entry may carry a `placement` field like `{"required": ["cn"], "preferred": ["cn-sh"]}`.
+//! It only runs on instances having all `required` labels,
+//! and instances having any of the `preferred` labels get the first chance to acquire it.
+
+use mongodb::bson::{doc, Document};
+
+/// Field of placement constraints in entries.
+pub const PLACEMENT_FIELD: &str = "placement";
+
+/// Match entries whose required labels are all in given labels.
+pub fn required_filter(labels: &[String]) -> Document {
+    // no required label is missing from ours
+    doc! {
+        format!("{}.required", PLACEMENT_FIELD): {"$not": {"$elemMatch": {"$nin": labels}}}
+    }
+}
+
+/// Match entries without preferred labels, or preferring any of given labels.
+pub fn preferred_filter(labels: &[String]) -> Document {
+    let field = format!("{}.preferred", PLACEMENT_FIELD);
+    doc! {
+        "$or": [
+            {&field: {"$exists": false}},
+            {&field: {"$size": 0}},
+            {&field: {"$in": labels}},
+        ]
+    }
+}
@@ -0,0 +1,43 @@
+//! Crash-loop detection.
+//!
+//! Tasks stopping abnormally report their failures, which are recorded in their entries along with the last error.
+//! A failed entry is released, but can't be acquired again until its cool-down ends at `retry_at`.
+//! Entries failing too many times in a row are quarantined until cleared manually.
+
+use std::time::Duration;
+
+use mongodb::bson::{doc, Document};
+
+use crate::config::CrashLoopConfig;
+
+/// Field of the time an entry is acquired, in milliseconds.
+pub const ACQUIRED_AT_FIELD: &str = "acquired_at";
+/// Field of the count of consecutive failures.
+pub const FAILURES_FIELD: &str = "failures";
+/// Field of the error of the last failure.
+pub const LAST_ERROR_FIELD: &str = "last_error";
+/// Field of the time before which a failed entry isn't acquired, in milliseconds.
+pub const RETRY_AT_FIELD: &str = "retry_at";
+/// Field marking an entry as quarantined.
+pub const QUARANTINED_FIELD: &str = "quarantined";
+
+/// Match entries which aren't quarantined.
+pub fn unquarantined_filter() -> Document {
+    doc! {QUARANTINED_FIELD: {"$ne": true}}
+}
+
+/// Cool-down after given count of consecutive failures.
+pub fn cooldown(failures: u32, config: &CrashLoopConfig) -> Duration {
+    let factor = 2_u32.saturating_pow(failures.saturating_sub(1));
+    config
+        .initial_cooldown
+        .checked_mul(factor)
+        .map_or(config.max_cooldown, |cooldown| {
+            cooldown.min(config.max_cooldown)
+        })
+}
+
+/// Whether an entry is quarantined after given count of consecutive failures.
+pub const fn is_quarantined(failures: u32, config: &CrashLoopConfig) -> bool {
+    config.quarantine_after > 0 && failures >= config.quarantine_after
+}
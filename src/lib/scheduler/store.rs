@@ -0,0 +1,185 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hmap_serde::Labelled;
+use mongodb::Database;
+use uuid::Uuid;
+
+use crate::db::{CollOperation, Collection, DBResult, Document};
+use crate::{LeaseStoreKind, ScheduleConfig};
+
+use super::memory::MemoryLeaseStore;
+use super::Task;
+
+use super::models::{Failure, Lease, TaskInfo, WeightedTask, WorkerInfo};
+use super::ops::{
+    AcquireOutdatedOp, CheckOwnershipOp, GetTasksOnWorkerOp, GetTotalWeightOp, GetWorkerInfoOp,
+    RecordFailureOp, ReleaseEntryOp, StealOp, UpdateEntryOp,
+};
+
+/// Storage of schedule entries and their leases.
+///
+/// Queries are mongodb filters on entry docs.
+#[async_trait]
+pub trait LeaseStore: Debug + Send + Sync {
+    /// Acquire an entry matching given query, which isn't renewed within given duration.
+    async fn acquire_outdated(
+        &self,
+        query: &Document,
+        ago: Duration,
+        lease: Lease,
+    ) -> DBResult<Option<Document>>;
+    /// Take over an entry from its owner. Returns `None` if the entry isn't held by the victim anymore.
+    async fn steal(&self, victim: TaskInfo, lease: Lease) -> DBResult<Option<Document>>;
+    /// Renew the lease of an entry, setting given fields and reported weight.
+    ///
+    /// Returns `false` if the entry isn't held by given task anymore, or is paused.
+    async fn renew(
+        &self,
+        info: TaskInfo,
+        body: Option<Document>,
+        weight: Option<u64>,
+    ) -> DBResult<bool>;
+    /// Give up an entry so that others can acquire it immediately.
+    async fn release(&self, info: TaskInfo) -> DBResult<bool>;
+    /// Record a failure of given task and release its entry, applying cool-down and quarantine.
+    ///
+    /// Returns the count of consecutive failures, or `None` if the entry isn't held by given task anymore.
+    async fn record_failure(&self, info: TaskInfo, failure: Failure) -> DBResult<Option<u32>>;
+    /// Check whether an entry is still held by given task.
+    async fn check_ownership(&self, info: TaskInfo) -> DBResult<bool>;
+    /// Total weight of entries matching given query.
+    async fn total_weight(&self, query: &Document) -> DBResult<u64>;
+    /// Workers other than given one holding entries renewed within given duration.
+    async fn workers(
+        &self,
+        query: &Document,
+        ago: Duration,
+        exclude: Uuid,
+    ) -> DBResult<Vec<WorkerInfo>>;
+    /// Entries held by given worker and renewed within given duration.
+    async fn tasks_on_worker(
+        &self,
+        query: &Document,
+        ago: Duration,
+        worker: Uuid,
+    ) -> DBResult<Vec<WeightedTask>>;
+}
+
+/// Open the lease store of given task as configured.
+///
+/// A memory store mirrors entries from the source collection in the background.
+pub fn open_store<T: Task>(db: &Database, config: &ScheduleConfig) -> Arc<dyn LeaseStore> {
+    let collection = db.collection::<Document>(T::Entry::KEY);
+    match config.lease_store {
+        LeaseStoreKind::Mongo => Arc::new(MongoLeaseStore::new(collection)),
+        LeaseStoreKind::Memory => {
+            let store = Arc::new(MemoryLeaseStore::new());
+            actix::spawn(store.clone().mirror(
+                collection,
+                T::state_fields(),
+                config.schedule_interval,
+            ));
+            store
+        }
+    }
+}
+
+/// Leases stored in the source collection in mongodb.
+#[derive(Debug, Clone)]
+pub struct MongoLeaseStore {
+    collection: Collection<Document>,
+}
+
+impl MongoLeaseStore {
+    pub const fn new(collection: Collection<Document>) -> Self {
+        Self { collection }
+    }
+}
+
+#[async_trait]
+impl LeaseStore for MongoLeaseStore {
+    async fn acquire_outdated(
+        &self,
+        query: &Document,
+        ago: Duration,
+        lease: Lease,
+    ) -> DBResult<Option<Document>> {
+        AcquireOutdatedOp {
+            query: query.clone(),
+            ago,
+            lease,
+        }
+        .execute(&self.collection)
+        .await
+    }
+
+    async fn steal(&self, victim: TaskInfo, lease: Lease) -> DBResult<Option<Document>> {
+        StealOp { victim, lease }.execute(&self.collection).await
+    }
+
+    async fn renew(
+        &self,
+        info: TaskInfo,
+        body: Option<Document>,
+        weight: Option<u64>,
+    ) -> DBResult<bool> {
+        UpdateEntryOp { info, body, weight }
+            .execute(&self.collection)
+            .await
+    }
+
+    async fn release(&self, info: TaskInfo) -> DBResult<bool> {
+        ReleaseEntryOp { info }.execute(&self.collection).await
+    }
+
+    async fn record_failure(&self, info: TaskInfo, failure: Failure) -> DBResult<Option<u32>> {
+        RecordFailureOp { info, failure }
+            .execute(&self.collection)
+            .await
+    }
+
+    async fn check_ownership(&self, info: TaskInfo) -> DBResult<bool> {
+        CheckOwnershipOp { info }.execute(&self.collection).await
+    }
+
+    async fn total_weight(&self, query: &Document) -> DBResult<u64> {
+        GetTotalWeightOp {
+            base_query: query.clone(),
+        }
+        .execute(&self.collection)
+        .await
+    }
+
+    async fn workers(
+        &self,
+        query: &Document,
+        ago: Duration,
+        exclude: Uuid,
+    ) -> DBResult<Vec<WorkerInfo>> {
+        GetWorkerInfoOp {
+            base_query: query.clone(),
+            ago,
+            parent_id: exclude,
+        }
+        .execute(&self.collection)
+        .await
+    }
+
+    async fn tasks_on_worker(
+        &self,
+        query: &Document,
+        ago: Duration,
+        worker: Uuid,
+    ) -> DBResult<Vec<WeightedTask>> {
+        GetTasksOnWorkerOp {
+            base_query: query.clone(),
+            ago,
+            worker,
+        }
+        .execute(&self.collection)
+        .await
+    }
+}
@@ -2,8 +2,9 @@ use std::convert::Infallible;
 use std::fmt::{Display, Formatter};
 use std::mem::MaybeUninit;
 use std::str::FromStr;
+use std::sync::Arc;
 
-use actix::{Actor, Context};
+use actix::{Actor, Addr, Context};
 use actix_signal::SignalHandler;
 use hmap_serde::Labelled;
 use serde::{Deserialize, Serialize};
@@ -12,8 +13,13 @@ use tracing::{info_span, Span};
 use crate::db::Document;
 use crate::scheduler::{Entry, Task, TaskFieldGetter};
 use crate::utils::Scheduler;
+use crate::ScheduleConfig;
 
+use super::driver::ScheduleDriverActor;
+use super::messages::TrySchedule;
 use super::models::TaskInfo;
+use super::ops::ScheduleMode;
+use super::{MemoryLeaseStore, ScheduleActor};
 
 #[derive(Debug, Clone, SignalHandler)]
 struct DummyTask {
@@ -53,16 +59,16 @@ impl Task for DummyTask {
     type Ctor = ();
 
     fn query() -> Document {
-        unreachable!()
+        Document::new()
     }
 
     fn construct(
         _entry: Entry<Self::Entry>,
         _ctor: Self::Ctor,
-        _scheduler: Scheduler<Self>,
-        _info: TaskInfo,
+        scheduler: Scheduler<Self>,
+        info: TaskInfo,
     ) -> Self {
-        unreachable!()
+        Self { info, scheduler }
     }
 
     fn span(&self) -> Span {
@@ -70,6 +76,32 @@ impl Task for DummyTask {
     }
 }
 
+/// Start a scheduler of dummy tasks on given store, with a driver of its own.
+fn start_scheduler(
+    store: &Arc<MemoryLeaseStore>,
+    config: ScheduleConfig,
+) -> Addr<ScheduleActor<DummyTask>> {
+    let driver = ScheduleDriverActor::new(config).start();
+    ScheduleActor::<DummyTask>::builder()
+        .store(store.clone())
+        .ctor_builder(|| ())
+        .config(config)
+        .driver(driver)
+        .build()
+        .start()
+}
+
+/// Try to acquire an entry, returning its lease and the started task.
+async fn try_schedule(
+    scheduler: &Addr<ScheduleActor<DummyTask>>,
+) -> Option<(TaskInfo, Addr<DummyTask>)> {
+    scheduler
+        .send(TrySchedule::new(ScheduleMode::Auto))
+        .await
+        .unwrap()
+        .unwrap()
+}
+
 #[test]
 fn must_task_impl_getter() {
     fn _accept_getter<T: TaskFieldGetter>(_t: &MaybeUninit<T>) {}
@@ -77,3 +109,606 @@ fn must_task_impl_getter() {
     let dummy: MaybeUninit<DummyTask> = MaybeUninit::uninit();
     _accept_getter(&dummy);
 }
+
+#[test]
+fn must_steal_only_worthy_tasks() {
+    use mongodb::bson::oid::ObjectId;
+    use uuid::Uuid;
+
+    use super::models::WeightedTask;
+    use super::ops::steal_candidates;
+
+    let victim = Uuid::new_v4();
+    let task = |weight: u64| WeightedTask {
+        doc_id: ObjectId::new(),
+        uuid: Uuid::new_v4(),
+        parent_uuid: victim,
+        weight,
+    };
+
+    // unweighted tasks behave like plain task counts
+    let tasks = vec![task(1), task(1), task(1), task(1)];
+    assert_eq!(steal_candidates(2, 4, &tasks).len(), 4);
+    assert!(steal_candidates(3, 4, &tasks).is_empty());
+
+    // a single heavy task is never moved
+    assert!(steal_candidates(0, 100, &[task(100)]).is_empty());
+
+    // only tasks light enough to keep us below the victim are stolen
+    let tasks = vec![task(50), task(10), task(5)];
+    let candidates = steal_candidates(40, 65, &tasks);
+    assert_eq!(
+        candidates
+            .iter()
+            .map(|task| task.weight)
+            .collect::<Vec<_>>(),
+        vec![10, 5]
+    );
+
+    // tasks preferring other instances can't be stolen, but still weigh on the victim
+    let stealable = vec![task(5)];
+    assert!(steal_candidates(10, 5, &stealable).is_empty());
+    assert_eq!(steal_candidates(10, 100, &stealable).len(), 1);
+}
+
+#[test]
+fn must_build_placement_filters() {
+    use mongodb::bson::doc;
+
+    use super::placement::{preferred_filter, required_filter};
+
+    let labels = vec![String::from("cn")];
+    assert_eq!(
+        required_filter(&labels),
+        doc! {"placement.required": {"$not": {"$elemMatch": {"$nin": ["cn"]}}}}
+    );
+    assert_eq!(
+        preferred_filter(&labels),
+        doc! {
+            "$or": [
+                {"placement.preferred": {"$exists": false}},
+                {"placement.preferred": {"$size": 0}},
+                {"placement.preferred": {"$in": ["cn"]}},
+            ]
+        }
+    );
+}
+
+#[test]
+fn must_parse_entry_changes() {
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+
+    use super::watch::{parse_change, EntryChange};
+
+    let id = ObjectId::new();
+    let update = |updated: Document, removed: Vec<&str>| {
+        doc! {
+            "operationType": "update",
+            "documentKey": {"_id": id},
+            "updateDescription": {"updatedFields": updated, "removedFields": removed},
+        }
+    };
+
+    assert_eq!(
+        parse_change(
+            &doc! {"operationType": "insert", "documentKey": {"_id": id}},
+            &[]
+        ),
+        Some(EntryChange::Available)
+    );
+    assert_eq!(
+        parse_change(
+            &doc! {"operationType": "delete", "documentKey": {"_id": id}},
+            &[]
+        ),
+        Some(EntryChange::Deleted(id))
+    );
+    // released by the owner
+    assert_eq!(
+        parse_change(&update(doc! {}, vec!["uuid", "timestamp"]), &[]),
+        Some(EntryChange::Available)
+    );
+    assert_eq!(
+        parse_change(&update(doc! {"released_at": 1_i64}, vec![]), &[]),
+        Some(EntryChange::Available)
+    );
+    // let out of quarantine
+    assert_eq!(
+        parse_change(
+            &update(doc! {}, vec!["quarantined", "failures", "retry_at"]),
+            &[]
+        ),
+        Some(EntryChange::Available)
+    );
+    // heartbeats and task states are ignored
+    assert_eq!(
+        parse_change(
+            &update(doc! {"timestamp": 1_i64, "since": 2_i64}, vec![]),
+            &["since"]
+        ),
+        None
+    );
+    // so are acquisitions
+    assert_eq!(
+        parse_change(
+            &update(
+                doc! {"timestamp": 1_i64, "epoch": 2_i64, "acquired_at": 1_i64},
+                vec![]
+            ),
+            &[]
+        ),
+        None
+    );
+    assert_eq!(
+        parse_change(
+            &update(doc! {"timestamp": 1_i64, "uid": 2_i64}, vec![]),
+            &["since"]
+        ),
+        Some(EntryChange::Modified(id))
+    );
+}
+
+#[test]
+fn must_not_filter_ownership_by_epoch() {
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::bson::{self, doc};
+    use uuid::Uuid;
+
+    let id = ObjectId::new();
+    let (uuid, parent_uuid) = (Uuid::new_v4(), Uuid::new_v4());
+    let acquired = doc! {
+        "_id": id,
+        "uuid": bson::Uuid::from(uuid),
+        "parent_uuid": bson::Uuid::from(parent_uuid),
+        "epoch": 7_i64,
+    };
+    let info: TaskInfo = bson::from_document(acquired).unwrap();
+    assert_eq!(info.epoch, 7);
+    assert!(!bson::to_document(&info).unwrap().contains_key("epoch"));
+}
+
+#[test]
+fn must_match_queries_in_memory() {
+    use mongodb::bson::doc;
+
+    use super::memory::{matches, validate};
+    use super::placement::{preferred_filter, required_filter};
+
+    let is_match = |entry: &Document, query: &Document| {
+        validate(query).unwrap();
+        matches(entry, query)
+    };
+    let entry =
+        doc! {"name": "a", "weight": 3, "placement": {"required": ["cn"], "preferred": ["hk"]}};
+    let (cn, jp) = (vec![String::from("cn")], vec![String::from("jp")]);
+    assert!(is_match(&entry, &doc! {}));
+    assert!(is_match(
+        &entry,
+        &doc! {"name": "a", "weight": {"$gte": 3_i64}}
+    ));
+    assert!(!is_match(
+        &entry,
+        &doc! {"$or": [{"name": "b"}, {"weight": {"$lt": 3}}]}
+    ));
+    assert!(is_match(&entry, &required_filter(&cn)));
+    assert!(!is_match(&entry, &required_filter(&jp)));
+    assert!(!is_match(&entry, &preferred_filter(&cn)));
+    assert!(is_match(&doc! {}, &required_filter(&[])));
+    assert!(is_match(&doc! {}, &preferred_filter(&jp)));
+
+    // unsupported operators are rejected instead of never matching
+    assert!(validate(&doc! {"$expr": {"$gt": ["$weight", 1]}}).is_err());
+    assert!(validate(&doc! {"name": {"$regex": "^a"}}).is_err());
+    assert!(validate(&doc! {"$or": [{"name": {"$not": {"$mod": [2, 0]}}}]}).is_err());
+}
+
+#[actix::test]
+async fn must_schedule_without_database() {
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+
+    use super::messages::CheckOwnership;
+    use super::LeaseStore;
+
+    let store = Arc::new(MemoryLeaseStore::new());
+    let id = store.insert(doc! {"root": {"$ref": "vtubers", "$id": ObjectId::new()}});
+
+    let scheduler = start_scheduler(&store, ScheduleConfig::default());
+    let try_schedule = || async { try_schedule(&scheduler).await.map(|(info, _)| info) };
+    let check = |info| {
+        let scheduler = scheduler.clone();
+        async move {
+            scheduler
+                .send(CheckOwnership { info })
+                .await
+                .unwrap()
+                .unwrap()
+        }
+    };
+
+    let info = try_schedule().await.expect("entry not acquired");
+    assert_eq!((info.doc_id, info.epoch), (id, 1));
+    assert!(check(info).await);
+    assert!(try_schedule().await.is_none(), "entry acquired twice");
+
+    // a released entry is acquired again by a new lease
+    assert!(store.release(info).await.unwrap());
+    assert!(!check(info).await);
+    let info = try_schedule().await.expect("entry not acquired");
+    assert_eq!(info.epoch, 2);
+    assert!(check(info).await);
+}
+
+#[actix::test]
+async fn must_stop_paused_entries() {
+    use std::time::Duration;
+
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+
+    use super::messages::UpdateAll;
+    use super::pause::PAUSED_FIELD;
+    use super::LeaseStore;
+
+    let store = Arc::new(MemoryLeaseStore::new());
+    let id = store.insert(doc! {"root": {"$ref": "vtubers", "$id": ObjectId::new()}, "since": 42});
+    let set_paused = |paused: bool| {
+        let mut entry = store.get(id).unwrap();
+        if paused {
+            entry.insert(PAUSED_FIELD, true);
+        } else {
+            entry.remove(PAUSED_FIELD);
+        }
+        store.insert(entry);
+    };
+
+    let scheduler = start_scheduler(&store, ScheduleConfig::default());
+
+    let (info, addr) = try_schedule(&scheduler).await.expect("entry not acquired");
+
+    // the owner stops its task once the entry is paused
+    set_paused(true);
+    scheduler.send(UpdateAll { evict: true }).await.unwrap();
+    actix::clock::sleep(Duration::from_millis(100)).await;
+    assert!(!addr.connected(), "paused task still running");
+    assert!(!store.renew(info, None, None).await.unwrap());
+
+    // paused entries aren't acquired even if released
+    assert!(store.release(info).await.unwrap());
+    assert!(
+        try_schedule(&scheduler).await.is_none(),
+        "paused entry acquired"
+    );
+
+    // resumed entries are acquired again with their state kept
+    set_paused(false);
+    let (info, _) = try_schedule(&scheduler).await.expect("entry not acquired");
+    assert_eq!(info.doc_id, id);
+    assert_eq!(store.get(id).unwrap().get_i32("since"), Ok(42));
+}
+
+#[test]
+fn must_back_off_failures() {
+    use std::time::Duration;
+
+    use super::quarantine::{cooldown, is_quarantined};
+    use crate::CrashLoopConfig;
+
+    let config = CrashLoopConfig {
+        initial_cooldown: Duration::from_secs(30),
+        max_cooldown: Duration::from_secs(5 * 60),
+        quarantine_after: 3,
+    };
+    assert_eq!(cooldown(1, &config), Duration::from_secs(30));
+    assert_eq!(cooldown(2, &config), Duration::from_secs(60));
+    assert_eq!(cooldown(4, &config), Duration::from_secs(240));
+    assert_eq!(cooldown(5, &config), Duration::from_secs(300));
+    assert_eq!(cooldown(100, &config), Duration::from_secs(300));
+    assert!(!is_quarantined(2, &config));
+    assert!(is_quarantined(3, &config));
+    assert!(!is_quarantined(
+        100,
+        &CrashLoopConfig {
+            quarantine_after: 0,
+            ..config
+        }
+    ));
+}
+
+#[actix::test]
+async fn must_quarantine_crash_loops() {
+    use std::time::Duration;
+
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+
+    use super::messages::ReportFailure;
+    use super::quarantine::{FAILURES_FIELD, LAST_ERROR_FIELD, QUARANTINED_FIELD, RETRY_AT_FIELD};
+    use crate::CrashLoopConfig;
+
+    let store = Arc::new(MemoryLeaseStore::new());
+    let id = store.insert(doc! {"root": {"$ref": "vtubers", "$id": ObjectId::new()}});
+
+    let config = ScheduleConfig {
+        crash_loop: CrashLoopConfig {
+            initial_cooldown: Duration::ZERO,
+            max_cooldown: Duration::ZERO,
+            quarantine_after: 2,
+        },
+        ..ScheduleConfig::default()
+    };
+    let scheduler = start_scheduler(&store, config);
+    let try_schedule = || async { try_schedule(&scheduler).await.map(|(info, _)| info) };
+
+    // a failed entry is released, and acquired again once cooled down
+    let info = try_schedule().await.expect("entry not acquired");
+    scheduler
+        .send(ReportFailure::permanent(info, "bad uid"))
+        .await
+        .unwrap();
+    let entry = store.get(id).unwrap();
+    assert_eq!(entry.get_i32(FAILURES_FIELD), Ok(1));
+    assert_eq!(entry.get_str(LAST_ERROR_FIELD), Ok("bad uid"));
+    assert!(entry.contains_key(RETRY_AT_FIELD));
+    assert!(!entry.contains_key("uuid"));
+
+    // transient failures never put the entry into quarantine
+    let info = try_schedule().await.expect("entry not acquired");
+    scheduler
+        .send(ReportFailure::new(info, "network error"))
+        .await
+        .unwrap();
+    let entry = store.get(id).unwrap();
+    assert_eq!(entry.get_i32(FAILURES_FIELD), Ok(2));
+    assert_eq!(entry.get_bool(QUARANTINED_FIELD), Ok(false));
+
+    // consecutive failures put the entry into quarantine
+    let info = try_schedule().await.expect("entry not acquired");
+    scheduler
+        .send(ReportFailure::permanent(info, "bad uid"))
+        .await
+        .unwrap();
+    let entry = store.get(id).unwrap();
+    assert_eq!(entry.get_i32(FAILURES_FIELD), Ok(3));
+    assert_eq!(entry.get_bool(QUARANTINED_FIELD), Ok(true));
+    assert!(try_schedule().await.is_none(), "quarantined entry acquired");
+}
+
+#[actix::test]
+async fn must_cool_down_failed_entries() {
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+
+    use super::messages::ReportFailure;
+
+    let store = Arc::new(MemoryLeaseStore::new());
+    store.insert(doc! {"root": {"$ref": "vtubers", "$id": ObjectId::new()}});
+
+    let scheduler = start_scheduler(&store, ScheduleConfig::default());
+    let try_schedule = || async { try_schedule(&scheduler).await.map(|(info, _)| info) };
+
+    let info = try_schedule().await.expect("entry not acquired");
+    scheduler
+        .send(ReportFailure::new(info, "network error"))
+        .await
+        .unwrap();
+    assert!(
+        try_schedule().await.is_none(),
+        "entry acquired during cool-down"
+    );
+}
+
+#[test]
+fn must_track_task_health() {
+    use super::health::{HealthState, TaskHealth};
+
+    let mut health = TaskHealth::resume(None);
+    assert_eq!(health, TaskHealth::default());
+
+    health.connected();
+    assert_eq!(health.state, HealthState::Active);
+    health.record_event();
+    health.record_event();
+    assert_eq!(health.events, 2);
+    assert!(health.last_event_at.is_some());
+    health.record_error("malformed packet");
+    assert_eq!(health.state, HealthState::Degraded);
+    assert_eq!(health.last_error.as_deref(), Some("malformed packet"));
+
+    // counters are carried over to the next task of the entry
+    let resumed = TaskHealth::resume(Some(&health));
+    assert_eq!(resumed.state, HealthState::Connecting);
+    assert_eq!(resumed.events, 2);
+    assert_eq!(resumed.reconnects, 1);
+    assert_eq!(resumed.last_event_at, health.last_event_at);
+}
+
+#[actix::test]
+async fn must_persist_task_health() {
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::bson::{self, doc};
+
+    use super::health::{TaskHealth, HEALTH_FIELD};
+    use super::messages::UpdateEntry;
+
+    let store = Arc::new(MemoryLeaseStore::new());
+    let id = store.insert(doc! {"root": {"$ref": "vtubers", "$id": ObjectId::new()}});
+
+    let scheduler = start_scheduler(&store, ScheduleConfig::default());
+
+    let (info, _) = try_schedule(&scheduler).await.expect("entry not acquired");
+
+    let mut health = TaskHealth::default();
+    health.connected();
+    health.record_event();
+    assert!(scheduler
+        .send(UpdateEntry::empty_payload(info).health(health.clone()))
+        .await
+        .unwrap()
+        .unwrap());
+
+    let entry = store.get(id).unwrap();
+    let persisted: TaskHealth =
+        bson::from_document(entry.get_document(HEALTH_FIELD).unwrap().clone()).unwrap();
+    assert_eq!(persisted, health);
+    assert!(entry.contains_key("timestamp"));
+}
+
+#[test]
+fn must_sync_memory_store() {
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+
+    use super::pause::PAUSED_FIELD;
+
+    let store = MemoryLeaseStore::new();
+    let (kept, removed) = (ObjectId::new(), ObjectId::new());
+    store.insert(doc! {"_id": kept, "uid": 1, "epoch": 3_i64, "since": 42, PAUSED_FIELD: true});
+    store.insert(doc! {"_id": removed, "uid": 2});
+
+    // the entry is modified and resumed in the database
+    store.sync(vec![doc! {"_id": kept, "uid": 10}], &["since"]);
+    let entry = store.get(kept).unwrap();
+    assert_eq!(entry.get_i32("uid"), Ok(10));
+    assert_eq!(entry.get_i64("epoch"), Ok(3));
+    assert_eq!(entry.get_i32("since"), Ok(42));
+    assert!(!entry.contains_key(PAUSED_FIELD));
+    assert!(store.get(removed).is_none());
+}
+
+#[actix::test]
+async fn must_release_entries_on_drain() {
+    use std::time::Duration;
+
+    use mongodb::bson::doc;
+    use mongodb::bson::oid::ObjectId;
+
+    use super::ops::RELEASED_AT_FIELD;
+    use crate::Drain;
+
+    let store = Arc::new(MemoryLeaseStore::new());
+    let id = store.insert(doc! {"root": {"$ref": "vtubers", "$id": ObjectId::new()}});
+    let draining = start_scheduler(&store, ScheduleConfig::default());
+    let (_, addr) = try_schedule(&draining).await.expect("entry not acquired");
+
+    draining.send(Drain).await.unwrap();
+    actix::clock::sleep(Duration::from_millis(100)).await;
+    assert!(!addr.connected(), "drained task still running");
+    let entry = store.get(id).unwrap();
+    assert!(!entry.contains_key("uuid"));
+    assert!(entry.contains_key(RELEASED_AT_FIELD));
+    assert!(
+        try_schedule(&draining).await.is_none(),
+        "entry acquired while draining"
+    );
+
+    // another instance takes over right away instead of waiting for the lease to expire
+    let other = start_scheduler(&store, ScheduleConfig::default());
+    let (info, _) = try_schedule(&other).await.expect("entry not handed over");
+    assert_eq!(info.doc_id, id);
+}
+
+/// Static weights of entries used by weight tests, and the effective weight of each.
+fn weighted_entries() -> Vec<(Document, u64)> {
+    use mongodb::bson::doc;
+
+    vec![
+        (doc! {"weight": 5_i64}, 5),
+        (doc! {"weight": 2.5}, 2),
+        (doc! {"weight": "3"}, 3),
+        // malformed or missing weights count as 1, and negative ones as 0
+        (doc! {"weight": "heavy"}, 1),
+        (doc! {}, 1),
+        (doc! {"weight": -2_i64}, 0),
+    ]
+}
+
+/// Acquire all entries inserted from `weighted_entries` as a worker, and check weights reported by the store.
+async fn check_weights(store: &dyn super::LeaseStore) {
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use super::Lease;
+
+    let query = Document::new();
+    let ago = Duration::from_secs(60);
+    let expected: u64 = weighted_entries().iter().map(|(_, weight)| weight).sum();
+    assert_eq!(store.total_weight(&query).await.unwrap(), expected);
+
+    let worker = Uuid::new_v4();
+    let lease = || Lease {
+        uuid: Uuid::new_v4(),
+        parent_uuid: worker,
+    };
+    let mut infos = Vec::new();
+    while let Some(entry) = store.acquire_outdated(&query, ago, lease()).await.unwrap() {
+        infos.push(mongodb::bson::from_document::<TaskInfo>(entry).unwrap());
+    }
+    assert_eq!(infos.len(), weighted_entries().len());
+
+    let workers = store.workers(&query, ago, Uuid::new_v4()).await.unwrap();
+    assert_eq!(workers.len(), 1);
+    assert_eq!(
+        (workers[0].id, workers[0].count, workers[0].weight),
+        (worker, infos.len() as u64, expected)
+    );
+    assert!(
+        store.workers(&query, ago, worker).await.unwrap().is_empty(),
+        "excluded worker listed"
+    );
+
+    let tasks = store.tasks_on_worker(&query, ago, worker).await.unwrap();
+    assert_eq!(tasks.len(), infos.len());
+    assert_eq!(tasks.iter().map(|task| task.weight).sum::<u64>(), expected);
+
+    // the weight reported by a task overrides the static one
+    let info = infos[0];
+    let before = tasks
+        .iter()
+        .find(|task| task.doc_id == info.doc_id)
+        .unwrap()
+        .weight;
+    assert!(store.renew(info, None, Some(10)).await.unwrap());
+    let tasks = store.tasks_on_worker(&query, ago, worker).await.unwrap();
+    let task = tasks
+        .iter()
+        .find(|task| task.doc_id == info.doc_id)
+        .unwrap();
+    assert_eq!(task.weight, 10);
+    assert_eq!(
+        store.total_weight(&query).await.unwrap(),
+        expected - before + 10
+    );
+}
+
+#[actix::test]
+async fn must_weigh_entries_in_memory() {
+    let store = MemoryLeaseStore::new();
+    for (entry, _) in weighted_entries() {
+        store.insert(entry);
+    }
+    check_weights(&store).await;
+}
+
+#[actix::test]
+async fn must_weigh_entries_in_mongodb() {
+    use crate::tests::with_db;
+
+    use super::MongoLeaseStore;
+
+    if option_env!("TEST_FAST").is_some() {
+        return;
+    }
+
+    with_db(|db| async move {
+        let collection = db.collection::<Document>("dummy");
+        collection
+            .insert_many(weighted_entries().into_iter().map(|(entry, _)| entry), None)
+            .await
+            .unwrap();
+        check_weights(&MongoLeaseStore::new(collection)).await;
+    })
+    .await;
+}
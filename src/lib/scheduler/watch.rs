@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use actix::{Message, Recipient};
+use futures::StreamExt;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, Document};
+use tracing::{info, warn};
+
+use crate::db::Collection;
+use crate::utils::DBErrorExt;
+
+use super::health::HEALTH_FIELD;
+use super::ops::{RELEASED_AT_FIELD, REPORTED_WEIGHT_FIELD};
+use super::quarantine::{
+    ACQUIRED_AT_FIELD, FAILURES_FIELD, LAST_ERROR_FIELD, QUARANTINED_FIELD, RETRY_AT_FIELD,
+};
+
+/// Error code raised when change streams are opened on a standalone server.
+const CHANGE_STREAM_UNSUPPORTED: i32 = 40573;
+
+/// Error codes meaning the stream can't be resumed from the saved token, e.g. the oplog has rolled over.
+const NON_RESUMABLE_ERRORS: &[i32] = &[
+    260, // InvalidResumeToken
+    280, // ChangeStreamFatalError
+    286, // ChangeStreamHistoryLost
+];
+
+/// Upper bound of the retry interval, as a multiple of the initial one.
+const MAX_BACKOFF_FACTOR: u32 = 32;
+
+/// Fields written by schedulers to acquire and keep entries.
+pub(super) const BOOKKEEPING_FIELDS: &[&str] = &[
+    "timestamp",
+    "uuid",
+    "parent_uuid",
+    "epoch",
+    REPORTED_WEIGHT_FIELD,
+    RELEASED_AT_FIELD,
+    ACQUIRED_AT_FIELD,
+    FAILURES_FIELD,
+    LAST_ERROR_FIELD,
+    RETRY_AT_FIELD,
+    QUARANTINED_FIELD,
+    HEALTH_FIELD,
+];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Message)]
+#[rtype("()")]
+pub enum EntryChange {
+    /// An entry is inserted or released by its owner, and can be acquired now.
+    Available,
+    /// An entry is modified. Its task should be restarted to pick up the change.
+    Modified(ObjectId),
+    /// An entry is deleted. Its task should be stopped.
+    Deleted(ObjectId),
+}
+
+/// Interpret a change event of a source collection.
+///
+/// Updates touching only bookkeeping fields or given state fields written by tasks themselves are ignored.
+pub fn parse_change(event: &Document, state_fields: &[&str]) -> Option<EntryChange> {
+    let doc_id = || {
+        event
+            .get_document("documentKey")
+            .ok()?
+            .get_object_id("_id")
+            .ok()
+    };
+    match event.get_str("operationType").ok()? {
+        "insert" => Some(EntryChange::Available),
+        "replace" => doc_id().map(EntryChange::Modified),
+        "delete" => doc_id().map(EntryChange::Deleted),
+        "update" => {
+            let desc = event.get_document("updateDescription").ok()?;
+            let removed: Vec<&str> = desc
+                .get_array("removedFields")
+                .map(|fields| fields.iter().filter_map(Bson::as_str).collect())
+                .unwrap_or_default();
+            let updated: Vec<&str> = desc
+                .get_document("updatedFields")
+                .map(|fields| fields.keys().map(String::as_str).collect())
+                .unwrap_or_else(|_| Vec::new());
+            // released by its owner, or let out of cool-down or quarantine
+            if updated.contains(&RELEASED_AT_FIELD)
+                || removed
+                    .iter()
+                    .any(|field| ["uuid", RETRY_AT_FIELD, QUARANTINED_FIELD].contains(field))
+            {
+                return Some(EntryChange::Available);
+            }
+            let significant = updated.into_iter().chain(removed).any(|field| {
+                let field = field.split('.').next().unwrap_or(field);
+                !BOOKKEEPING_FIELDS.contains(&field) && !state_fields.contains(&field)
+            });
+            if significant {
+                doc_id().map(EntryChange::Modified)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Watch a source collection and report entry changes.
+///
+/// Returns immediately if the deployment doesn't support change streams, leaving polling as the only way to
+/// discover changes. The stream is reopened if it's interrupted, waiting from `retry_interval` up to
+/// `MAX_BACKOFF_FACTOR` times of it between failed attempts.
+///
+/// If the stream can't be resumed, a new one is opened and an `Available` change is reported, so that entries
+/// changed in between are picked up by a scheduling pass.
+pub async fn watch(
+    collection: Collection<Document>,
+    state_fields: &'static [&'static str],
+    recipient: Recipient<EntryChange>,
+    retry_interval: Duration,
+) {
+    let mut resume_token: Option<Bson> = None;
+    let mut backoff = retry_interval;
+    loop {
+        let mut invalidated = false;
+        let mut stage = Document::new();
+        if let Some(token) = &resume_token {
+            stage.insert("resumeAfter", token.clone());
+        }
+        let error = match collection
+            .aggregate([doc! {"$changeStream": stage}], None)
+            .await
+        {
+            Ok(mut cursor) => {
+                info!("watching changes of {}", collection.name());
+                let mut error = None;
+                while let Some(event) = cursor.next().await {
+                    match event {
+                        Ok(event) if event.get_str("operationType") == Ok("invalidate") => {
+                            // the collection is dropped or renamed, and the stream is closed
+                            warn!("change stream of {} invalidated", collection.name());
+                            invalidated = true;
+                            break;
+                        }
+                        Ok(event) => {
+                            backoff = retry_interval;
+                            resume_token = event.get("_id").cloned();
+                            if let Some(change) = parse_change(&event, state_fields) {
+                                if recipient.send(change).await.is_err() {
+                                    return; // driver stopped
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            warn!("change stream of {} interrupted: {}", collection.name(), e);
+                            error = Some(e);
+                            break;
+                        }
+                    }
+                }
+                error
+            }
+            Err(e) => {
+                if e.command() == Some(CHANGE_STREAM_UNSUPPORTED) {
+                    info!(
+                        "change streams unsupported, polling {} instead",
+                        collection.name()
+                    );
+                    return;
+                }
+                warn!("unable to watch {}: {}", collection.name(), e);
+                Some(e)
+            }
+        };
+        let non_resumable = error
+            .as_ref()
+            .and_then(DBErrorExt::command)
+            .is_some_and(|code| NON_RESUMABLE_ERRORS.contains(&code));
+        if non_resumable || invalidated {
+            // changes in between are missed, so rescan the collection
+            resume_token = None;
+            if recipient.send(EntryChange::Available).await.is_err() {
+                return;
+            }
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(retry_interval * MAX_BACKOFF_FACTOR);
+    }
+}
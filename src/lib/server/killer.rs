@@ -1,10 +1,32 @@
-use actix::{Actor, Context, Handler, Message, Supervised, System, SystemService};
+use std::mem;
+use std::time::Duration;
+
+use actix::{
+    Actor, ActorFutureExt, Context, Handler, Message, Recipient, ResponseActFuture, Supervised,
+    System, SystemService, WrapFuture,
+};
 use actix_web::dev::ServerHandle;
+use tracing::{info, warn};
+
+/// Time allowed for actors to drain before the instance is stopped anyway.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Clone, Message)]
 #[rtype("()")]
 pub struct RegisterHttpServer(pub ServerHandle);
 
+/// Release resources held by an actor, e.g. scheduled tasks, so that other instances can take over.
+///
+/// Sent to registered actors on graceful shutdown.
+#[derive(Debug, Copy, Clone, Message)]
+#[rtype("()")]
+pub struct Drain;
+
+/// Register an actor to be drained on graceful shutdown.
+#[derive(Debug, Clone, Message)]
+#[rtype("()")]
+pub struct RegisterDrain(pub Recipient<Drain>);
+
 #[derive(Debug, Copy, Clone, Message)]
 #[rtype("()")]
 pub struct Kill {
@@ -12,7 +34,10 @@ pub struct Kill {
 }
 
 #[derive(Debug, Default)]
-pub struct KillerActor(Option<ServerHandle>);
+pub struct KillerActor {
+    http_server: Option<ServerHandle>,
+    drains: Vec<Recipient<Drain>>,
+}
 
 impl KillerActor {
     pub fn new() -> Self {
@@ -22,6 +47,14 @@ impl KillerActor {
     pub fn kill(graceful: bool) {
         Self::from_registry().do_send(Kill { graceful });
     }
+
+    fn stop(&self, graceful: bool) {
+        if let Some(http_server) = &self.http_server {
+            drop(http_server.stop(graceful)); // deliberately not awaited
+        } else {
+            System::current().stop();
+        }
+    }
 }
 
 impl Actor for KillerActor {
@@ -36,18 +69,41 @@ impl Handler<RegisterHttpServer> for KillerActor {
     type Result = ();
 
     fn handle(&mut self, msg: RegisterHttpServer, _ctx: &mut Self::Context) -> Self::Result {
-        self.0 = Some(msg.0);
+        self.http_server = Some(msg.0);
     }
 }
 
-impl Handler<Kill> for KillerActor {
+impl Handler<RegisterDrain> for KillerActor {
     type Result = ();
 
+    fn handle(&mut self, msg: RegisterDrain, _ctx: &mut Self::Context) -> Self::Result {
+        self.drains.push(msg.0);
+    }
+}
+
+impl Handler<Kill> for KillerActor {
+    type Result = ResponseActFuture<Self, ()>;
+
     fn handle(&mut self, msg: Kill, _ctx: &mut Self::Context) -> Self::Result {
-        if let Some(http_server) = &self.0 {
-            drop(http_server.stop(msg.graceful)); // deliberately not awaited
+        // Drain only once. A second kill request stops the instance right away.
+        let drains = if msg.graceful {
+            mem::take(&mut self.drains)
         } else {
-            System::current().stop();
+            vec![]
+        };
+        if !drains.is_empty() {
+            info!("draining {} actors before shutdown", drains.len());
         }
+        let drain = futures::future::join_all(drains.iter().map(|drain| drain.send(Drain)));
+        Box::pin(
+            tokio::time::timeout(DRAIN_TIMEOUT, drain)
+                .into_actor(self)
+                .map(move |res, act, _ctx| {
+                    if res.is_err() {
+                        warn!("drain timed out, stopping anyway");
+                    }
+                    act.stop(msg.graceful);
+                }),
+        )
     }
 }
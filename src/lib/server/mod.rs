@@ -13,11 +13,12 @@ use actix_web::App;
 use actix_web::HttpServer;
 use pin_project::pin_project;
 use tokio::sync::mpsc::unbounded_channel;
+use tracing::{error, info};
 use uuid::Uuid;
 
 use handler::ArbiterHandler;
-pub use killer::KillerActor;
 use killer::RegisterHttpServer;
+pub use killer::{Drain, KillerActor, RegisterDrain};
 
 use crate::context::{ArbiterContext, InstanceContext};
 use crate::server::watchdog::WatchdogActor;
@@ -54,6 +55,48 @@ impl Future for ServerHandler {
     }
 }
 
+/// Stop the instance gracefully on the first SIGINT or SIGTERM, and forcefully on the second one or on SIGQUIT.
+async fn kill_on_signal() {
+    for graceful in [true, false] {
+        let quit = match wait_for_signal().await {
+            Ok(quit) => quit,
+            Err(e) => {
+                error!("unable to listen for signals: {}", e);
+                return;
+            }
+        };
+        let graceful = graceful && !quit;
+        info!("signal received, stopping (graceful: {})", graceful);
+        KillerActor::kill(graceful);
+        if !graceful {
+            return;
+        }
+    }
+}
+
+/// Wait for a stop signal. Returns `true` if it asks to quit immediately, i.e. SIGQUIT.
+#[cfg(unix)]
+async fn wait_for_signal() -> std::io::Result<bool> {
+    use futures::future::{select, Either};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigquit = signal(SignalKind::quit())?;
+    let ctrl_c = Box::pin(tokio::signal::ctrl_c());
+    let stop = Box::pin(select(ctrl_c, Box::pin(sigterm.recv())));
+    let res = match select(stop, Box::pin(sigquit.recv())).await {
+        Either::Left((Either::Left((res, _)), _)) => res.map(|_| false),
+        Either::Left((Either::Right(_), _)) => Ok(false),
+        Either::Right(_) => Ok(true),
+    };
+    res
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> std::io::Result<bool> {
+    tokio::signal::ctrl_c().await.map(|_| false)
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum ServerMode {
     NoHTTP,
@@ -145,6 +188,7 @@ where
                 }
 
                 KillerActor::from_registry();
+                actix::spawn(kill_on_signal());
                 Ok(ServerHandler::NoHTTP(ArbiterHandler::new(
                     self.workers,
                     stop_rx,
@@ -164,10 +208,13 @@ where
                         .configure(http_services)
                 })
                 .workers(self.workers)
+                // signals are handled by the killer so that tasks are drained first
+                .disable_signals()
                 .bind(port)?
                 .run();
 
                 KillerActor::from_registry().do_send(RegisterHttpServer(srv.handle()));
+                actix::spawn(kill_on_signal());
                 Ok(ServerHandler::HTTP(srv))
             }
         }
@@ -60,8 +60,25 @@ mod killer {
     use tokio::sync::mpsc::unbounded_channel;
     use tokio::time::{sleep, timeout};
 
+    use actix::{Actor, Context, Handler};
+    use tokio::sync::mpsc::UnboundedSender;
+
     use crate::server::killer::Kill;
-    use crate::KillerActor;
+    use crate::{Drain, KillerActor, RegisterDrain};
+
+    struct Drained(UnboundedSender<()>);
+
+    impl Actor for Drained {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Drain> for Drained {
+        type Result = ();
+
+        fn handle(&mut self, _msg: Drain, _ctx: &mut Self::Context) -> Self::Result {
+            self.0.send(()).unwrap();
+        }
+    }
 
     #[test]
     fn must_kill_system() {
@@ -94,6 +111,24 @@ mod killer {
             assert!(fut.await.is_err(), "system is not killed");
         });
     }
+
+    #[test]
+    fn must_drain_before_kill() {
+        let (tx, mut rx) = unbounded_channel();
+
+        {
+            let sys = System::new();
+            sys.block_on(async {
+                let drained = Drained(tx.clone()).start();
+                let addr = KillerActor::from_registry();
+                addr.do_send(RegisterDrain(drained.recipient()));
+                addr.do_send(Kill { graceful: true });
+            });
+            sys.run().unwrap(); // join system
+        }
+
+        assert!(rx.try_recv().is_ok(), "actor is not drained");
+    }
 }
 
 mod watchdog {
@@ -2,6 +2,7 @@ use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::num::ParseIntError;
 use std::str::FromStr;
+use std::time::Duration;
 
 use actix::fut::ready;
 use actix::{
@@ -13,17 +14,23 @@ use actix_signal::SignalHandler;
 use actix_web::{get, web, Responder};
 use hmap_serde::Labelled;
 use mongodb::bson;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, info_span, Span};
 use tracing_actix::ActorInstrument;
 
 use crate::db::{Coll, Document};
+use crate::scheduler::health::{self, TaskHealth};
+use crate::scheduler::messages::{ReportFailure, UpdateEntry};
 use crate::scheduler::{Entry, Task, TaskInfo};
 use crate::source::ToCollector;
 use crate::utils::Scheduler;
 
 type BoxedError = Box<dyn Error>;
 
+/// Interval of reporting the load of a room, i.e. packets received in the interval.
+const LOAD_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Hash)]
 pub struct BililiveEntry {
     pub uid: u64,
@@ -49,35 +56,105 @@ impl Display for BililiveEntry {
     }
 }
 
+/// A packet from bilibili live.
+///
+/// Fields besides the command vary among commands, and are passed through as is.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BililivePacket {
+    /// Command of the packet, e.g. `DANMU_MSG` or `SEND_GIFT`.
+    pub cmd: String,
+    #[serde(flatten)]
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
 #[derive(Debug, Clone, SignalHandler)]
 pub struct BililiveActor {
     entry: Entry<BililiveEntry>,
+    health: TaskHealth,
+    /// Packets received since the load is last reported.
+    packets: u64,
     info: TaskInfo,
     scheduler: Scheduler<Self>,
 }
 
 impl_task_field_getter!(BililiveActor, info, scheduler);
 impl_stop_on_panic!(BililiveActor);
-impl_to_collector_handler!(BililiveActor, entry);
+impl_to_collector_handler!(BililiveActor, entry, health);
 
 impl StreamHandler<Result<Packet, StreamError>> for BililiveActor {
     fn handle(&mut self, item: Result<Packet, StreamError>, ctx: &mut Self::Context) {
         let _span = self.span().entered();
         match item {
-            Ok(msg) => {
-                if let Ok(msg) = msg.json::<serde_json::Value>() {
+            Ok(msg) => match msg.json::<serde_json::Value>() {
+                Ok(msg) => {
+                    self.packets += 1;
+                    let packet = match serde_json::from_value::<BililivePacket>(msg) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            debug!("packet without command ignored: {}", e);
+                            return;
+                        }
+                    };
                     debug!("publishing event to collector");
-                    ctx.notify(ToCollector::new("bililive", msg));
+                    let key = packet_key(self.entry.data.uid, &packet);
+                    let topic = packet_topic(&packet.cmd);
+                    let mut event = ToCollector::new(&topic, packet);
+                    if let Some(key) = key {
+                        event = event.idempotency_key(key);
+                    }
+                    ctx.notify(event);
                 }
-            }
+                Err(e) => self.health.record_error(e),
+            },
             Err(e) => {
                 error!("stream error: {}", e);
+                self.health.record_error(&e);
+                // persist the final health before the entry is released
+                self.scheduler
+                    .do_send(UpdateEntry::empty_payload(self.info).health(self.health.clone()));
+                self.scheduler.do_send(ReportFailure::new(self.info, e));
                 ctx.stop();
             }
         }
     }
 }
 
+/// Topic of a packet derived from its command, e.g. `bililive.danmaku`, so that consumers can subscribe to some kinds only.
+///
+/// Well-known commands get friendly names, while others are lower-cased as is.
+fn packet_topic(cmd: &str) -> String {
+    // some commands carry extra flags after a colon, e.g. `DANMU_MSG:4:0:2:2:2:0`
+    let cmd = cmd.split(':').next().unwrap_or(cmd);
+    match cmd {
+        "DANMU_MSG" => String::from("bililive.danmaku"),
+        "SEND_GIFT" | "COMBO_SEND" => String::from("bililive.gift"),
+        "GUARD_BUY" => String::from("bililive.guard"),
+        "SUPER_CHAT_MESSAGE" | "SUPER_CHAT_MESSAGE_JPN" => String::from("bililive.superchat"),
+        "LIVE" => String::from("bililive.live"),
+        "PREPARING" => String::from("bililive.preparing"),
+        "" => String::from("bililive.unknown"),
+        cmd => format!("bililive.{}", cmd.to_lowercase()),
+    }
+}
+
+/// Idempotency key of a packet, taken from the id bilibili assigns to the message.
+///
+/// Packets without a known id aren't deduplicated.
+fn packet_key(uid: u64, packet: &BililivePacket) -> Option<String> {
+    let data = packet.fields.get("data");
+    let id = packet
+        .fields
+        .get("msg_id")
+        .or_else(|| data.and_then(|data| data.get("id")))
+        .or_else(|| data.and_then(|data| data.get("tid")))?;
+    let id = match id {
+        serde_json::Value::String(id) if !id.is_empty() => id.clone(),
+        serde_json::Value::Number(id) => id.to_string(),
+        _ => return None,
+    };
+    Some(format!("{}:{}", uid, id))
+}
+
 impl Actor for BililiveActor {
     type Context = Context<Self>;
 
@@ -86,6 +163,14 @@ impl Actor for BililiveActor {
             info!("started");
         });
 
+        health::report_periodically(ctx, |act| &act.health);
+        // busy rooms weigh more, so that they are spread among workers
+        ctx.run_interval(LOAD_REPORT_INTERVAL, |act, _| {
+            let load = std::mem::take(&mut act.packets).max(1);
+            act.scheduler
+                .do_send(UpdateEntry::empty_payload(act.info).weight(load));
+        });
+
         let uid = self.entry.data.uid;
         ctx.spawn(
             ready(())
@@ -108,13 +193,20 @@ impl Actor for BililiveActor {
                     }
                     .into_actor(act)
                 })
-                .map(|stream, _, ctx| match stream {
+                .map(|stream, act, ctx| match stream {
                     Ok(stream) => {
                         info!("stream added");
+                        act.health.connected();
                         Self::add_stream(stream, ctx);
                     }
                     Err(e) => {
                         error!("failed to connect stream: {}", e);
+                        act.health.record_error(&e);
+                        act.scheduler.do_send(
+                            UpdateEntry::empty_payload(act.info).health(act.health.clone()),
+                        );
+                        // bad uids can't be told apart from network errors here, so quarantine never applies
+                        act.scheduler.do_send(ReportFailure::new(act.info, e));
                         ctx.stop();
                     }
                 })
@@ -138,6 +230,8 @@ impl Task for BililiveActor {
         info: TaskInfo,
     ) -> Self {
         Self {
+            health: TaskHealth::resume(entry.health.as_ref()),
+            packets: 0,
             entry,
             info,
             scheduler,
@@ -1,17 +1,21 @@
 use std::fmt::{Display, Formatter};
 use std::num::ParseIntError;
 use std::str::FromStr;
+use std::time::Duration;
 
-use actix::{Actor, Context};
+use actix::{Actor, AsyncContext, Context};
 use actix_signal::SignalHandler;
 use actix_web::{get, web, Responder};
 use hmap_serde::Labelled;
 use mongodb::bson;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, info_span, Span};
 
 use crate::db::{Coll, Document};
+use crate::scheduler::health::{self, TaskHealth};
 use crate::scheduler::{Entry, Task, TaskInfo};
+use crate::source::ToCollector;
 use crate::utils::Scheduler;
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -39,23 +43,49 @@ impl Display for DebugEntry {
     }
 }
 
+/// Interval of ticks emitted by debug tasks.
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A tick emitted periodically by a debug task, to exercise collectors end to end.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DebugTick {
+    /// Id of the debug entry.
+    pub id: u64,
+    /// Sequence number of the tick, starting from 0 every time the task starts.
+    pub seq: u64,
+}
+
 #[derive(Debug, Clone, SignalHandler)]
 pub struct DebugActor {
     entry: Entry<DebugEntry>,
+    health: TaskHealth,
+    /// Ticks emitted so far.
+    seq: u64,
     info: TaskInfo,
     scheduler: Scheduler<Self>,
 }
 impl_task_field_getter!(DebugActor, info, scheduler);
 impl_stop_on_panic!(DebugActor);
-impl_to_collector_handler!(DebugActor, entry);
+impl_to_collector_handler!(DebugActor, entry, health);
 
 impl Actor for DebugActor {
     type Context = Context<Self>;
 
-    fn started(&mut self, _ctx: &mut Self::Context) {
+    fn started(&mut self, ctx: &mut Self::Context) {
         self.span().in_scope(|| {
             info!("started");
         });
+        // ticks are generated locally, so there's nothing to connect to
+        self.health.connected();
+        health::report_periodically(ctx, |act| &act.health);
+        ctx.run_interval(TICK_INTERVAL, |act, ctx| {
+            let tick = DebugTick {
+                id: act.entry.data.id,
+                seq: act.seq,
+            };
+            act.seq += 1;
+            ctx.notify(ToCollector::new("debug.tick", tick));
+        });
     }
 
     fn stopped(&mut self, _ctx: &mut Self::Context) {
@@ -80,6 +110,8 @@ impl Task for DebugActor {
         info: TaskInfo,
     ) -> Self {
         Self {
+            health: TaskHealth::resume(entry.health.as_ref()),
+            seq: 0,
             entry,
             info,
             scheduler,
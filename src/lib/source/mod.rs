@@ -1,6 +1,15 @@
+use std::collections::BTreeMap;
+
 use actix::Message;
+use schemars::schema::RootSchema;
+use schemars::schema_for;
 use serde::Serialize;
 
+use crate::collector::digest::{Summary, DIGEST_TOPIC};
+use bililive::BililivePacket;
+use debug::DebugTick;
+use twitter::Tweet;
+
 pub mod bililive;
 pub mod debug;
 pub mod twitter;
@@ -10,6 +19,7 @@ pub mod twitter;
 pub struct ToCollector<T: Serialize> {
     pub(crate) topic: String,
     pub(crate) body: T,
+    pub(crate) idempotency_key: Option<String>,
 }
 
 impl<T: Serialize> ToCollector<T> {
@@ -17,6 +27,27 @@ impl<T: Serialize> ToCollector<T> {
         Self {
             topic: topic.to_string(),
             body,
+            idempotency_key: None,
         }
     }
+
+    /// Set a deterministic key identifying the event, e.g. the tweet id.
+    ///
+    /// Events with the same key emitted by the same kind of source are delivered only once within the dedup window.
+    #[must_use]
+    pub fn idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+}
+
+/// JSON Schemas of event payloads, keyed by topic.
+pub fn payload_schemas() -> BTreeMap<&'static str, RootSchema> {
+    BTreeMap::from([
+        // packets from bilibili live, with topics derived from their commands
+        ("bililive.*", schema_for!(BililivePacket)),
+        ("debug.tick", schema_for!(DebugTick)),
+        ("twitter", schema_for!(Tweet)),
+        (DIGEST_TOPIC, schema_for!(Summary)),
+    ])
 }
@@ -11,17 +11,24 @@ use actix_web::{get, web, Responder};
 use egg_mode::entities::MediaType;
 use egg_mode::user::UserID;
 use egg_mode::{tweet, Token};
+use futures::TryStreamExt;
 use hmap_serde::Labelled;
-use mongodb::bson;
+use mongodb::bson::{self, doc};
+use mongodb::Database;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use tracing::Span;
 use tracing::{error, info, info_span, warn};
 use tracing_actix::ActorInstrument;
 
-use crate::db::{Coll, Document};
-use crate::scheduler::messages::UpdateEntry;
+use crate::db::{Coll, Collection, DBRef, DBResult, Document};
+use crate::manager::Vtuber;
+use crate::scheduler::health::TaskHealth;
+use crate::scheduler::messages::{ReportFailure, UpdateEntry};
 use crate::scheduler::{Entry, Task, TaskInfo};
+use crate::source::debug::DebugEntry;
 use crate::source::ToCollector;
+use crate::utils::DBErrorExt;
 use crate::utils::Scheduler;
 use crate::ScheduleConfig;
 
@@ -32,7 +39,7 @@ pub struct TwitterEntry {
 }
 
 impl Labelled for TwitterEntry {
-    const KEY: &'static str = "debug";
+    const KEY: &'static str = "twitter";
 }
 
 impl FromStr for TwitterEntry {
@@ -57,8 +64,9 @@ pub struct TwitterSince {
     since: Option<u64>,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Hash)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Hash, JsonSchema)]
 pub struct Tweet {
+    id: u64,
     text: String,
     photos: Vec<String>,
     link: String,
@@ -69,6 +77,7 @@ pub struct Tweet {
 pub struct TwitterActor {
     token: Token,
     entry: Entry<TwitterEntry>,
+    health: TaskHealth,
     schedule_config: ScheduleConfig,
     info: TaskInfo,
     scheduler: Scheduler<Self>,
@@ -76,7 +85,7 @@ pub struct TwitterActor {
 
 impl_task_field_getter!(TwitterActor, info, scheduler);
 impl_stop_on_panic!(TwitterActor);
-impl_to_collector_handler!(TwitterActor, entry);
+impl_to_collector_handler!(TwitterActor, entry, health);
 
 impl Actor for TwitterActor {
     type Context = Context<Self>;
@@ -95,19 +104,37 @@ impl Actor for TwitterActor {
                     .then(|tweets, act, ctx| -> ResponseActFuture<Self, _> {
                         match tweets {
                             Ok((since, tweets)) => {
-                                if !tweets.is_empty() {
-                                    ctx.notify(ToCollector::new("twitter", tweets));
+                                // tweets are sorted from newest to oldest, and published one by one so that
+                                // overlapping fetches are deduplicated per tweet
+                                for tweet in tweets.into_iter().rev() {
+                                    let key = tweet.id.to_string();
+                                    ctx.notify(
+                                        ToCollector::new("twitter", tweet).idempotency_key(key),
+                                    );
                                 }
                                 act.entry.data.since = since;
+                                act.health.connected();
                                 Box::pin(
                                     act.scheduler
-                                        .send(UpdateEntry::new(act.info, TwitterSince { since }))
+                                        .send(
+                                            UpdateEntry::new(act.info, TwitterSince { since })
+                                                .health(act.health.clone()),
+                                        )
                                         .into_actor(act)
                                         .map(|res, _, _| Some(res)),
                                 )
                             }
                             Err(e) => {
                                 error!("tweet fetch error: {:?}", e);
+                                act.health.record_error(&e);
+                                act.scheduler.do_send(
+                                    UpdateEntry::empty_payload(act.info).health(act.health.clone()),
+                                );
+                                act.scheduler.do_send(if is_permanent(&e) {
+                                    ReportFailure::permanent(act.info, e)
+                                } else {
+                                    ReportFailure::new(act.info, e)
+                                });
                                 ctx.stop();
                                 Box::pin(ready(None).into_actor(act))
                             }
@@ -127,6 +154,22 @@ impl Actor for TwitterActor {
     }
 }
 
+/// Whether the error won't go away by retrying, i.e. the user doesn't exist or is suspended.
+///
+/// Network errors, rate limits and token errors are resolved once the API recovers or the token is fixed.
+fn is_permanent(e: &egg_mode::error::Error) -> bool {
+    // 34: page does not exist, 50: user not found, 63: user has been suspended
+    const PERMANENT_CODES: [i32; 3] = [34, 50, 63];
+    match e {
+        egg_mode::error::Error::TwitterError(_, errors) => errors
+            .errors
+            .iter()
+            .any(|error| PERMANENT_CODES.contains(&error.code)),
+        egg_mode::error::Error::BadStatus(status) => status.as_u16() == 404,
+        _ => false,
+    }
+}
+
 async fn fetch_tweets(
     token: Token,
     entry: TwitterEntry,
@@ -142,6 +185,7 @@ async fn fetch_tweets(
             .response
             .into_iter()
             .map(|tweet| Tweet {
+                id: tweet.id,
                 text: tweet.text,
                 photos: tweet
                     .entities
@@ -170,6 +214,10 @@ impl Task for TwitterActor {
         Document::new()
     }
 
+    fn state_fields() -> &'static [&'static str] {
+        &["since"]
+    }
+
     fn construct(
         entry: Entry<Self::Entry>,
         ctor: Self::Ctor,
@@ -178,6 +226,7 @@ impl Task for TwitterActor {
     ) -> Self {
         Self {
             token: Token::Bearer(ctor.token),
+            health: TaskHealth::resume(entry.health.as_ref()),
             entry,
             schedule_config: ctor.schedule_config,
             info,
@@ -209,6 +258,62 @@ impl TwitterCtor {
 
 pub struct TwitterColl;
 
+/// Move twitter entries stored under the `debug` key, which twitter entries used by mistake, to the `twitter` one.
+///
+/// Such entries shared the collection and vtuber field with debug entries, and are told apart by `uid`. Vtuber fields
+/// referring to them are moved as well. Returns the number of migrated entries.
+///
+/// # Errors
+/// Pass errors raised by mongodb driver.
+pub async fn migrate_legacy_entries(db: &Database, vtubers: &Collection<Vtuber>) -> DBResult<u64> {
+    let legacy: Collection<Document> = db.collection(DebugEntry::KEY);
+    let entries: Collection<Document> = db.collection(TwitterEntry::KEY);
+    let mut migrated = 0;
+    let mut cursor = legacy.find(doc! {"uid": {"$exists": true}}, None).await?;
+    while let Some(entry) = cursor.try_next().await? {
+        let id = match entry.get_object_id("_id") {
+            Ok(id) => id,
+            Err(_) => {
+                warn!(
+                    "skipping legacy twitter entry without object id: {:?}",
+                    entry.get("_id")
+                );
+                continue;
+            }
+        };
+        // each step is idempotent, so that an interrupted migration can be resumed on next startup
+        if let Err(e) = entries.insert_one(&entry, None).await {
+            if e.write() != Some(11000) {
+                return Err(e);
+            }
+        }
+        let field = DBRef {
+            collection: String::from(TwitterEntry::KEY),
+            id,
+            db: None,
+        };
+        vtubers
+            .update_many(
+                doc! {format!("fields.{}.$id", DebugEntry::KEY): id},
+                doc! {
+                    "$set": {format!("fields.{}", TwitterEntry::KEY): bson::to_bson(&field)?},
+                    "$unset": {format!("fields.{}", DebugEntry::KEY): ""}
+                },
+                None,
+            )
+            .await?;
+        legacy.delete_one(doc! {"_id": id}, None).await?;
+        migrated += 1;
+    }
+    if migrated > 0 {
+        info!(
+            "migrated {} twitter entries from the debug collection",
+            migrated
+        );
+    }
+    Ok(migrated)
+}
+
 #[get("/set")]
 pub async fn set(
     coll: web::Data<Coll<TwitterColl>>,
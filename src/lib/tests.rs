@@ -1,7 +1,14 @@
+use std::future::Future;
+
 use actix::{Actor, Context, Handler, Message, System};
+use mongodb::Database;
+use testcontainers::clients::Cli;
+use testcontainers::images::generic::{GenericImage, WaitFor};
+use testcontainers::Docker;
 use uuid::Uuid;
 
 use crate::common::ResponseWrapper;
+use crate::db::connect_db;
 use crate::scheduler::messages::GetId;
 
 #[derive(Debug, Copy, Clone, Message)]
@@ -105,6 +112,38 @@ impl Handler<GetId> for Adder {
     }
 }
 
+fn mongo_image() -> GenericImage {
+    GenericImage::new("mongo:5")
+        .with_wait_for(WaitFor::message_on_stdout("Waiting for connections"))
+}
+
+/// Run given test against a fresh database, either at `TEST_MONGODB_URI` or in a temporary container.
+pub async fn with_db<F, Fut>(f: F)
+where
+    F: FnOnce(Database) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let db_name = format!("stargazer_test_{}", Uuid::new_v4().to_simple());
+    // read at runtime, so that tests can be pointed at a server without rebuilding
+    if let Ok(uri) = std::env::var("TEST_MONGODB_URI") {
+        f(connect_db(&uri, &db_name)
+            .await
+            .expect("unable to connect to db"))
+        .await;
+    } else {
+        let client = Cli::default();
+        // published on a random port, so that tests may run in parallel
+        let container = client.run(mongo_image());
+        let port = container.get_host_port(27017).expect("port not published");
+        f(
+            connect_db(&format!("mongodb://127.0.0.1:{}", port), &db_name)
+                .await
+                .expect("unable to connect to db"),
+        )
+        .await;
+    }
+}
+
 mod utils {
     use std::convert::Infallible;
     use std::fmt::{Debug, Display, Formatter};
@@ -119,6 +158,7 @@ mod utils {
     use tracing::Span;
 
     use crate::db::Document;
+    use crate::scheduler::health::TaskHealth;
     use crate::scheduler::{Entry, Task, TaskInfo};
     use crate::utils::Scheduler;
 
@@ -130,6 +170,7 @@ mod utils {
         _marker_2: Rc<()>,
         info: TaskInfo,
         entry: Entry<()>,
+        health: TaskHealth,
         scheduler: Scheduler<Self>,
     }
 
@@ -187,6 +228,6 @@ mod utils {
     }
 
     impl_stop_on_panic!(A<T: StaticUnpinned, U: StaticUnpinned+ Copy + Eq>);
-    impl_to_collector_handler!(A<T: StaticUnpinned, U: Copy + Eq + StaticUnpinned>, entry);
+    impl_to_collector_handler!(A<T: StaticUnpinned, U: Copy + Eq + StaticUnpinned>, entry, health);
     impl_task_field_getter!(A<T: StaticUnpinned, U: StaticUnpinned+ Copy + Eq>, info, scheduler);
 }
@@ -63,7 +63,7 @@ macro_rules! impl_task_field_getter {
 
 #[macro_export]
 macro_rules! impl_to_collector_handler {
-    ($Self: ident $(< $( $lt:tt $( : $clt:tt $(+ $dlt:tt )* )? ),+ >)? , $entry: ident) => {
+    ($Self: ident $(< $( $lt:tt $( : $clt:tt $(+ $dlt:tt )* )? ),+ >)? , $entry: ident, $health: ident) => {
         impl<$($( $lt $( : $clt $(+ $dlt )* )? ),+ , )? Z: 'static + serde::Serialize + Send + Sync >
             actix::Handler<crate::source::ToCollector<Z>> for $Self $(< $( $lt ),+ >)?
         {
@@ -84,16 +84,24 @@ macro_rules! impl_to_collector_handler {
                 use crate::scheduler::SchedulerGetter;
 
                 let root = self.$entry.root.clone();
+                let info = self.get_info();
+                let source = <<Self as crate::scheduler::Task>::Entry as hmap_serde::Labelled>::KEY;
                 Box::pin(
                     self.get_scheduler()
-                        .send(crate::scheduler::messages::CheckOwnership{ info: self.get_info() })
+                        .send(crate::scheduler::messages::CheckOwnership{ info })
                         .into_actor(self)
-                        .map(move |res, _act, ctx| {
+                        .map(move |res, act, ctx| {
                             let holding_ownership = res.unwrap_or(Ok(false)).unwrap_or(false);
                             if holding_ownership {
+                                act.$health.record_event();
                                 crate::context::ArbiterContext::with(|ctx| {
                                     ctx.send::<crate::collector::CollectorActor, _>(
-                                        crate::collector::Publish::new(root, &*msg.topic, msg.body),
+                                        crate::collector::Publish::new(root, source, &*msg.topic, msg.body)
+                                            .idempotency_key(msg.idempotency_key)
+                                            .fencing_token(crate::collector::envelope::FencingToken {
+                                                entry: info.doc_id.to_hex(),
+                                                epoch: info.epoch,
+                                            }),
                                     )
                                     .unwrap()
                                     .immediately();
@@ -196,6 +204,7 @@ where
 
 pub trait DBErrorExt {
     fn write(&self) -> Option<i32>;
+    fn command(&self) -> Option<i32>;
 }
 
 impl DBErrorExt for mongodb::error::Error {
@@ -205,4 +214,11 @@ impl DBErrorExt for mongodb::error::Error {
             _ => None,
         }
     }
+
+    fn command(&self) -> Option<i32> {
+        match &*self.kind {
+            ErrorKind::Command(e) => Some(e.code),
+            _ => None,
+        }
+    }
 }
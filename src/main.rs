@@ -1,3 +1,4 @@
+use std::error::Error;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -5,27 +6,38 @@ use actix::fut::ready;
 use actix::Actor;
 use actix_web::web::Data;
 use actix_web::{get, web, Responder};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use itertools::Itertools;
 use mongodb::bson::doc;
 use mongodb::options::IndexOptions;
 use mongodb::IndexModel;
 
 use stargazer_lib::collector::amqp::AMQPFactory;
+use stargazer_lib::collector::archive::{self, ArchiveFactory, ArchivedEvent, ARCHIVE_COLLECTION};
+use stargazer_lib::collector::dead_letter::{self, DeadLetter, DEAD_LETTER_COLLECTION};
 use stargazer_lib::collector::debug::DebugCollectorFactory;
-use stargazer_lib::collector::CollectorActor;
+use stargazer_lib::collector::dedup::{self, Dedup, IdempotencyKey, IDEMPOTENCY_COLLECTION};
+use stargazer_lib::collector::digest::Digest;
+use stargazer_lib::collector::envelope::{envelope_schema, ENVELOPE_VERSION};
+use stargazer_lib::collector::fencing::{Fence, Fencing, FENCE_COLLECTION};
+use stargazer_lib::collector::file::FileFactory;
+use stargazer_lib::collector::outbox::{self, Outbox, OutboxEntry, OUTBOX_COLLECTION};
+use stargazer_lib::collector::priority::Priorities;
+use stargazer_lib::collector::routing::Routing;
+use stargazer_lib::collector::stream::{self, StreamFactory, StreamHub};
+use stargazer_lib::collector::template::{StoredTemplate, Templates, TEMPLATE_COLLECTION};
+use stargazer_lib::collector::{self, CollectorActor, CollectorFactoryWrapped};
 use stargazer_lib::db::{connect_db, Coll, Collection, Document};
 use stargazer_lib::manager::{Manager, Vtuber};
 use stargazer_lib::o;
 use stargazer_lib::scheduler::driver::ScheduleDriverActor;
 use stargazer_lib::scheduler::messages::{ActorsIter, UpdateAll};
-use stargazer_lib::scheduler::ScheduleActor;
+use stargazer_lib::scheduler::{open_store, ScheduleActor};
 use stargazer_lib::source::bililive::{BililiveActor, BililiveColl};
 use stargazer_lib::source::debug::{DebugActor, DebugColl};
-use stargazer_lib::source::twitter::{TwitterActor, TwitterColl, TwitterCtor};
-use stargazer_lib::{
-    ArbiterContext, Config, InstanceContext, ScheduleConfig, Server, TwitterConfig, AMQP,
-};
+use stargazer_lib::source::payload_schemas;
+use stargazer_lib::source::twitter::{self, TwitterActor, TwitterColl, TwitterCtor};
+use stargazer_lib::{ArbiterContext, Config, InstanceContext, Server, TwitterConfig, AMQP};
 
 #[derive(Parser)]
 #[clap(
@@ -36,6 +48,23 @@ struct Opts {
     /// Sets a custom config file. This flag overrides system-wide and user-wide configs.
     #[clap(short, long)]
     config: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print JSON Schemas of the event envelope and payloads, then exit.
+    Schema,
+}
+
+fn print_schemas() {
+    let schemas = serde_json::json!({
+        "version": ENVELOPE_VERSION,
+        "envelope": envelope_schema(),
+        "payloads": payload_schemas(),
+    });
+    println!("{}", serde_json::to_string_pretty(&schemas).unwrap());
 }
 
 #[get("/status")]
@@ -60,19 +89,30 @@ async fn status(ctx: web::Data<InstanceContext>) -> impl Responder {
 }
 
 #[actix_web::main]
-async fn main() {
+async fn main() -> Result<(), Box<dyn Error>> {
     tracing_subscriber::fmt::init();
 
     let opts = Opts::parse();
+    if let Some(Command::Schema) = opts.command {
+        print_schemas();
+        return Ok(());
+    }
     let config = Config::new(opts.config.as_deref()).unwrap();
     let collector_config = config.collector.clone();
-    let sched_config = ScheduleConfig::default();
+    let labels = config.basic.labels.clone();
 
     let source_config = config.source.clone();
     let twitter_config = source_config.twitter.clone();
     let bililive_config = source_config.bililive;
     let debug_source_config = source_config.debug;
 
+    let bililive_sched_config = bililive_config.schedule.apply(config.schedule);
+    let twitter_sched_config = match &twitter_config {
+        TwitterConfig::Enabled { schedule, .. } => schedule.apply(config.schedule),
+        TwitterConfig::Disabled => config.schedule,
+    };
+    let debug_sched_config = debug_source_config.schedule.apply(config.schedule);
+
     let database = connect_db(config.mongodb.uri(), config.mongodb.database())
         .await
         .expect("unable to connect to db");
@@ -91,64 +131,120 @@ async fn main() {
                 .build(),
             None,
         )
-        .await
-        .expect("unable to create index");
+        .await?;
+    // twitter entries used to be stored under the key of debug entries
+    twitter::migrate_legacy_entries(&database, &coll_vtuber).await?;
+
+    let coll_events: Collection<ArchivedEvent> = database.collection(ARCHIVE_COLLECTION);
+    if collector_config.archive.enabled {
+        archive::create_indexes(&database, &coll_events, collector_config.archive.ttl).await?;
+    }
+
+    let coll_dead_letters: Collection<DeadLetter> = database.collection(DEAD_LETTER_COLLECTION);
+    dead_letter::create_indexes(&coll_dead_letters).await?;
+
+    let coll_outbox: Collection<OutboxEntry> = database.collection(OUTBOX_COLLECTION);
+    if collector_config.outbox.enabled {
+        outbox::create_indexes(&coll_outbox).await?;
+    }
+
+    let coll_idempotency: Collection<IdempotencyKey> = database.collection(IDEMPOTENCY_COLLECTION);
+    if collector_config.dedup.enabled {
+        dedup::create_indexes(&database, &coll_idempotency, collector_config.dedup.window).await?;
+    }
+
+    let coll_fences: Collection<Fence> = database.collection(FENCE_COLLECTION);
+
+    let stream_hub = collector_config
+        .stream
+        .enabled
+        .then(|| Arc::new(StreamHub::new(collector_config.stream)));
+
+    // shared by all arbiters, so that they don't rotate files under each other
+    let file_factory = FileFactory::new(collector_config.file.clone());
+
+    let coll_templates: Collection<StoredTemplate> = database.collection(TEMPLATE_COLLECTION);
+    let templates = Arc::new(Templates::new(collector_config.templates.clone()));
+    if collector_config.templates.directory.is_some() || collector_config.templates.mongodb {
+        actix_web::rt::spawn(templates.clone().watch(coll_templates));
+    }
 
     let arc_coll_bililive: Arc<Coll<BililiveColl>> = Arc::new(Coll::new(coll_bililive.clone()));
     let arc_coll_twitter: Arc<Coll<TwitterColl>> = Arc::new(Coll::new(coll_twitter.clone()));
     let arc_coll_debug: Arc<Coll<DebugColl>> = Arc::new(Coll::new(coll_debug.clone()));
     // TODO ---
 
-    let bililive_driver = ScheduleDriverActor::new(sched_config).start();
-    let twitter_driver = ScheduleDriverActor::new(sched_config).start();
-    let debug_driver = ScheduleDriverActor::new(sched_config).start();
+    let bililive_driver = ScheduleDriverActor::new(bililive_sched_config)
+        .watch(coll_bililive.clone())
+        .start();
+    let twitter_driver = ScheduleDriverActor::new(twitter_sched_config)
+        .watch(coll_twitter.clone())
+        .start();
+    let debug_driver = ScheduleDriverActor::new(debug_sched_config)
+        .watch(coll_debug.clone())
+        .start();
+    // shared by schedulers on all arbiters
+    let bililive_store = bililive_config
+        .enabled
+        .then(|| open_store::<BililiveActor>(&database, &bililive_sched_config));
+    let twitter_store = matches!(twitter_config, TwitterConfig::Enabled { .. })
+        .then(|| open_store::<TwitterActor>(&database, &twitter_sched_config));
+    let debug_store = debug_source_config
+        .enabled
+        .then(|| open_store::<DebugActor>(&database, &debug_sched_config));
     Server::new(move |instance_id| {
         let database = database.clone();
         let coll_vtuber = coll_vtuber.clone();
+        let coll_events = coll_events.clone();
+        let coll_outbox = coll_outbox.clone();
+        let coll_dead_letters = coll_dead_letters.clone();
+        let coll_idempotency = coll_idempotency.clone();
+        let coll_fences = coll_fences.clone();
+        let stream_hub = stream_hub.clone();
+        let file_factory = file_factory.clone();
+        let templates = templates.clone();
 
         let collector_config = collector_config.clone();
         let ctx = ArbiterContext::new(instance_id);
 
-        let bililive_actor: Option<ScheduleActor<BililiveActor>> = if bililive_config.enabled {
-            Some(
+        let bililive_actor: Option<ScheduleActor<BililiveActor>> =
+            bililive_store.as_ref().map(|store| {
                 ScheduleActor::builder()
-                    .db(&database)
+                    .store(store.clone())
                     .ctor_builder(|| ())
-                    .config(sched_config)
+                    .config(bililive_sched_config)
                     .driver(bililive_driver.clone())
-                    .build(),
-            )
-        } else {
-            None
-        };
+                    .labels(labels.clone())
+                    .build()
+            });
 
         let twitter_actor: Option<ScheduleActor<TwitterActor>> =
-            if let TwitterConfig::Enabled { token } = &twitter_config {
+            if let (TwitterConfig::Enabled { token, .. }, Some(store)) =
+                (&twitter_config, &twitter_store)
+            {
                 let token = token.clone();
                 Some(
                     ScheduleActor::builder()
-                        .db(&database)
-                        .ctor_builder(move || TwitterCtor::new(sched_config, &*token))
-                        .config(sched_config)
+                        .store(store.clone())
+                        .ctor_builder(move || TwitterCtor::new(twitter_sched_config, &token))
+                        .config(twitter_sched_config)
                         .driver(twitter_driver.clone())
+                        .labels(labels.clone())
                         .build(),
                 )
             } else {
                 None
             };
 
-        let debug_actor: Option<ScheduleActor<DebugActor>> = if debug_source_config.enabled {
-            Some(
-                ScheduleActor::builder()
-                    .db(&database)
-                    .ctor_builder(|| ())
-                    .config(sched_config)
-                    .driver(debug_driver.clone())
-                    .build(),
-            )
-        } else {
-            None
-        };
+        let debug_actor: Option<ScheduleActor<DebugActor>> = debug_store.as_ref().map(|store| {
+            ScheduleActor::builder()
+                .store(store.clone())
+                .ctor_builder(|| ())
+                .config(debug_sched_config)
+                .driver(debug_driver.clone())
+                .labels(labels.clone())
+                .build()
+        });
 
         let bililive_addr = bililive_actor.map(Actor::start);
         let twitter_addr = twitter_actor.map(Actor::start);
@@ -158,14 +254,69 @@ async fn main() {
         let ctx = o!(twitter_addr.map_or(ctx, |addr| ctx.register_addr(addr)));
         let ctx = o!(debug_addr.map_or(ctx, |addr| ctx.register_addr(addr)));
 
+        let configure = |factory: CollectorFactoryWrapped, key: &str| {
+            let routing = Routing::new(&collector_config.route(key)).expect("invalid route");
+            let digest = Digest::new(&collector_config.digest(key)).expect("invalid digest");
+            factory
+                .retry(collector_config.retry_policy(key))
+                .routing(routing)
+                .digest(digest)
+                .templates(templates.clone(), collector_config.locale(key))
+        };
         let mut collector_factories = Vec::new();
-        if let AMQP::Enabled { uri, exchange } = collector_config.amqp {
-            collector_factories.push(AMQPFactory::new(uri.as_str(), exchange.as_str()).into());
+        for amqp in collector_config.amqp.iter() {
+            if let AMQP::Enabled {
+                name,
+                uri,
+                exchange,
+                exchange_kind,
+                routing_key,
+                encoding,
+            } = amqp
+            {
+                let key = name
+                    .as_ref()
+                    .map_or_else(|| String::from("amqp"), |name| format!("amqp.{}", name));
+                collector_factories.push(configure(
+                    AMQPFactory::new(uri, exchange)
+                        .exchange_kind(*exchange_kind)
+                        .routing_key(routing_key)
+                        .encoding(*encoding)
+                        .into(),
+                    &key,
+                ));
+            }
         }
         if collector_config.debug.enabled {
-            collector_factories.push(DebugCollectorFactory.into());
+            collector_factories.push(configure(DebugCollectorFactory.into(), "debug"));
+        }
+        if collector_config.file.enabled {
+            collector_factories.push(configure(file_factory.into(), "file"));
+        }
+        if collector_config.archive.enabled {
+            collector_factories.push(configure(
+                ArchiveFactory::new(coll_events.clone()).into(),
+                "archive",
+            ));
+        }
+        if let Some(hub) = &stream_hub {
+            collector_factories.push(configure(StreamFactory::new(hub.clone()).into(), "stream"));
+        }
+        let priorities = Priorities::new(&collector_config.priorities).expect("invalid priorities");
+        let mut collector_actor =
+            CollectorActor::new(database.clone(), collector_factories).priorities(priorities);
+        if collector_config.outbox.enabled {
+            collector_actor =
+                collector_actor.outbox(Outbox::new(coll_outbox, collector_config.outbox.clone()));
+        }
+        if collector_config.dedup.enabled {
+            collector_actor =
+                collector_actor.dedup(Dedup::new(coll_idempotency, collector_config.dedup));
+        }
+        if collector_config.fencing.enabled {
+            collector_actor =
+                collector_actor.fencing(Fencing::new(coll_fences, collector_config.fencing));
         }
-        let collector_actor = CollectorActor::new(database.clone(), collector_factories);
         let collector_addr = collector_actor.start();
 
         let arc_coll_bililive = arc_coll_bililive.clone();
@@ -182,16 +333,27 @@ async fn main() {
             cfg.app_data(Data::from(arc_coll_bililive))
                 .app_data(Data::from(arc_coll_twitter))
                 .app_data(Data::from(arc_coll_debug))
+                .app_data(Data::new(coll_events))
+                .app_data(Data::new(coll_dead_letters))
                 .service(status)
+                .service(collector::collector_stats)
+                .service(archive::events)
+                .service(archive::replay)
+                .service(dead_letter::dead_letters)
+                .service(dead_letter::replay_dead_letters)
                 .service(web::scope("/bililive").service(stargazer_lib::source::bililive::set))
                 .service(web::scope("/twitter").service(stargazer_lib::source::twitter::set))
                 .service(web::scope("/debug").service(stargazer_lib::source::debug::set))
                 .service(manager.build("/manage"));
+            if let Some(hub) = stream_hub {
+                cfg.app_data(Data::from(hub))
+                    .service(stream::sse)
+                    .service(stream::websocket);
+            }
         })
     })
     .workers(config.basic.workers)
-    .run(config.http.into())
-    .unwrap()
-    .await
-    .unwrap();
+    .run(config.http.into())?
+    .await?;
+    Ok(())
 }